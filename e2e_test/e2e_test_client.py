@@ -16,16 +16,29 @@ i.e.
 python3 e2e_test_client.py proxy 8000 redis
 """
 
-# let the proxy and redis start
-# a better approach here would be to expose a 'ready' signal from the proxy
-time.sleep(3)
-
 assert len(sys.argv) == 4, "incorrect arguments"
 PROXY_NETWORK = sys.argv[1]
 PROXY_PORT = sys.argv[2]
 REDIS_NETWORK = sys.argv[3]
 
 
+def wait_until_ready(timeout_sec=60):
+    # the proxy answers /readyz with a 200 once it can reach redis
+    deadline = time.time() + timeout_sec
+    url = "http://{}:{}/readyz".format(PROXY_NETWORK, PROXY_PORT)
+    while time.time() < deadline:
+        try:
+            if requests.get(url).status_code == 200:
+                return
+        except requests.exceptions.ConnectionError:
+            pass
+        time.sleep(0.2)
+    raise TimeoutError("proxy was not ready after {} seconds".format(timeout_sec))
+
+
+wait_until_ready()
+
+
 def generate_test_data(size):
     test_data = {}
     for i in range(size):
@@ -49,20 +62,34 @@ def get_from_proxy_expect_success(test_data):
     (k, v) = test_data
     request = f"http://{PROXY_NETWORK}:{PROXY_PORT}/{k}"
     proxy_res = requests.get(request)
+    assert proxy_res.status_code == 200
+    assert proxy_res.text == v
+
+
+def write_through_proxy(test_data):
+    (k, v) = test_data
+    request = f"http://{PROXY_NETWORK}:{PROXY_PORT}/{k}"
+    assert requests.put(request, data=v).status_code == 204
+    proxy_res = requests.get(request)
+    assert proxy_res.status_code == 200
     assert proxy_res.text == v
+    assert requests.delete(request).status_code == 204
+    assert requests.get(request).status_code == 404
 
 
 def get_from_proxy_expect_fail(test_data):
     (k, v) = test_data
     request = f"http://{PROXY_NETWORK}:{PROXY_PORT}/{k}"
     proxy_res = requests.get(request)
-    assert proxy_res.text == ""
+    assert proxy_res.status_code == 404
+    assert proxy_res.json()["error"] == "not_found"
 
 
 test_data = generate_test_data(1000)
 set_in_redis(test_data)
 
 bad_test_data = generate_test_data(10)
+write_test_data = generate_test_data(10)
 
 pool_size = 10
 successful_requests = 0
@@ -77,8 +104,12 @@ with Pool(pool_size) as p:
     # out of the cache
     p.map(get_from_proxy_expect_success, test_data.items())
     successful_requests += len(test_data)
+    # test that values written and deleted through the proxy are
+    # reflected in the following reads
+    p.map(write_through_proxy, write_test_data.items())
+    successful_requests += len(write_test_data) * 4
 
 print(
-    f"test client sucessfully ran {successful_requests} get requests across {pool_size} parallel clients"
+    f"test client sucessfully ran {successful_requests} requests across {pool_size} parallel clients"
 )
 
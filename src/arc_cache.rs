@@ -0,0 +1,311 @@
+use {
+    crate::cache_policy::EvictionPolicy,
+    crate::clock::{Clock, SystemClock},
+    crate::key_list::KeyList,
+    crate::lru_cache::{entry_lifetime, ByteSize, Cache, CacheEntry, CacheStats},
+    std::{collections::HashMap, sync::Arc, time::Duration},
+};
+
+/*
+ * The ARCCache is the Adaptive Replacement Cache of Megiddo and Modha. The
+ * cached keys are split between two LRU lists:
+ *   recent    keys used once since they were last cached
+ *   frequent  keys used at least twice
+ * and the keys most recently evicted from each are remembered, without
+ * their values, in two ghost lists of their own. A put of a key found in a
+ * ghost list means the cache evicted it too early, so the target size of
+ * the recent list moves towards the side that lost it. A scan of keys read
+ * once only ever churns the recent list, leaving the frequent list alone.
+ *
+ * The cached lists hold at most capacity keys between them, and the cached
+ * and ghost lists at most twice that.
+ */
+pub struct ARCCache<V = String> {
+    entries: HashMap<String, CacheEntry<V>>,
+    recent: KeyList,
+    frequent: KeyList,
+    recent_ghosts: KeyList,
+    frequent_ghosts: KeyList,
+    //How many of the cached keys should be in recent, adapted as ghosts are hit
+    recent_target: usize,
+    max_cache_entry_lifetime: Duration,
+    stale_grace: Duration,
+    clock: Arc<dyn Clock>,
+    capacity: usize,
+    stats: CacheStats,
+}
+
+impl<V: Clone> Cache<V> for ARCCache<V> {
+    fn get(&mut self, key: &str) -> Option<V> {
+        let now = self.clock.now();
+        let expired = match self.entries.get(key) {
+            Some(entry) => entry.expired(now),
+            None => {
+                self.stats.misses += 1;
+                return None;
+            }
+        };
+        if expired {
+            if self.entries[key].expired_for(now, self.stale_grace) {
+                self.remove(key);
+                self.stats.expirations += 1;
+            }
+            self.stats.misses += 1;
+            return None;
+        }
+        self.stats.hits += 1;
+        self.recent.remove(key);
+        self.frequent.push_newest(key);
+        Some(self.entries[key].val.clone())
+    }
+
+    fn put(&mut self, key: &str, val: V, ttl: Option<Duration>) {
+        if let Some(entry) = self.entries.get(key) {
+            if !entry.expired(self.clock.now()) {
+                eprintln!("unexpected double write of {} - ignoring", key);
+                return;
+            }
+            //A refresh of an entry kept through the grace window
+            self.remove(key);
+        }
+        let lifetime = match entry_lifetime(ttl, self.max_cache_entry_lifetime) {
+            Some(lifetime) => lifetime,
+            None => return,
+        };
+
+        if self.recent_ghosts.contains(key) {
+            //Evicted from recent too soon, so recent should be bigger
+            let step = (self.frequent_ghosts.len() / self.recent_ghosts.len()).max(1);
+            self.recent_target = (self.recent_target + step).min(self.capacity);
+            self.recent_ghosts.remove(key);
+            self.make_room(false);
+            self.frequent.push_newest(key);
+        } else if self.frequent_ghosts.contains(key) {
+            let step = (self.recent_ghosts.len() / self.frequent_ghosts.len()).max(1);
+            self.recent_target = self.recent_target.saturating_sub(step);
+            self.frequent_ghosts.remove(key);
+            self.make_room(true);
+            self.frequent.push_newest(key);
+        } else {
+            if self.recent.len() + self.recent_ghosts.len() >= self.capacity {
+                if self.recent.len() < self.capacity {
+                    self.recent_ghosts.pop_oldest();
+                    self.make_room(false);
+                } else {
+                    //Recent fills the whole cache, so there is nothing to
+                    //learn from remembering what it drops
+                    self.evict_oldest_recent(false);
+                }
+            } else {
+                let total = self.recent.len()
+                    + self.frequent.len()
+                    + self.recent_ghosts.len()
+                    + self.frequent_ghosts.len();
+                if total >= 2 * self.capacity {
+                    self.frequent_ghosts.pop_oldest();
+                }
+                self.make_room(false);
+            }
+            self.recent.push_newest(key);
+        }
+        self.entries.insert(
+            key.to_string(),
+            CacheEntry::new(val, lifetime, self.clock.now()),
+        );
+    }
+
+    fn remove(&mut self, key: &str) {
+        if self.entries.remove(key).is_some() {
+            self.recent.remove(key);
+            self.frequent.remove(key);
+        }
+    }
+
+    fn get_stale(&mut self, key: &str) -> Option<V> {
+        let entry = &self.entries.get(key)?;
+        let now = self.clock.now();
+        if entry.expired_for(now, self.stale_grace) {
+            return None;
+        }
+        let val = entry.val.clone();
+        //A fresh entry is handed back too, but isn't served stale
+        if entry.expired(now) {
+            self.stats.stale_hits += 1;
+        }
+        Some(val)
+    }
+}
+
+impl<V: Clone + ByteSize + Send> EvictionPolicy<V> for ARCCache<V> {
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len() as u64,
+            ..self.stats
+        }
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.recent_target = self.recent_target.min(capacity);
+        while self.entries.len() > capacity {
+            self.replace(false);
+        }
+        while self.recent.len() + self.recent_ghosts.len() > capacity
+            && self.recent_ghosts.pop_oldest().is_some()
+        {}
+        while self.recent.len()
+            + self.frequent.len()
+            + self.recent_ghosts.len()
+            + self.frequent_ghosts.len()
+            > 2 * capacity
+            && self.frequent_ghosts.pop_oldest().is_some()
+        {}
+    }
+
+    fn set_max_lifetime(&mut self, max_cache_entry_lifetime: Duration) {
+        self.max_cache_entry_lifetime = max_cache_entry_lifetime;
+        for entry in self.entries.values_mut() {
+            entry.cap_lifetime(max_cache_entry_lifetime);
+        }
+    }
+
+    fn set_stale_grace(&mut self, stale_grace: Duration) {
+        self.stale_grace = stale_grace;
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+}
+
+impl<V> ARCCache<V> {
+    pub fn new(capacity: usize, max_cache_entry_lifetime: Duration) -> ARCCache<V> {
+        ARCCache {
+            entries: HashMap::with_capacity(capacity),
+            recent: KeyList::default(),
+            frequent: KeyList::default(),
+            recent_ghosts: KeyList::default(),
+            frequent_ghosts: KeyList::default(),
+            recent_target: 0,
+            max_cache_entry_lifetime,
+            stale_grace: Duration::from_secs(0),
+            clock: Arc::new(SystemClock),
+            capacity,
+            stats: CacheStats::default(),
+        }
+    }
+
+    //Evicts an entry if the cache is full, so there is room for one more
+    fn make_room(&mut self, frequent_ghost_hit: bool) {
+        if !self.entries.is_empty() && self.entries.len() >= self.capacity {
+            self.replace(frequent_ghost_hit);
+        }
+    }
+
+    /*
+     * Evicts from recent if it is over its target, and from frequent
+     * otherwise, remembering the key in the matching ghost list. A hit on
+     * a frequent ghost breaks the tie in favour of evicting from recent.
+     */
+    fn replace(&mut self, frequent_ghost_hit: bool) {
+        let from_recent = !self.recent.is_empty()
+            && (self.recent.len() > self.recent_target
+                || (frequent_ghost_hit && self.recent.len() == self.recent_target)
+                || self.frequent.is_empty());
+        if from_recent {
+            self.evict_oldest_recent(true);
+        } else if let Some(key) = self.frequent.pop_oldest() {
+            self.entries.remove(&key);
+            self.frequent_ghosts.push_newest(&key);
+            self.stats.evictions += 1;
+        }
+    }
+
+    fn evict_oldest_recent(&mut self, remember: bool) {
+        if let Some(key) = self.recent.pop_oldest() {
+            self.entries.remove(&key);
+            if remember {
+                self.recent_ghosts.push_newest(&key);
+            }
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {crate::arc_cache::*, crate::clock::FakeClock};
+
+    fn put_all(cache: &mut ARCCache, keys: &[&str]) {
+        for key in keys {
+            if cache.get(key).is_none() {
+                cache.put(key, key.to_string(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_scan_keeps_frequent_keys() {
+        let mut cache = ARCCache::new(4, Duration::from_secs(60));
+        put_all(&mut cache, &["a", "b", "a", "b"]);
+        assert_eq!(cache.frequent.keys(), vec!["b", "a"]);
+
+        put_all(&mut cache, &["s1", "s2", "s3", "s4", "s5", "s6"]);
+        assert_eq!(cache.get("a"), Some(String::from("a")));
+        assert_eq!(cache.get("b"), Some(String::from("b")));
+        assert_eq!(cache.stats().entries, 4);
+    }
+
+    #[test]
+    fn test_ghost_hit_adapts_target() {
+        let mut cache = ARCCache::new(2, Duration::from_secs(60));
+        put_all(&mut cache, &["a", "a", "b", "c"]);
+        //b was evicted from recent to make room for c
+        assert_eq!(cache.recent_ghosts.keys(), vec!["b"]);
+        assert_eq!(cache.recent_target, 0);
+
+        put_all(&mut cache, &["b"]);
+        assert_eq!(cache.recent_target, 1);
+        assert!(cache.frequent.contains("b"));
+        assert_eq!(cache.stats().entries, 2);
+    }
+
+    #[test]
+    fn test_bounds() {
+        let mut cache = ARCCache::new(8, Duration::from_secs(60));
+        let mut rng = 7u64;
+        for _ in 0..5000 {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            let key = format!("key{}", rng % 40);
+            put_all(&mut cache, &[&key]);
+            if rng % 11 == 0 {
+                cache.remove(&key);
+            }
+            assert!(cache.entries.len() <= 8);
+            assert_eq!(
+                cache.entries.len(),
+                cache.recent.len() + cache.frequent.len()
+            );
+            let ghosts = cache.recent_ghosts.len() + cache.frequent_ghosts.len();
+            assert!(cache.entries.len() + ghosts <= 16);
+            assert!(cache.recent.len() + cache.recent_ghosts.len() <= 8);
+        }
+        cache.set_capacity(3);
+        assert!(cache.entries.len() <= 3);
+        assert!(cache.recent.len() + cache.recent_ghosts.len() <= 3);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let clock = Arc::new(FakeClock::default());
+        let mut cache = ARCCache::new(2, Duration::from_secs(10));
+        cache.set_clock(clock.clone());
+        put_all(&mut cache, &["a"]);
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(cache.get("a"), None);
+        assert!(cache.recent.is_empty());
+        assert_eq!(cache.stats().expirations, 1);
+    }
+}
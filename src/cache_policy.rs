@@ -0,0 +1,250 @@
+use {
+    crate::arc_cache::ARCCache,
+    crate::clock::Clock,
+    crate::lfu_cache::LFUCache,
+    crate::lru_cache::{ByteSize, Cache, CacheStats, LRUCache},
+    crate::tiny_lfu_cache::TinyLFUCache,
+    std::{sync::Arc, time::Duration},
+};
+
+/*
+ * A cache that decides for itself what to evict. Each shard of a
+ * ShardedCache is one of these, so the policy can be chosen at startup
+ * while the rest of the proxy only sees the Cache trait.
+ *
+ * Only the LRU policy can be bounded by bytes, or spread out expiry with
+ * jitter and early refresh. The others count entries and ignore
+ * set_max_bytes and set_early_expiry, and the config refuses to combine
+ * them with the options that set these.
+ */
+pub trait EvictionPolicy<V>: Cache<V> + Send {
+    fn stats(&self) -> CacheStats;
+    fn set_capacity(&mut self, capacity: usize);
+    fn set_max_lifetime(&mut self, max_cache_entry_lifetime: Duration);
+    fn set_stale_grace(&mut self, stale_grace: Duration);
+    fn set_clock(&mut self, clock: Arc<dyn Clock>);
+    fn set_max_bytes(&mut self, _max_bytes: usize, _max_entry_bytes: usize) {}
+    fn set_early_expiry(&mut self, _expr_jitter_percent: usize, _early_refresh_percent: usize) {}
+}
+
+impl<V: Clone + ByteSize + Send> EvictionPolicy<V> for LRUCache<V> {
+    fn stats(&self) -> CacheStats {
+        LRUCache::stats(self)
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        LRUCache::set_capacity(self, capacity)
+    }
+
+    fn set_max_lifetime(&mut self, max_cache_entry_lifetime: Duration) {
+        LRUCache::set_max_lifetime(self, max_cache_entry_lifetime)
+    }
+
+    fn set_stale_grace(&mut self, stale_grace: Duration) {
+        LRUCache::set_stale_grace(self, stale_grace)
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        LRUCache::set_clock(self, clock)
+    }
+
+    fn set_max_bytes(&mut self, max_bytes: usize, max_entry_bytes: usize) {
+        LRUCache::set_max_bytes(self, max_bytes, max_entry_bytes)
+    }
+
+    fn set_early_expiry(&mut self, expr_jitter_percent: usize, early_refresh_percent: usize) {
+        LRUCache::set_early_expiry(self, expr_jitter_percent, early_refresh_percent)
+    }
+}
+
+/*
+ * The policies --cache_policy chooses between:
+ *   lru      evicts the least recently used entry. Cheap and a good
+ *            default, but a scan of keys read once flushes the hot set.
+ *   lfu      evicts the least frequently used entry. Counts are halved
+ *            periodically so keys that were hot once don't stay forever.
+ *   arc      Adaptive Replacement Cache. Splits the cache between keys
+ *            seen once and keys seen again, and remembers recently
+ *            evicted keys to learn which side deserves more room.
+ *   tinylfu  W-TinyLFU. New keys enter a small LRU window, and only move
+ *            on to the main cache if a frequency sketch says they are
+ *            used more often than what they would replace.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CachePolicy {
+    Lru,
+    Lfu,
+    Arc,
+    TinyLfu,
+}
+
+impl CachePolicy {
+    pub const ALL: [CachePolicy; 4] = [
+        CachePolicy::Lru,
+        CachePolicy::Lfu,
+        CachePolicy::Arc,
+        CachePolicy::TinyLfu,
+    ];
+
+    pub fn from_name(name: &str) -> Option<CachePolicy> {
+        CachePolicy::ALL
+            .iter()
+            .cloned()
+            .find(|policy| policy.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CachePolicy::Lru => "lru",
+            CachePolicy::Lfu => "lfu",
+            CachePolicy::Arc => "arc",
+            CachePolicy::TinyLfu => "tinylfu",
+        }
+    }
+
+    pub fn build<V: Clone + ByteSize + Send + 'static>(
+        self,
+        capacity: usize,
+        max_cache_entry_lifetime: Duration,
+    ) -> Box<dyn EvictionPolicy<V>> {
+        match self {
+            CachePolicy::Lru => Box::new(LRUCache::new(capacity, max_cache_entry_lifetime)),
+            CachePolicy::Lfu => Box::new(LFUCache::new(capacity, max_cache_entry_lifetime)),
+            CachePolicy::Arc => Box::new(ARCCache::new(capacity, max_cache_entry_lifetime)),
+            CachePolicy::TinyLfu => Box::new(TinyLFUCache::new(capacity, max_cache_entry_lifetime)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::cache_policy::*,
+        std::{env, fs},
+    };
+
+    //xorshift keeps the traces deterministic without pulling in a rand crate
+    fn next_rand(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    /*
+     * Replays a trace the way the consumer uses the cache: a lookup for
+     * every key, and a put of the value fetched on every miss. Returns
+     * the fraction of lookups that hit.
+     */
+    fn replay(policy: CachePolicy, capacity: usize, trace: &[String]) -> f64 {
+        let mut cache = policy.build::<()>(capacity, Duration::from_secs(3600));
+        let mut hits = 0;
+        for key in trace {
+            match cache.get(key) {
+                Some(()) => hits += 1,
+                None => cache.put(key, (), None),
+            }
+        }
+        assert!(cache.stats().entries as usize <= capacity);
+        hits as f64 / trace.len() as f64
+    }
+
+    //Every policy's hit ratio on the trace, printed as a table row
+    fn report(name: &str, capacity: usize, trace: &[String]) -> Vec<f64> {
+        let ratios: Vec<f64> = CachePolicy::ALL
+            .iter()
+            .map(|policy| replay(*policy, capacity, trace))
+            .collect();
+        let row: Vec<String> = ratios
+            .iter()
+            .map(|ratio| format!("{:>8.1}%", ratio * 100.0))
+            .collect();
+        println!("{:<12}{}", name, row.join(""));
+        ratios
+    }
+
+    //Keys drawn with a Zipf-like skew: key i is picked in proportion to 1/(i+1)
+    fn zipf_trace(keys: usize, len: usize, rng: &mut u64) -> Vec<String> {
+        let weights: Vec<f64> = (0..keys).map(|i| 1.0 / (i + 1) as f64).collect();
+        let total: f64 = weights.iter().sum();
+        let mut cumulative = Vec::with_capacity(keys);
+        let mut sum = 0.0;
+        for weight in &weights {
+            sum += weight / total;
+            cumulative.push(sum);
+        }
+        (0..len)
+            .map(|_| {
+                let point = (next_rand(rng) % 1_000_000) as f64 / 1_000_000.0;
+                let i = cumulative
+                    .iter()
+                    .position(|&c| c >= point)
+                    .unwrap_or(keys - 1);
+                format!("hot{}", i)
+            })
+            .collect()
+    }
+
+    /*
+     * Prints the hit ratio of every policy on a few synthetic traces, and
+     * on the trace in the file named by CACHE_TRACE if it is set, one key
+     * per line. CACHE_TRACE_CAPACITY sets the cache size for it, 1000 by
+     * default. Run with
+     *     cargo test test_trace_replay -- --nocapture
+     * to see the table.
+     */
+    #[test]
+    fn test_trace_replay() {
+        let header: Vec<String> = CachePolicy::ALL
+            .iter()
+            .map(|policy| format!("{:>9}", policy.name()))
+            .collect();
+        println!("{:<12}{}", "trace", header.join(""));
+        let mut rng = 0x9E37_79B9_7F4A_7C15;
+
+        let zipf = zipf_trace(1000, 50_000, &mut rng);
+        let zipf_ratios = report("zipf", 100, &zipf);
+
+        //The same skewed traffic, interrupted by batch jobs that walk a
+        //range of keys once each
+        let mut scan = Vec::new();
+        for (batch, chunk) in zipf.chunks(5000).enumerate() {
+            scan.extend(chunk.iter().cloned());
+            scan.extend((0..2000).map(|i| format!("scan{}-{}", batch, i)));
+        }
+        let scan_ratios = report("zipf+scan", 100, &scan);
+
+        //A loop just bigger than the cache, which LRU never hits
+        let looping: Vec<String> = (0..50_000).map(|i| format!("loop{}", i % 120)).collect();
+        let loop_ratios = report("loop", 100, &looping);
+
+        if let Ok(path) = env::var("CACHE_TRACE") {
+            let trace: Vec<String> = fs::read_to_string(&path)
+                .expect("unreadable CACHE_TRACE")
+                .lines()
+                .map(String::from)
+                .collect();
+            let capacity = env::var("CACHE_TRACE_CAPACITY")
+                .map(|capacity| capacity.parse().expect("invalid CACHE_TRACE_CAPACITY"))
+                .unwrap_or(1000);
+            report(&path, capacity, &trace);
+        }
+
+        //Order is lru, lfu, arc, tinylfu. The frequency aware policies keep
+        //the hot set through a scan where LRU loses it.
+        for ratio in &scan_ratios[1..] {
+            assert!(*ratio > scan_ratios[0], "{:?}", scan_ratios);
+        }
+        assert!(zipf_ratios[3] >= zipf_ratios[0], "{:?}", zipf_ratios);
+        assert!(loop_ratios[0] < 0.01);
+        assert!(loop_ratios[3] > 0.5, "{:?}", loop_ratios);
+    }
+
+    #[test]
+    fn test_policy_names() {
+        for policy in CachePolicy::ALL.iter() {
+            assert_eq!(CachePolicy::from_name(policy.name()), Some(*policy));
+        }
+        assert_eq!(CachePolicy::from_name("fifo"), None);
+    }
+}
@@ -0,0 +1,291 @@
+use {crate::lru_cache::ByteSize, std::mem};
+
+/*
+ * A value as redis holds it, and as the cache holds it in turn. Strings
+ * are the common case, and are kept as bytes since redis strings are
+ * binary safe. The collection types are cached whole, so every view of
+ * a key (one field of a hash, a range of a list, a sorted set with or
+ * without its scores) is served from the one cached copy. They are
+ * answered as JSON, so their members must be UTF-8.
+ *
+ * Set members are kept sorted so a set always reads back the same way.
+ * A sorted set keeps redis' order, lowest score first.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum CacheValue {
+    Str(Vec<u8>),
+    Hash(Vec<(String, String)>),
+    List(Vec<String>),
+    Set(Vec<String>),
+    ZSet(Vec<(String, f64)>),
+}
+
+//The redis types the proxy can serve
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueKind {
+    Str,
+    Hash,
+    List,
+    Set,
+    ZSet,
+}
+
+impl ValueKind {
+    //Maps a reply to TYPE. None for keys that don't exist or hold a type
+    //the proxy doesn't serve, such as a stream
+    pub fn from_type_name(name: &str) -> Option<ValueKind> {
+        match name {
+            "string" => Some(ValueKind::Str),
+            "hash" => Some(ValueKind::Hash),
+            "list" => Some(ValueKind::List),
+            "set" => Some(ValueKind::Set),
+            "zset" => Some(ValueKind::ZSet),
+            _ => None,
+        }
+    }
+
+    //The name TYPE gives the kind
+    pub fn type_name(self) -> &'static str {
+        match self {
+            ValueKind::Str => "string",
+            ValueKind::Hash => "hash",
+            ValueKind::List => "list",
+            ValueKind::Set => "set",
+            ValueKind::ZSet => "zset",
+        }
+    }
+}
+
+/*
+ * A part of a collection that can be read from redis without the rest of
+ * it, so one field of a huge hash doesn't cost fetching the whole hash
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValuePart {
+    HashField(String),
+    //The items from start to stop, counted as LRANGE does
+    ListRange { start: i64, stop: i64 },
+}
+
+impl ValuePart {
+    pub fn kind(&self) -> ValueKind {
+        match self {
+            ValuePart::HashField(_) => ValueKind::Hash,
+            ValuePart::ListRange { .. } => ValueKind::List,
+        }
+    }
+
+    /*
+     * Picks the part out of the whole value: a hash of just the field, or
+     * of nothing if it has no such field, or a list of the items in range.
+     * None if the value is of another kind.
+     */
+    pub fn select(&self, val: &CacheValue) -> Option<CacheValue> {
+        match (self, val) {
+            (ValuePart::HashField(field), CacheValue::Hash(fields)) => Some(CacheValue::Hash(
+                fields
+                    .iter()
+                    .filter(|(name, _)| name == field)
+                    .cloned()
+                    .collect(),
+            )),
+            (ValuePart::ListRange { start, stop }, CacheValue::List(items)) => {
+                Some(CacheValue::List(list_range(items, *start, *stop).to_vec()))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl CacheValue {
+    pub fn kind(&self) -> ValueKind {
+        match self {
+            CacheValue::Str(_) => ValueKind::Str,
+            CacheValue::Hash(_) => ValueKind::Hash,
+            CacheValue::List(_) => ValueKind::List,
+            CacheValue::Set(_) => ValueKind::Set,
+            CacheValue::ZSet(_) => ValueKind::ZSet,
+        }
+    }
+
+    //Fields, items or members in a collection, 1 for a string
+    pub fn members(&self) -> usize {
+        match self {
+            CacheValue::Str(_) => 1,
+            CacheValue::Hash(fields) => fields.len(),
+            CacheValue::List(items) | CacheValue::Set(items) => items.len(),
+            CacheValue::ZSet(members) => members.len(),
+        }
+    }
+
+    //The value if it is a string
+    pub fn into_str(self) -> Option<Vec<u8>> {
+        match self {
+            CacheValue::Str(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    /*
+     * Reads the reply to the command that fetches a key of the given kind:
+     * GET, HGETALL, LRANGE 0 -1, SMEMBERS or ZRANGE 0 -1 WITHSCORES. Redis
+     * never keeps an empty collection, so an empty reply means the key
+     * does not exist.
+     */
+    pub fn from_reply(
+        kind: ValueKind,
+        reply: &redis::Value,
+    ) -> redis::RedisResult<Option<CacheValue>> {
+        let val = match kind {
+            ValueKind::Str => {
+                return Ok(redis::from_redis_value::<Option<Vec<u8>>>(reply)?.map(CacheValue::Str))
+            }
+            ValueKind::Hash => CacheValue::Hash(redis::from_redis_value(reply)?),
+            ValueKind::List => CacheValue::List(redis::from_redis_value(reply)?),
+            ValueKind::Set => {
+                let mut members: Vec<String> = redis::from_redis_value(reply)?;
+                members.sort();
+                CacheValue::Set(members)
+            }
+            ValueKind::ZSet => CacheValue::ZSet(redis::from_redis_value(reply)?),
+        };
+        Ok(if val.members() == 0 { None } else { Some(val) })
+    }
+}
+
+//Each member of a collection is charged its own allocation as well as its bytes
+impl ByteSize for CacheValue {
+    fn heap_bytes(&self) -> usize {
+        match self {
+            CacheValue::Str(val) => val.len(),
+            CacheValue::Hash(fields) => fields
+                .iter()
+                .map(|(field, val)| mem::size_of::<(String, String)>() + field.len() + val.len())
+                .sum(),
+            CacheValue::List(items) | CacheValue::Set(items) => items
+                .iter()
+                .map(|item| mem::size_of::<String>() + item.len())
+                .sum(),
+            CacheValue::ZSet(members) => members
+                .iter()
+                .map(|(member, _)| mem::size_of::<(String, f64)>() + member.len())
+                .sum(),
+        }
+    }
+}
+
+/*
+ * The items of a list from start to stop inclusive, as LRANGE counts
+ * them: negative indices count back from the end, and a range running
+ * past either end is clamped to the list
+ */
+pub fn list_range(items: &[String], start: i64, stop: i64) -> &[String] {
+    let len = items.len() as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop || start >= len {
+        return &[];
+    }
+    &items[start as usize..=stop as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache_value::*;
+
+    fn bulk(items: &[&str]) -> redis::Value {
+        redis::Value::Bulk(
+            items
+                .iter()
+                .map(|item| redis::Value::Data(item.as_bytes().to_vec()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_from_reply() {
+        assert_eq!(
+            CacheValue::from_reply(ValueKind::Str, &redis::Value::Nil),
+            Ok(None)
+        );
+        assert_eq!(
+            CacheValue::from_reply(ValueKind::Str, &redis::Value::Data(vec![0xff, 0])),
+            Ok(Some(CacheValue::Str(vec![0xff, 0])))
+        );
+        assert_eq!(
+            CacheValue::from_reply(ValueKind::Hash, &bulk(&["a", "1", "b", "2"])),
+            Ok(Some(CacheValue::Hash(vec![
+                (String::from("a"), String::from("1")),
+                (String::from("b"), String::from("2")),
+            ])))
+        );
+        assert_eq!(
+            CacheValue::from_reply(ValueKind::Set, &bulk(&["b", "c", "a"])),
+            Ok(Some(CacheValue::Set(vec![
+                String::from("a"),
+                String::from("b"),
+                String::from("c"),
+            ])))
+        );
+        assert_eq!(
+            CacheValue::from_reply(ValueKind::ZSet, &bulk(&["x", "1.5", "y", "inf"])),
+            Ok(Some(CacheValue::ZSet(vec![
+                (String::from("x"), 1.5),
+                (String::from("y"), f64::INFINITY),
+            ])))
+        );
+        assert_eq!(
+            CacheValue::from_reply(ValueKind::List, &bulk(&[])),
+            Ok(None)
+        );
+        assert!(CacheValue::from_reply(ValueKind::ZSet, &bulk(&["x", "high"])).is_err());
+    }
+
+    #[test]
+    fn test_list_range() {
+        let items: Vec<String> = ["a", "b", "c", "d"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(list_range(&items, 0, -1), &items[..]);
+        assert_eq!(list_range(&items, 1, 2), &items[1..3]);
+        assert_eq!(list_range(&items, -2, 100), &items[2..]);
+        assert_eq!(list_range(&items, -100, 0), &items[..1]);
+        assert!(list_range(&items, 3, 1).is_empty());
+        assert!(list_range(&items, 4, 10).is_empty());
+        assert!(list_range(&[], 0, -1).is_empty());
+    }
+
+    #[test]
+    fn test_select_part() {
+        let hash = CacheValue::Hash(vec![
+            (String::from("a"), String::from("1")),
+            (String::from("b"), String::from("2")),
+        ]);
+        assert_eq!(
+            ValuePart::HashField(String::from("b")).select(&hash),
+            Some(CacheValue::Hash(vec![(
+                String::from("b"),
+                String::from("2")
+            )]))
+        );
+        assert_eq!(
+            ValuePart::HashField(String::from("c")).select(&hash),
+            Some(CacheValue::Hash(vec![]))
+        );
+
+        let list = CacheValue::List(vec![String::from("x"), String::from("y")]);
+        let range = ValuePart::ListRange { start: -1, stop: 5 };
+        assert_eq!(
+            range.select(&list),
+            Some(CacheValue::List(vec![String::from("y")]))
+        );
+        assert_eq!(range.select(&hash), None);
+        assert_eq!((hash.members(), list.members()), (2, 2));
+    }
+}
@@ -0,0 +1,52 @@
+use std::time::Instant;
+#[cfg(test)]
+use std::{sync::Mutex, time::Duration};
+
+/*
+ * The time source the caches expire entries by. It is monotonic, so a
+ * wall clock stepped back by NTP can't make entries live forever, nor
+ * one stepped forward expire everything at once.
+ *
+ * The caches take it as a trait object so tests can swap in a FakeClock
+ * and move time on by hand rather than sleeping.
+ */
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+//Stands still until advanced. Only used by tests.
+#[cfg(test)]
+pub struct FakeClock {
+    now: Mutex<Instant>,
+}
+
+#[cfg(test)]
+impl Default for FakeClock {
+    fn default() -> FakeClock {
+        FakeClock {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+#[cfg(test)]
+impl FakeClock {
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
@@ -0,0 +1,842 @@
+use {
+    crate::cache_policy::CachePolicy,
+    redis::IntoConnectionInfo,
+    std::{collections::HashMap, fmt, fs, str::FromStr, time::Duration},
+};
+
+/*
+ * The proxy's configuration is built up in layers, each overriding the
+ * one before it:
+ *   1. the defaults below
+ *   2. a TOML file, named by --config or REDIS_PROXY_CONFIG
+ *   3. environment variables, REDIS_PROXY_ followed by the option name in
+ *      upper case, e.g. REDIS_PROXY_CACHE_SIZE
+ *   4. command line options, --cache_size 100 or --cache_size=100
+ *
+ * Every layer sets options by the same names, so all of them go through
+ * ProxyConfig::set and are validated the same way. A bad value or unknown
+ * option is reported as a ConfigError naming where it came from, rather
+ * than panicking.
+ */
+
+//Prefix of the environment variables the proxy reads
+const ENV_PREFIX: &str = "REDIS_PROXY_";
+
+//Every option that can be set, whether it is a bare flag on the command
+//line, and its help text
+const OPTIONS: [(&str, bool, &str); 21] = [
+    ("address", false, "the address the HTTP listener binds to"),
+    ("port", false, "the port the HTTP listener binds to"),
+    (
+        "cache_expr_sec",
+        false,
+        "sets the time in seconds that values will remain in the cache",
+    ),
+    (
+        "cache_size",
+        false,
+        "sets the Size of the internal LRU cache",
+    ),
+    (
+        "cache_grace_sec",
+        false,
+        "sets the time in seconds expired values may still be served stale",
+    ),
+    (
+        "cache_expr_jitter_percent",
+        false,
+        "cut each entry's lifetime by a random amount up to this percentage",
+    ),
+    (
+        "cache_early_refresh_percent",
+        false,
+        "hits in this last percentage of a lifetime may refresh the entry early",
+    ),
+    (
+        "cache_policy",
+        false,
+        "the eviction policy of the cache: lru, lfu, arc or tinylfu",
+    ),
+    (
+        "cache_max_bytes",
+        false,
+        "bound the cache by the size of its entries instead, e.g. 64M",
+    ),
+    (
+        "cache_max_entry_percent",
+        false,
+        "the largest value cached, as a percentage of cache_max_bytes",
+    ),
+    (
+        "negative_cache_size",
+        false,
+        "the number of misses to remember, 0 turns negative caching off",
+    ),
+    (
+        "negative_cache_expr_sec",
+        false,
+        "sets the time in seconds that misses will remain in the cache",
+    ),
+    ("redis_addr", false, "the address of the backing redis"),
+    (
+        "redis_pool_size",
+        false,
+        "the maximum number of open connections to redis",
+    ),
+    (
+        "redis_pool_idle_sec",
+        false,
+        "sets the time in seconds an idle redis connection is kept open",
+    ),
+    (
+        "redis_startup_timeout_sec",
+        false,
+        "sets the time in seconds to keep retrying redis at startup",
+    ),
+    (
+        "workers",
+        false,
+        "the number of consumer threads serving requests",
+    ),
+    (
+        "resp_port",
+        false,
+        "also serve the redis protocol on this port",
+    ),
+    (
+        "request_timeout_ms",
+        false,
+        "sets the time in milliseconds a request may wait for a result",
+    ),
+    (
+        "legacy_empty_miss",
+        true,
+        "answer misses with 200 and an empty body instead of 404",
+    ),
+    (
+        "keyspace_invalidation",
+        true,
+        "drop cached entries when redis publishes a keyspace event for them",
+    ),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyConfig {
+    pub address: String,
+    pub port: u16,
+    pub cache_expr: Duration,
+    pub cache_size: usize,
+    //0 turns stale serving off
+    pub cache_grace: Duration,
+    //0 turns jitter and early refresh off
+    pub cache_expr_jitter_percent: usize,
+    pub cache_early_refresh_percent: usize,
+    pub cache_policy: CachePolicy,
+    //0 bounds the cache by cache_size alone
+    pub cache_max_bytes: usize,
+    pub cache_max_entry_percent: usize,
+    pub negative_cache_size: usize,
+    pub negative_cache_expr: Duration,
+    pub redis_addr: String,
+    pub redis_pool_size: usize,
+    pub redis_pool_idle: Duration,
+    pub redis_startup_timeout: Duration,
+    pub workers: usize,
+    pub resp_port: Option<u16>,
+    pub request_timeout: Duration,
+    pub legacy_empty_miss: bool,
+    pub keyspace_invalidation: bool,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> ProxyConfig {
+        ProxyConfig {
+            address: String::from("0.0.0.0"),
+            port: 8000,
+            cache_expr: Duration::from_secs(10),
+            cache_size: 100,
+            cache_grace: Duration::from_secs(0),
+            cache_expr_jitter_percent: 0,
+            cache_early_refresh_percent: 0,
+            cache_policy: CachePolicy::Lru,
+            cache_max_bytes: 0,
+            cache_max_entry_percent: 10,
+            negative_cache_size: 0,
+            negative_cache_expr: Duration::from_secs(1),
+            redis_addr: String::from("redis://127.0.0.1/"),
+            redis_pool_size: 8,
+            redis_pool_idle: Duration::from_secs(60),
+            redis_startup_timeout: Duration::from_secs(60),
+            workers: 4,
+            resp_port: None,
+            request_timeout: Duration::from_millis(5000),
+            legacy_empty_miss: false,
+            keyspace_invalidation: false,
+        }
+    }
+}
+
+//Where a bad setting came from, e.g. "--cache_size" or "proxy.toml
+//cache.size", and what was wrong with it
+#[derive(Debug, PartialEq)]
+pub struct ConfigError {
+    pub source: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.source, self.message)
+    }
+}
+
+//What main should do once the command line has been read
+#[derive(Debug, PartialEq)]
+pub enum Startup {
+    Run(ProxyConfig),
+    PrintConfig(ProxyConfig),
+    Help,
+}
+
+impl ProxyConfig {
+    /*
+     * Builds the config from every layer. args includes the program name,
+     * as std::env::args does, and env holds the environment variables.
+     */
+    pub fn load(args: &[String], env: &HashMap<String, String>) -> Result<Startup, ConfigError> {
+        let command_line = match parse_command_line(args)? {
+            Some(command_line) => command_line,
+            None => return Ok(Startup::Help),
+        };
+        let mut config = ProxyConfig::default();
+
+        let config_path = command_line
+            .config_path
+            .or_else(|| env.get("REDIS_PROXY_CONFIG").cloned());
+        if let Some(path) = config_path {
+            let contents = fs::read_to_string(&path).map_err(|err| ConfigError {
+                source: path.clone(),
+                message: err.to_string(),
+            })?;
+            for setting in parse_toml(&path, &contents)? {
+                config.apply(setting)?;
+            }
+        }
+
+        for setting in env_settings(env) {
+            config.apply(setting)?;
+        }
+        for setting in command_line.settings {
+            config.apply(setting)?;
+        }
+        //Settings that only conflict with each other are checked once
+        //every layer has been applied, as a later one may resolve it
+        let lru_only = [
+            (
+                "cache_max_bytes",
+                config.cache_max_bytes > 0,
+                "be bounded by bytes",
+            ),
+            (
+                "cache_expr_jitter_percent",
+                config.cache_expr_jitter_percent > 0,
+                "jitter entry lifetimes",
+            ),
+            (
+                "cache_early_refresh_percent",
+                config.cache_early_refresh_percent > 0,
+                "refresh entries early",
+            ),
+        ];
+        for (name, set, feature) in lru_only.iter() {
+            if *set && config.cache_policy != CachePolicy::Lru {
+                return Err(ConfigError {
+                    source: name.to_string(),
+                    message: format!(
+                        "the {} cache policy can't {}, only lru can",
+                        config.cache_policy.name(),
+                        feature
+                    ),
+                });
+            }
+        }
+        if command_line.print_config {
+            return Ok(Startup::PrintConfig(config));
+        }
+        Ok(Startup::Run(config))
+    }
+
+    fn apply(&mut self, setting: Setting) -> Result<(), ConfigError> {
+        self.set(&setting.name, &setting.value)
+            .map_err(|message| ConfigError {
+                source: setting.source,
+                message,
+            })
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "address" if value.is_empty() => return Err(String::from("must not be empty")),
+            "address" => self.address = value.to_string(),
+            "port" => self.port = parse(value)?,
+            "cache_expr_sec" => self.cache_expr = Duration::from_secs(parse(value)?),
+            "cache_size" => self.cache_size = at_least_one(value)?,
+            "cache_grace_sec" => self.cache_grace = Duration::from_secs(parse(value)?),
+            "cache_expr_jitter_percent" => self.cache_expr_jitter_percent = percent(value)?,
+            "cache_early_refresh_percent" => self.cache_early_refresh_percent = percent(value)?,
+            "cache_policy" => {
+                self.cache_policy = CachePolicy::from_name(value)
+                    .ok_or_else(|| format!("unknown cache policy {:?}", value))?
+            }
+            "cache_max_bytes" => self.cache_max_bytes = parse_bytes(value)?,
+            "cache_max_entry_percent" => match at_least_one(value)? {
+                percent if percent > 100 => return Err(String::from("must be at most 100")),
+                percent => self.cache_max_entry_percent = percent,
+            },
+            "negative_cache_size" => self.negative_cache_size = parse(value)?,
+            "negative_cache_expr_sec" => {
+                self.negative_cache_expr = Duration::from_secs(parse(value)?)
+            }
+            "redis_addr" => {
+                value
+                    .into_connection_info()
+                    .map_err(|err| format!("invalid redis address {:?} {}", value, err))?;
+                self.redis_addr = value.to_string();
+            }
+            "redis_pool_size" => self.redis_pool_size = at_least_one(value)?,
+            "redis_pool_idle_sec" => self.redis_pool_idle = Duration::from_secs(parse(value)?),
+            "redis_startup_timeout_sec" => {
+                self.redis_startup_timeout = Duration::from_secs(parse(value)?)
+            }
+            "workers" => self.workers = at_least_one(value)?,
+            "resp_port" => self.resp_port = Some(parse(value)?),
+            "request_timeout_ms" => {
+                self.request_timeout = Duration::from_millis(at_least_one(value)? as u64)
+            }
+            "legacy_empty_miss" => self.legacy_empty_miss = parse(value)?,
+            "keyspace_invalidation" => self.keyspace_invalidation = parse(value)?,
+            _ => return Err(format!("unknown option {}", name)),
+        }
+        Ok(())
+    }
+
+    //The largest entry the cache takes when it is bounded by bytes
+    pub fn cache_max_entry_bytes(&self) -> usize {
+        (self.cache_max_bytes as u128 * self.cache_max_entry_percent as u128 / 100) as usize
+    }
+
+    //The effective config in the same TOML format --config reads
+    pub fn to_toml(&self) -> String {
+        let mut lines = vec![
+            format!("address = {:?}", self.address),
+            format!("port = {}", self.port),
+            format!("cache_expr_sec = {}", self.cache_expr.as_secs()),
+            format!("cache_size = {}", self.cache_size),
+            format!("cache_grace_sec = {}", self.cache_grace.as_secs()),
+            format!(
+                "cache_expr_jitter_percent = {}",
+                self.cache_expr_jitter_percent
+            ),
+            format!(
+                "cache_early_refresh_percent = {}",
+                self.cache_early_refresh_percent
+            ),
+            format!("cache_policy = {:?}", self.cache_policy.name()),
+            format!("cache_max_bytes = {}", self.cache_max_bytes),
+            format!("cache_max_entry_percent = {}", self.cache_max_entry_percent),
+            format!("negative_cache_size = {}", self.negative_cache_size),
+            format!(
+                "negative_cache_expr_sec = {}",
+                self.negative_cache_expr.as_secs()
+            ),
+            format!("redis_addr = {:?}", self.redis_addr),
+            format!("redis_pool_size = {}", self.redis_pool_size),
+            format!("redis_pool_idle_sec = {}", self.redis_pool_idle.as_secs()),
+            format!(
+                "redis_startup_timeout_sec = {}",
+                self.redis_startup_timeout.as_secs()
+            ),
+            format!("workers = {}", self.workers),
+        ];
+        match self.resp_port {
+            Some(port) => lines.push(format!("resp_port = {}", port)),
+            None => lines.push(String::from("#resp_port is not set")),
+        }
+        lines.push(format!(
+            "request_timeout_ms = {}",
+            self.request_timeout.as_millis()
+        ));
+        lines.push(format!("legacy_empty_miss = {}", self.legacy_empty_miss));
+        lines.push(format!(
+            "keyspace_invalidation = {}",
+            self.keyspace_invalidation
+        ));
+        lines.join("\n") + "\n"
+    }
+}
+
+//Options that only make sense on the command line
+const COMMAND_LINE_OPTIONS: [(&str, &str); 3] = [
+    ("config", "read options from this TOML file"),
+    (
+        "print-config",
+        "print the effective configuration as TOML and exit",
+    ),
+    ("help", "print this message and exit"),
+];
+
+pub fn help() {
+    println!("Usage:\n    redis_proxy [options]\n\n    Options:");
+    let options = COMMAND_LINE_OPTIONS
+        .iter()
+        .cloned()
+        .chain(OPTIONS.iter().map(|(name, _, help)| (*name, *help)));
+    for (name, help) in options {
+        let flag = format!("--{}", name);
+        if flag.len() < 22 {
+            println!("    {:<22}{}", flag, help);
+        } else {
+            println!("    {}\n    {:<22}{}", flag, "", help);
+        }
+    }
+    println!(
+        "\n    Every option can also be set in the config file, or with an environment\n    \
+         variable such as {}CACHE_SIZE",
+        ENV_PREFIX
+    );
+}
+
+//One option set by one of the layers
+#[derive(Debug, PartialEq)]
+struct Setting {
+    name: String,
+    value: String,
+    source: String,
+}
+
+struct CommandLine {
+    config_path: Option<String>,
+    print_config: bool,
+    settings: Vec<Setting>,
+}
+
+//Returns None if --help was passed
+fn parse_command_line(args: &[String]) -> Result<Option<CommandLine>, ConfigError> {
+    let mut command_line = CommandLine {
+        config_path: None,
+        print_config: false,
+        settings: Vec::new(),
+    };
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let option = match arg.strip_prefix("--") {
+            Some(option) => option,
+            None => {
+                return Err(ConfigError {
+                    source: arg.clone(),
+                    message: String::from("expected an option starting with --"),
+                })
+            }
+        };
+        match option {
+            "help" => return Ok(None),
+            "print-config" => {
+                command_line.print_config = true;
+                continue;
+            }
+            _ => (),
+        }
+
+        let (name, value) = match option.find('=') {
+            Some(pos) => (&option[..pos], Some(option[pos + 1..].to_string())),
+            None => (option, None),
+        };
+        let bare_flag = OPTIONS
+            .iter()
+            .any(|(option_name, flag, _)| *flag && *option_name == name);
+        let value = match value {
+            Some(value) => value,
+            None if bare_flag => String::from("true"),
+            None => args.next().cloned().ok_or_else(|| ConfigError {
+                source: arg.clone(),
+                message: String::from("missing value"),
+            })?,
+        };
+
+        if name == "config" {
+            command_line.config_path = Some(value);
+        } else {
+            command_line.settings.push(Setting {
+                name: name.to_string(),
+                value,
+                source: format!("--{}", name),
+            });
+        }
+    }
+    Ok(Some(command_line))
+}
+
+/*
+ * Every REDIS_PROXY_ variable other than REDIS_PROXY_CONFIG must name an
+ * option, so a typo is reported rather than silently ignored
+ */
+fn env_settings(env: &HashMap<String, String>) -> Vec<Setting> {
+    let mut vars: Vec<(&String, &String)> = env
+        .iter()
+        .filter(|(var, _)| var.starts_with(ENV_PREFIX) && *var != "REDIS_PROXY_CONFIG")
+        .collect();
+    vars.sort();
+    vars.into_iter()
+        .map(|(var, value)| Setting {
+            name: var[ENV_PREFIX.len()..].to_lowercase(),
+            value: value.clone(),
+            source: var.clone(),
+        })
+        .collect()
+}
+
+/*
+ * Reads the options out of a TOML file. Every value has to be a string, a
+ * number or a boolean. A key in a [section] table is the option named
+ * section_key, so `size = 100` under `[cache]` sets cache_size.
+ */
+fn parse_toml(path: &str, contents: &str) -> Result<Vec<Setting>, ConfigError> {
+    let table = match contents.parse::<toml::Value>() {
+        Ok(toml::Value::Table(table)) => table,
+        Ok(_) => unreachable!("a TOML document is always a table"),
+        Err(err) => {
+            return Err(ConfigError {
+                source: path.to_string(),
+                message: err.to_string(),
+            })
+        }
+    };
+    let mut settings = Vec::new();
+    for (key, value) in &table {
+        match value {
+            toml::Value::Table(section) => {
+                for (section_key, value) in section {
+                    settings.push(toml_setting(
+                        path,
+                        &format!("{}.{}", key, section_key),
+                        &format!("{}_{}", key, section_key),
+                        value,
+                    )?);
+                }
+            }
+            value => settings.push(toml_setting(path, key, key, value)?),
+        }
+    }
+    Ok(settings)
+}
+
+fn toml_setting(
+    path: &str,
+    key: &str,
+    name: &str,
+    value: &toml::Value,
+) -> Result<Setting, ConfigError> {
+    let source = format!("{} {}", path, key);
+    let value = match value {
+        toml::Value::String(value) => value.clone(),
+        toml::Value::Integer(value) => value.to_string(),
+        toml::Value::Float(value) => value.to_string(),
+        toml::Value::Boolean(value) => value.to_string(),
+        _ => {
+            return Err(ConfigError {
+                source,
+                message: String::from("expected a string, a number or a boolean"),
+            })
+        }
+    };
+    Ok(Setting {
+        name: name.to_string(),
+        value,
+        source,
+    })
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value {:?}", value))
+}
+
+fn at_least_one(value: &str) -> Result<usize, String> {
+    match parse(value)? {
+        0 => Err(String::from("must be at least 1")),
+        n => Ok(n),
+    }
+}
+
+fn percent(value: &str) -> Result<usize, String> {
+    match parse(value)? {
+        n if n > 100 => Err(String::from("must be at most 100")),
+        n => Ok(n),
+    }
+}
+
+//A number of bytes, optionally with a K, M or G suffix for powers of 1024
+fn parse_bytes(value: &str) -> Result<usize, String> {
+    let (digits, unit) = match value.char_indices().last() {
+        Some((pos, 'K')) | Some((pos, 'k')) => (&value[..pos], 1 << 10),
+        Some((pos, 'M')) | Some((pos, 'm')) => (&value[..pos], 1 << 20),
+        Some((pos, 'G')) | Some((pos, 'g')) => (&value[..pos], 1 << 30),
+        _ => (value, 1),
+    };
+    parse::<usize>(digits)
+        .map_err(|_| format!("invalid value {:?}", value))?
+        .checked_mul(unit)
+        .ok_or_else(|| format!("{:?} is too large", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use {crate::config::*, std::env, std::process};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        std::iter::once("redis_proxy")
+            .chain(args.iter().cloned())
+            .map(String::from)
+            .collect()
+    }
+
+    fn load(cli: &[&str], env: &[(&str, &str)]) -> Result<ProxyConfig, ConfigError> {
+        let env = env
+            .iter()
+            .map(|(var, value)| (var.to_string(), value.to_string()))
+            .collect();
+        match ProxyConfig::load(&args(cli), &env)? {
+            Startup::Run(config) => Ok(config),
+            startup => panic!("unexpected {:?}", startup),
+        }
+    }
+
+    fn write_config_file(name: &str, contents: &str) -> String {
+        let path = env::temp_dir().join(format!("redis_proxy_{}_{}.toml", name, process::id()));
+        fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(load(&[], &[]).unwrap(), ProxyConfig::default());
+        assert_eq!(
+            ProxyConfig::load(&args(&["--cache_size", "5", "--help"]), &HashMap::new()),
+            Ok(Startup::Help)
+        );
+        let mut config = ProxyConfig::default();
+        config.cache_size = 5;
+        assert_eq!(
+            ProxyConfig::load(
+                &args(&["--print-config", "--cache_size", "5"]),
+                &HashMap::new()
+            ),
+            Ok(Startup::PrintConfig(config))
+        );
+    }
+
+    #[test]
+    fn test_layers_override_in_order() {
+        let path = write_config_file(
+            "layers",
+            "# proxy config\n\
+             workers = 2\n\
+             \n\
+             [cache]\n\
+             size = 500 # entries\n\
+             expr_sec = 30\n\
+             \n\
+             [redis]\n\
+             addr = \"redis://file:6379/\"\n",
+        );
+        let config = load(
+            &["--config", &path, "--cache_size=7", "--legacy_empty_miss"],
+            &[
+                ("REDIS_PROXY_CACHE_SIZE", "50"),
+                ("REDIS_PROXY_CACHE_EXPR_SEC", "20"),
+                ("HOME", "/root"),
+            ],
+        )
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.workers, 2);
+        assert_eq!(config.redis_addr, "redis://file:6379/");
+        assert_eq!(config.cache_expr, Duration::from_secs(20));
+        assert_eq!(config.cache_size, 7);
+        assert!(config.legacy_empty_miss);
+        assert!(!config.keyspace_invalidation);
+    }
+
+    #[test]
+    fn test_cache_max_bytes() {
+        let config = load(
+            &[
+                "--cache_max_bytes",
+                "64M",
+                "--cache_max_entry_percent",
+                "25",
+            ],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(config.cache_max_bytes, 64 << 20);
+        assert_eq!(config.cache_max_entry_bytes(), 16 << 20);
+        assert_eq!(parse_bytes("512"), Ok(512));
+        assert_eq!(parse_bytes("2k"), Ok(2048));
+        assert_eq!(parse_bytes("1G"), Ok(1 << 30));
+        assert!(parse_bytes("M").is_err());
+    }
+
+    #[test]
+    fn test_config_path_from_env() {
+        let path = write_config_file("env_path", "resp_port = 6380\n");
+        let config = load(&[], &[("REDIS_PROXY_CONFIG", &path)]).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(config.resp_port, Some(6380));
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        let error = |source: &str, message: &str| ConfigError {
+            source: source.to_string(),
+            message: message.to_string(),
+        };
+        assert_eq!(
+            load(&["--cache_size", "lots"], &[]),
+            Err(error("--cache_size", "invalid value \"lots\""))
+        );
+        assert_eq!(
+            load(&["--workers", "0"], &[]),
+            Err(error("--workers", "must be at least 1"))
+        );
+        assert_eq!(
+            load(&["--request_timeout_ms"], &[]),
+            Err(error("--request_timeout_ms", "missing value"))
+        );
+        assert_eq!(
+            load(&["--cache_sise", "5"], &[]),
+            Err(error("--cache_sise", "unknown option cache_sise"))
+        );
+        assert_eq!(
+            load(&["8000"], &[]),
+            Err(error("8000", "expected an option starting with --"))
+        );
+        assert_eq!(
+            load(&[], &[("REDIS_PROXY_PORT", "99999")]),
+            Err(error("REDIS_PROXY_PORT", "invalid value \"99999\""))
+        );
+        assert_eq!(
+            load(&["--cache_max_entry_percent", "150"], &[]),
+            Err(error("--cache_max_entry_percent", "must be at most 100"))
+        );
+        assert_eq!(
+            load(&["--cache_max_bytes", "64X"], &[]),
+            Err(error("--cache_max_bytes", "invalid value \"64X\""))
+        );
+        assert_eq!(
+            load(&["--cache_policy", "fifo"], &[]),
+            Err(error("--cache_policy", "unknown cache policy \"fifo\""))
+        );
+        assert_eq!(
+            load(&["--cache_policy", "arc", "--cache_max_bytes", "1M"], &[]),
+            Err(error(
+                "cache_max_bytes",
+                "the arc cache policy can't be bounded by bytes, only lru can"
+            ))
+        );
+        assert_eq!(
+            load(
+                &[
+                    "--cache_policy",
+                    "lfu",
+                    "--cache_early_refresh_percent",
+                    "5"
+                ],
+                &[]
+            ),
+            Err(error(
+                "cache_early_refresh_percent",
+                "the lfu cache policy can't refresh entries early, only lru can"
+            ))
+        );
+        assert_eq!(
+            load(&["--cache_expr_jitter_percent", "101"], &[]),
+            Err(error("--cache_expr_jitter_percent", "must be at most 100"))
+        );
+        assert!(load(&["--redis_addr", "localhost:6379"], &[]).is_err());
+        assert!(load(&["--config", "/nonexistent/proxy.toml"], &[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_toml() {
+        let settings = parse_toml(
+            "proxy.toml",
+            "address = \"127.0.0.1\" # loopback only\n[redis]\npool_size=3\n",
+        )
+        .unwrap();
+        assert_eq!(
+            settings,
+            vec![
+                Setting {
+                    name: String::from("address"),
+                    value: String::from("127.0.0.1"),
+                    source: String::from("proxy.toml address"),
+                },
+                Setting {
+                    name: String::from("redis_pool_size"),
+                    value: String::from("3"),
+                    source: String::from("proxy.toml redis.pool_size"),
+                },
+            ]
+        );
+
+        //Anything TOML allows in a string
+        let settings = parse_toml(
+            "proxy.toml",
+            "redis_addr = \"\"\"\nredis://\\u0061:6379/\"\"\"\naddress = 'C:\\x'\n",
+        )
+        .unwrap();
+        assert_eq!(settings[0].value, "C:\\x");
+        assert_eq!(settings[1].value, "redis://a:6379/");
+
+        for bad in &[
+            "cache_size",
+            "cache_size = ",
+            "addr = \"open",
+            "addr = bare words",
+            "[cache",
+            "workers = [1, 2]",
+            "[cache.shard]\nsize = 1",
+        ] {
+            assert!(parse_toml("proxy.toml", bad).is_err(), "accepted {:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_printed_config_reloads() {
+        let mut config = ProxyConfig::default();
+        config.resp_port = Some(6380);
+        config.keyspace_invalidation = true;
+        config.request_timeout = Duration::from_millis(250);
+        config.cache_grace = Duration::from_secs(30);
+
+        let path = write_config_file("print", &config.to_toml());
+        let reloaded = load(&["--config", &path], &[]).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(reloaded, config);
+
+        //every option in the table is one set understands
+        for (name, bare_flag, _) in OPTIONS.iter() {
+            let value = match *name {
+                _ if *bare_flag => "true",
+                "redis_addr" => "redis://127.0.0.1/",
+                "address" => "localhost",
+                "cache_policy" => "lfu",
+                _ => "1",
+            };
+            assert!(ProxyConfig::default().set(name, value).is_ok(), "{}", name);
+        }
+    }
+}
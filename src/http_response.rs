@@ -0,0 +1,664 @@
+use {
+    crate::cache_value::{list_range, CacheValue, ValueKind, ValuePart},
+    crate::metrics::ProxyMetrics,
+    crate::redis_request::ProxyResult,
+    rocket::{
+        fairing::{Fairing, Info, Kind},
+        http::{Accept, ContentType, Status},
+        request::Request,
+        response::{self, Responder, Response},
+        Data,
+    },
+    serde_json::{json, Map, Value},
+    std::{cmp::Ordering, io::Cursor, str, sync::Arc, time::Instant},
+};
+
+/*
+ * The ProxyResponse maps the outcome of a RedisRequest onto an HTTP
+ * response:
+ *   - hit                        200 with the value as the body, as
+ *                                text/plain or application/octet-stream
+ *                                by the Accept header, see negotiate
+ *   - hash, list, set or zset    200 with JSON on its typed route, see
+ *                                from_typed
+ *   - value of the wrong kind    409
+ *   - batch of values            200 with a JSON object, see from_batch
+ *   - miss                       404 (or 200 with an empty body in legacy mode)
+ *   - stale value                as a hit, with X-Cache: STALE and a
+ *                                Warning: 110 header
+ *   - value stored               204
+ *   - key deleted                204, or 404 if it did not exist
+ *   - request timed out          504
+ *   - redis unreachable          503
+ *   - proxy shutting down        503
+ *   - redis replied with error   502
+ *
+ * Errors carry a small JSON body so clients can tell failures apart from
+ * values without parsing the status line alone.
+ */
+#[derive(Debug, PartialEq)]
+pub struct ProxyResponse {
+    status: Status,
+    //None for responses without a body
+    content_type: Option<ContentType>,
+    body: Vec<u8>,
+    //The body is a stored value, so its content type is picked from the
+    //request's Accept header when the response is sent
+    negotiate: bool,
+    //The value expired and is served from the cache's grace window
+    stale: bool,
+}
+
+impl ProxyResponse {
+    /*
+     * legacy_empty_miss restores the original behaviour of answering a miss
+     * with 200 and an empty body, for clients written against it
+     */
+    pub fn from_proxy_result(result: ProxyResult, legacy_empty_miss: bool) -> ProxyResponse {
+        match result {
+            ProxyResult::Hit(val) => ProxyResponse::value(val),
+            ProxyResult::Value(val) | ProxyResult::Part(val) => {
+                ProxyResponse::wrong_type(val.kind(), ValueKind::Str)
+            }
+            ProxyResult::WrongType(held) => ProxyResponse::wrong_type(held, ValueKind::Str),
+            ProxyResult::Stale(val) => {
+                ProxyResponse::from_proxy_result(ProxyResult::from(val), false).stale()
+            }
+            ProxyResult::Pong => ProxyResponse::text(Status::Ok, String::from("PONG")),
+            ProxyResult::Values(vals) => {
+                let vals: Vec<Value> = vals.iter().map(|val| json_value(val)).collect();
+                ProxyResponse::json(Status::Ok, Value::from(vals).to_string())
+            }
+            ProxyResult::StaleValues(vals) => {
+                ProxyResponse::from_proxy_result(ProxyResult::Values(vals), false).stale()
+            }
+            ProxyResult::Miss if legacy_empty_miss => {
+                ProxyResponse::text(Status::Ok, String::new())
+            }
+            ProxyResult::Miss | ProxyResult::Deleted(false) => {
+                ProxyResponse::error(Status::NotFound, "not_found", "key not found")
+            }
+            ProxyResult::Stored | ProxyResult::Deleted(true) => ProxyResponse {
+                status: Status::NoContent,
+                content_type: None,
+                body: Vec::new(),
+                negotiate: false,
+                stale: false,
+            },
+            ProxyResult::Timeout => ProxyResponse::error(
+                Status::GatewayTimeout,
+                "backend_timeout",
+                "the request timed out",
+            ),
+            ProxyResult::Shutdown => ProxyResponse::error(
+                Status::ServiceUnavailable,
+                "shutting_down",
+                "the proxy is shutting down",
+            ),
+            ProxyResult::BackendError(err) if err.is_connection_refusal() || err.is_io_error() => {
+                ProxyResponse::error(
+                    Status::ServiceUnavailable,
+                    "backend_unavailable",
+                    &err.to_string(),
+                )
+            }
+            ProxyResult::BackendError(err) => {
+                ProxyResponse::error(Status::BadGateway, "backend_error", &err.to_string())
+            }
+        }
+    }
+
+    /*
+     * Answers a batch get with a JSON object from each key to its value,
+     * or null for a key that was not found. keys must be in the order
+     * they were requested in. A failure fails the whole batch. If any
+     * value was served stale the whole response is marked stale.
+     *
+     * JSON strings can only carry text, so a value that isn't UTF-8 is
+     * written as an object holding it in base64, {"base64":"AAEC"}.
+     */
+    pub fn from_batch(keys: &[String], result: ProxyResult) -> ProxyResponse {
+        match result {
+            ProxyResult::Values(vals) => {
+                let fields: Map<String, Value> = keys
+                    .iter()
+                    .zip(vals.iter())
+                    .map(|(key, val)| (key.clone(), json_value(val)))
+                    .collect();
+                ProxyResponse::json(Status::Ok, Value::from(fields).to_string())
+            }
+            ProxyResult::StaleValues(vals) => {
+                ProxyResponse::from_batch(keys, ProxyResult::Values(vals)).stale()
+            }
+            failure => ProxyResponse::from_proxy_result(failure, false),
+        }
+    }
+
+    /*
+     * Answers a typed route with the part of the value it asked for. A key
+     * holding some other kind of value is a 409 rather than a miss, so a
+     * client can tell it apart from a key that doesn't exist.
+     */
+    pub fn from_typed(result: ProxyResult, view: &TypedView) -> ProxyResponse {
+        let val = match result {
+            //Only the items asked for were read, so they are already in range
+            ProxyResult::Part(CacheValue::List(items)) => {
+                return ProxyResponse::json(Status::Ok, json_array(&items))
+            }
+            ProxyResult::Value(val) | ProxyResult::Part(val) if val.kind() == view.kind() => val,
+            ProxyResult::Value(val) | ProxyResult::Part(val) => {
+                return ProxyResponse::wrong_type(val.kind(), view.kind())
+            }
+            ProxyResult::WrongType(held) => return ProxyResponse::wrong_type(held, view.kind()),
+            ProxyResult::Hit(_) => return ProxyResponse::wrong_type(ValueKind::Str, view.kind()),
+            ProxyResult::Stale(val) => {
+                return ProxyResponse::from_typed(ProxyResult::from(val), view).stale()
+            }
+            failure => return ProxyResponse::from_proxy_result(failure, false),
+        };
+        match (view, &val) {
+            (TypedView::HashField(field), CacheValue::Hash(fields)) => {
+                match fields.iter().find(|(name, _)| name == field) {
+                    Some((_, val)) => ProxyResponse::text(Status::Ok, val.clone()),
+                    None => ProxyResponse::error(Status::NotFound, "not_found", "field not found"),
+                }
+            }
+            (TypedView::Hash, CacheValue::Hash(fields)) => {
+                let fields: Map<String, Value> = fields
+                    .iter()
+                    .map(|(name, val)| (name.clone(), Value::from(val.as_str())))
+                    .collect();
+                ProxyResponse::json(Status::Ok, Value::from(fields).to_string())
+            }
+            (TypedView::List { start, stop }, CacheValue::List(items)) => {
+                ProxyResponse::json(Status::Ok, json_array(list_range(items, *start, *stop)))
+            }
+            (TypedView::Set, CacheValue::Set(members)) => {
+                ProxyResponse::json(Status::Ok, json_array(members))
+            }
+            (TypedView::ZSet { withscores: false }, CacheValue::ZSet(members)) => {
+                let members: Vec<String> =
+                    members.iter().map(|(member, _)| member.clone()).collect();
+                ProxyResponse::json(Status::Ok, json_array(&members))
+            }
+            (TypedView::ZSet { withscores: true }, CacheValue::ZSet(members)) => {
+                let members: Vec<Value> = members
+                    .iter()
+                    .map(|(member, score)| json!({"member": member, "score": json_score(*score)}))
+                    .collect();
+                ProxyResponse::json(Status::Ok, Value::from(members).to_string())
+            }
+            _ => unreachable!("the value's kind was checked against the view"),
+        }
+    }
+
+    fn wrong_type(held: ValueKind, wanted: ValueKind) -> ProxyResponse {
+        ProxyResponse::error(
+            Status::Conflict,
+            "wrong_type",
+            &format!(
+                "the key holds a {}, not a {}",
+                held.type_name(),
+                wanted.type_name()
+            ),
+        )
+    }
+
+    fn stale(mut self) -> ProxyResponse {
+        self.stale = true;
+        self
+    }
+
+    //A stored value, text/plain unless negotiate finds otherwise
+    fn value(val: Vec<u8>) -> ProxyResponse {
+        ProxyResponse {
+            negotiate: true,
+            ..ProxyResponse::text(Status::Ok, val)
+        }
+    }
+
+    fn text(status: Status, body: impl Into<Vec<u8>>) -> ProxyResponse {
+        ProxyResponse {
+            status,
+            content_type: Some(ContentType::Plain),
+            body: body.into(),
+            negotiate: false,
+            stale: false,
+        }
+    }
+
+    pub fn json(status: Status, body: String) -> ProxyResponse {
+        ProxyResponse {
+            status,
+            content_type: Some(ContentType::JSON),
+            body: body.into_bytes(),
+            negotiate: false,
+            stale: false,
+        }
+    }
+
+    pub fn error(status: Status, error: &str, message: &str) -> ProxyResponse {
+        let body = json!({"status": status.code, "error": error, "message": message});
+        ProxyResponse::json(status, body.to_string())
+    }
+}
+
+/*
+ * The part of a hash, list, set or sorted set a typed route asks for
+ */
+#[derive(Debug, PartialEq)]
+pub enum TypedView {
+    //The value of one field of a hash
+    HashField(String),
+    Hash,
+    //The items from start to stop, counted as LRANGE does
+    List { start: i64, stop: i64 },
+    Set,
+    ZSet { withscores: bool },
+}
+
+impl TypedView {
+    pub fn kind(&self) -> ValueKind {
+        match self {
+            TypedView::HashField(_) | TypedView::Hash => ValueKind::Hash,
+            TypedView::List { .. } => ValueKind::List,
+            TypedView::Set => ValueKind::Set,
+            TypedView::ZSet { .. } => ValueKind::ZSet,
+        }
+    }
+
+    //The part of the value the view shows, if it isn't all of it
+    pub fn part(&self) -> Option<ValuePart> {
+        match self {
+            TypedView::HashField(field) => Some(ValuePart::HashField(field.clone())),
+            TypedView::List { start: 0, stop: -1 } => None,
+            TypedView::List { start, stop } => Some(ValuePart::ListRange {
+                start: *start,
+                stop: *stop,
+            }),
+            TypedView::Hash | TypedView::Set | TypedView::ZSet { .. } => None,
+        }
+    }
+}
+
+fn json_value(val: &Option<Vec<u8>>) -> Value {
+    match val.as_ref().map(|val| str::from_utf8(val)) {
+        Some(Ok(val)) => Value::from(val),
+        Some(Err(_)) => json!({ "base64": base64::encode(val.as_ref().unwrap()) }),
+        None => Value::Null,
+    }
+}
+
+fn json_array(items: &[String]) -> String {
+    Value::from(items).to_string()
+}
+
+//JSON has no infinity, which redis allows as a score, so it is written as
+//the string redis uses for it
+fn json_score(score: f64) -> Value {
+    if score.is_finite() {
+        Value::from(score)
+    } else if score > 0.0 {
+        Value::from("inf")
+    } else {
+        Value::from("-inf")
+    }
+}
+
+/*
+ * Picks the content type to send a stored value as. Redis doesn't know
+ * what its values are, so a value that is valid UTF-8 is taken to be text
+ * and anything else to be binary. Without an Accept header text is sent
+ * as text/plain and binary as application/octet-stream. With one, the
+ * client's most preferred type that can carry the value wins, so a
+ * client can ask for text as application/octet-stream too. None if
+ * nothing the client accepts can carry the value.
+ */
+fn negotiate(accept: Option<&Accept>, is_text: bool) -> Option<ContentType> {
+    let natural = if is_text {
+        ContentType::Plain
+    } else {
+        ContentType::Binary
+    };
+    let accept = match accept {
+        Some(accept) => accept,
+        None => return Some(natural),
+    };
+    let mut accepted: Vec<_> = accept
+        .iter()
+        .filter(|media_type| media_type.weight_or(1.0) > 0.0)
+        .collect();
+    //A stable sort, so types of equal weight keep the client's order
+    accepted.sort_by(|a, b| {
+        b.weight_or(1.0)
+            .partial_cmp(&a.weight_or(1.0))
+            .unwrap_or(Ordering::Equal)
+    });
+    for media_type in accepted {
+        let media_type = media_type.media_type();
+        let (top, sub) = (media_type.top(), media_type.sub());
+        if top == "*" {
+            return Some(natural);
+        } else if top == "text" && (sub == "plain" || sub == "*") && is_text {
+            return Some(ContentType::Plain);
+        } else if top == "application" && (sub == "octet-stream" || sub == "*") {
+            return Some(ContentType::Binary);
+        }
+    }
+    None
+}
+
+//Answers a client that accepts none of the types the value can be sent as
+fn not_acceptable(is_text: bool) -> ProxyResponse {
+    let message = if is_text {
+        "the value can only be sent as text/plain or application/octet-stream"
+    } else {
+        "the value is binary and can only be sent as application/octet-stream"
+    };
+    ProxyResponse::error(Status::NotAcceptable, "not_acceptable", message)
+}
+
+impl<'r> Responder<'r> for ProxyResponse {
+    fn respond_to(mut self, request: &Request) -> response::Result<'r> {
+        if self.negotiate {
+            let is_text = str::from_utf8(&self.body).is_ok();
+            match negotiate(request.accept(), is_text) {
+                Some(content_type) => self.content_type = Some(content_type),
+                None => return not_acceptable(is_text).respond_to(request),
+            }
+        }
+        let mut response = Response::build();
+        response.status(self.status);
+        if self.stale {
+            response
+                .raw_header("X-Cache", "STALE")
+                .raw_header("Warning", "110 - \"Response is Stale\"");
+        }
+        if let Some(content_type) = self.content_type {
+            response
+                .header(content_type)
+                .sized_body(Cursor::new(self.body));
+        }
+        response.ok()
+    }
+}
+
+/*
+ * Fairing that records the status code and latency of every HTTP response.
+ * The start time is stashed in the request's local cache on arrival.
+ */
+pub struct HttpMetrics {
+    metrics: Arc<ProxyMetrics>,
+}
+
+struct RequestStart(Instant);
+
+impl HttpMetrics {
+    pub fn new(metrics: Arc<ProxyMetrics>) -> HttpMetrics {
+        HttpMetrics { metrics }
+    }
+}
+
+impl Fairing for HttpMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "HTTP metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let start = request.local_cache(|| RequestStart(Instant::now()));
+        self.metrics
+            .record_http_response(response.status().code, start.0.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::http_response::*,
+        std::{io, sync::Arc},
+    };
+
+    fn response_for(result: ProxyResult) -> ProxyResponse {
+        ProxyResponse::from_proxy_result(result, false)
+    }
+
+    fn backend_error(err: redis::RedisError) -> ProxyResult {
+        ProxyResult::BackendError(Arc::new(err))
+    }
+
+    #[test]
+    fn test_hit() {
+        let response = response_for(ProxyResult::Hit(b"bar".to_vec()));
+        assert_eq!(response, ProxyResponse::value(b"bar".to_vec()));
+    }
+
+    #[test]
+    fn test_stale() {
+        let response = response_for(ProxyResult::Stale(CacheValue::Str(b"bar".to_vec())));
+        assert_eq!(response, ProxyResponse::value(b"bar".to_vec()).stale());
+        assert!(response.stale);
+
+        let set = ProxyResult::Stale(CacheValue::Set(vec![String::from("a")]));
+        let response = ProxyResponse::from_typed(set, &TypedView::Set);
+        assert_eq!((response.status, response.stale), (Status::Ok, true));
+        assert_eq!(response.body, br#"["a"]"#.to_vec());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let accept = |header: &str| header.parse::<Accept>().unwrap();
+        assert_eq!(negotiate(None, true), Some(ContentType::Plain));
+        assert_eq!(negotiate(None, false), Some(ContentType::Binary));
+        assert_eq!(
+            negotiate(Some(&accept("*/*")), false),
+            Some(ContentType::Binary)
+        );
+        assert_eq!(
+            negotiate(Some(&accept("application/octet-stream")), true),
+            Some(ContentType::Binary)
+        );
+        assert_eq!(
+            negotiate(
+                Some(&accept("application/octet-stream;q=0.5, text/plain")),
+                true
+            ),
+            Some(ContentType::Plain)
+        );
+        //Binary can't be sent as text, so the lower preference wins
+        assert_eq!(
+            negotiate(
+                Some(&accept("text/plain, application/octet-stream;q=0.1")),
+                false
+            ),
+            Some(ContentType::Binary)
+        );
+        assert_eq!(negotiate(Some(&accept("text/*")), false), None);
+        assert_eq!(negotiate(Some(&accept("application/json")), true), None);
+
+        //Only a binary value is refused for being binary
+        let message = |is_text| String::from_utf8(not_acceptable(is_text).body).unwrap();
+        assert!(message(false).contains("the value is binary"));
+        assert!(!message(true).contains("binary"));
+    }
+
+    #[test]
+    fn test_batch_binary_value() {
+        let keys = vec![String::from("blob")];
+        let response =
+            ProxyResponse::from_batch(&keys, ProxyResult::Values(vec![Some(vec![0, 0xff])]));
+        assert_eq!(response.body, br#"{"blob":{"base64":"AP8="}}"#);
+    }
+
+    #[test]
+    fn test_miss() {
+        let response = response_for(ProxyResult::Miss);
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(response.content_type, Some(ContentType::JSON));
+        assert_eq!(
+            response.body,
+            br#"{"status":404,"error":"not_found","message":"key not found"}"#
+        );
+    }
+
+    #[test]
+    fn test_legacy_miss() {
+        let response = ProxyResponse::from_proxy_result(ProxyResult::Miss, true);
+        assert_eq!(response, ProxyResponse::text(Status::Ok, String::new()));
+    }
+
+    #[test]
+    fn test_batch() {
+        let keys = vec![String::from("foo"), String::from("b\"az")];
+        let response = ProxyResponse::from_batch(
+            &keys,
+            ProxyResult::Values(vec![Some(b"bar".to_vec()), None]),
+        );
+        assert_eq!(
+            response,
+            ProxyResponse::json(Status::Ok, String::from(r#"{"foo":"bar","b\"az":null}"#))
+        );
+
+        let stale = ProxyResponse::from_batch(
+            &keys,
+            ProxyResult::StaleValues(vec![Some(b"bar".to_vec()), None]),
+        );
+        assert!(stale.stale);
+        assert_eq!(stale.body, response.body);
+
+        let response = ProxyResponse::from_batch(&keys, ProxyResult::Timeout);
+        assert_eq!(response.status, Status::GatewayTimeout);
+    }
+
+    #[test]
+    fn test_typed() {
+        let hash = || {
+            ProxyResult::Value(CacheValue::Hash(vec![
+                (String::from("a"), String::from("1")),
+                (String::from("b\""), String::from("2")),
+            ]))
+        };
+        assert_eq!(
+            ProxyResponse::from_typed(hash(), &TypedView::Hash),
+            ProxyResponse::json(Status::Ok, String::from(r#"{"a":"1","b\"":"2"}"#))
+        );
+        assert_eq!(
+            ProxyResponse::from_typed(hash(), &TypedView::HashField(String::from("a"))),
+            ProxyResponse::text(Status::Ok, String::from("1"))
+        );
+        assert_eq!(
+            ProxyResponse::from_typed(hash(), &TypedView::HashField(String::from("c"))).status,
+            Status::NotFound
+        );
+
+        let list = ProxyResult::Value(CacheValue::List(vec![
+            String::from("a"),
+            String::from("b"),
+            String::from("c"),
+        ]));
+        assert_eq!(
+            ProxyResponse::from_typed(list, &TypedView::List { start: 1, stop: -1 }),
+            ProxyResponse::json(Status::Ok, String::from(r#"["b","c"]"#))
+        );
+        //A range read from redis on its own is already sliced
+        let range = ProxyResult::Part(CacheValue::List(vec![String::from("c")]));
+        assert_eq!(
+            ProxyResponse::from_typed(range, &TypedView::List { start: 2, stop: 2 }),
+            ProxyResponse::json(Status::Ok, String::from(r#"["c"]"#))
+        );
+
+        let zset = || {
+            ProxyResult::Value(CacheValue::ZSet(vec![
+                (String::from("x"), 1.5),
+                (String::from("y"), f64::INFINITY),
+            ]))
+        };
+        assert_eq!(
+            ProxyResponse::from_typed(zset(), &TypedView::ZSet { withscores: false }),
+            ProxyResponse::json(Status::Ok, String::from(r#"["x","y"]"#))
+        );
+        assert_eq!(
+            ProxyResponse::from_typed(zset(), &TypedView::ZSet { withscores: true }),
+            ProxyResponse::json(
+                Status::Ok,
+                String::from(r#"[{"member":"x","score":1.5},{"member":"y","score":"inf"}]"#)
+            )
+        );
+
+        let response = ProxyResponse::from_typed(hash(), &TypedView::Set);
+        assert_eq!(response.status, Status::Conflict);
+        assert_eq!(
+            response.body,
+            br#"{"status":409,"error":"wrong_type","message":"the key holds a hash, not a set"}"#
+        );
+        assert_eq!(
+            ProxyResponse::from_typed(ProxyResult::Hit(b"a".to_vec()), &TypedView::Set).status,
+            Status::Conflict
+        );
+        assert_eq!(response_for(hash()).status, Status::Conflict);
+        let held = || ProxyResult::WrongType(ValueKind::Hash);
+        assert_eq!(response_for(held()).status, Status::Conflict);
+        assert_eq!(
+            ProxyResponse::from_typed(held(), &TypedView::Set).status,
+            Status::Conflict
+        );
+        assert_eq!(
+            ProxyResponse::from_typed(ProxyResult::Miss, &TypedView::Set).status,
+            Status::NotFound
+        );
+    }
+
+    #[test]
+    fn test_writes() {
+        assert_eq!(response_for(ProxyResult::Stored).status, Status::NoContent);
+        assert_eq!(
+            response_for(ProxyResult::Deleted(true)).status,
+            Status::NoContent
+        );
+        assert_eq!(
+            response_for(ProxyResult::Deleted(false)).status,
+            Status::NotFound
+        );
+    }
+
+    #[test]
+    fn test_failures() {
+        assert_eq!(
+            response_for(ProxyResult::Timeout).status,
+            Status::GatewayTimeout
+        );
+        assert_eq!(
+            response_for(ProxyResult::Shutdown).status,
+            Status::ServiceUnavailable
+        );
+
+        let refused =
+            redis::RedisError::from(io::Error::new(io::ErrorKind::ConnectionRefused, "down"));
+        assert_eq!(
+            response_for(backend_error(refused)).status,
+            Status::ServiceUnavailable
+        );
+
+        let wrong_type = redis::RedisError::from((
+            redis::ErrorKind::TypeError,
+            "WRONGTYPE",
+            String::from("Operation against a key holding the wrong kind of value"),
+        ));
+        let response = response_for(backend_error(wrong_type));
+        assert_eq!(response.status, Status::BadGateway);
+        assert_eq!(
+            response.body,
+            concat!(
+                r#"{"status":502,"error":"backend_error","#,
+                r#""message":"WRONGTYPE: Operation against a key holding the wrong kind of value"}"#
+            )
+            .as_bytes()
+        );
+    }
+}
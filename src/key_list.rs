@@ -0,0 +1,209 @@
+use std::{collections::HashMap, mem};
+
+//Sentinel slot index used in place of a null pointer at either end of the list
+const NIL: usize = usize::MAX;
+
+struct KeyNode {
+    key: String,
+    newer: usize,
+    older: usize,
+}
+
+/*
+ * An ordered set of keys, oldest to newest. Nodes live in a slab (a Vec of
+ * slots) and link to their neighbours by slot index rather than by
+ * pointer, which keeps the borrow checker happy without reaching for
+ * unsafe or Rc<RefCell>, and a map from key to slot finds a key's node. A
+ * key can be pushed or moved to the newest end, removed from anywhere, or
+ * popped from the oldest end, all in O(1).
+ *
+ * It only holds keys. The cache policies keep their values in a map of
+ * their own. LRUCache orders its keys with one list, while ARC and
+ * W-TinyLFU move keys between several as entries change state.
+ */
+pub struct KeyList {
+    key_to_slot: HashMap<String, usize>,
+    slots: Vec<Option<KeyNode>>,
+    free_slots: Vec<usize>,
+    newest: usize,
+    oldest: usize,
+}
+
+impl Default for KeyList {
+    fn default() -> KeyList {
+        KeyList {
+            key_to_slot: HashMap::new(),
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+            newest: NIL,
+            oldest: NIL,
+        }
+    }
+}
+
+impl KeyList {
+    pub fn len(&self) -> usize {
+        self.key_to_slot.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.key_to_slot.is_empty()
+    }
+
+    /*
+     * What holding a key costs the list: the key, held by both the map and
+     * its node, and the fixed size of a slot and a map entry
+     */
+    pub fn key_bytes(key: &str) -> usize {
+        2 * key.len() + mem::size_of::<Option<KeyNode>>() + mem::size_of::<(String, usize)>()
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.key_to_slot.contains_key(key)
+    }
+
+    pub fn oldest(&self) -> Option<&str> {
+        match self.oldest {
+            NIL => None,
+            slot => Some(&self.node(slot).key),
+        }
+    }
+
+    //Adds the key at the newest end, or moves it there if it is already held
+    pub fn push_newest(&mut self, key: &str) {
+        if let Some(&slot) = self.key_to_slot.get(key) {
+            if self.newest != slot {
+                self.unlink(slot);
+                self.link_newest(slot);
+            }
+            return;
+        }
+        let node = KeyNode {
+            key: key.to_string(),
+            newer: NIL,
+            older: NIL,
+        };
+        let slot = match self.free_slots.pop() {
+            Some(slot) => {
+                self.slots[slot] = Some(node);
+                slot
+            }
+            None => {
+                self.slots.push(Some(node));
+                self.slots.len() - 1
+            }
+        };
+        self.key_to_slot.insert(key.to_string(), slot);
+        self.link_newest(slot);
+    }
+
+    //Returns whether the key was held
+    pub fn remove(&mut self, key: &str) -> bool {
+        match self.key_to_slot.get(key) {
+            Some(&slot) => {
+                self.remove_slot(slot);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn pop_oldest(&mut self) -> Option<String> {
+        match self.oldest {
+            NIL => None,
+            slot => Some(self.remove_slot(slot)),
+        }
+    }
+
+    fn remove_slot(&mut self, slot: usize) -> String {
+        self.unlink(slot);
+        let node = self.slots[slot]
+            .take()
+            .expect("key list remove called on an empty slot");
+        self.key_to_slot.remove(&node.key);
+        self.free_slots.push(slot);
+        node.key
+    }
+
+    fn node(&self, slot: usize) -> &KeyNode {
+        self.slots[slot]
+            .as_ref()
+            .expect("key list references an empty slot")
+    }
+
+    fn node_mut(&mut self, slot: usize) -> &mut KeyNode {
+        self.slots[slot]
+            .as_mut()
+            .expect("key list references an empty slot")
+    }
+
+    fn unlink(&mut self, slot: usize) {
+        let (newer, older) = {
+            let node = self.node(slot);
+            (node.newer, node.older)
+        };
+        match newer {
+            NIL => self.newest = older,
+            newer => self.node_mut(newer).older = older,
+        }
+        match older {
+            NIL => self.oldest = newer,
+            older => self.node_mut(older).newer = newer,
+        }
+        let node = self.node_mut(slot);
+        node.newer = NIL;
+        node.older = NIL;
+    }
+
+    fn link_newest(&mut self, slot: usize) {
+        let old_newest = self.newest;
+        self.node_mut(slot).older = old_newest;
+        match old_newest {
+            NIL => self.oldest = slot,
+            old_newest => self.node_mut(old_newest).newer = slot,
+        }
+        self.newest = slot;
+    }
+
+    //Newest first. Only used by tests to inspect the order.
+    #[cfg(test)]
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys = Vec::with_capacity(self.len());
+        let mut slot = self.newest;
+        while slot != NIL {
+            let node = self.node(slot);
+            keys.push(node.key.clone());
+            slot = node.older;
+        }
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key_list::*;
+
+    #[test]
+    fn test_order() {
+        let mut list = KeyList::default();
+        assert_eq!(list.pop_oldest(), None);
+        for key in &["a", "b", "c"] {
+            list.push_newest(key);
+        }
+        assert_eq!(list.keys(), vec!["c", "b", "a"]);
+        assert_eq!(list.oldest(), Some("a"));
+
+        list.push_newest("a");
+        assert_eq!(list.keys(), vec!["a", "c", "b"]);
+        assert!(list.remove("c"));
+        assert!(!list.remove("c"));
+        assert_eq!(list.pop_oldest(), Some(String::from("b")));
+        assert_eq!(list.keys(), vec!["a"]);
+
+        //Freed slots are reused
+        list.push_newest("d");
+        assert_eq!(list.slots.len(), 3);
+        assert_eq!(list.len(), 2);
+        assert!(list.contains("d") && !list.is_empty());
+    }
+}
@@ -0,0 +1,194 @@
+use {
+    crate::{metrics::ProxyMetrics, redis_request::Message},
+    redis::IntoConnectionInfo,
+    std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc::{SyncSender, TrySendError},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    },
+};
+
+/*
+ * The KeyspaceSubscriber keeps the cache in step with writes made to redis
+ * by other clients. It subscribes to redis keyspace notifications for the
+ * proxy's database and, for every event on a key (a write, delete, expiry
+ * or eviction), sends a Message::Invalidate down the work queue so a
+ * consumer drops the cached entry straight away rather than serving it
+ * until it expires.
+ *
+ * Redis only publishes these events when notify-keyspace-events is
+ * enabled on the server, e.g. `CONFIG SET notify-keyspace-events KA`. The
+ * proxy does not change server config itself, but warns at startup if the
+ * events look to be disabled.
+ *
+ * Pub/sub is fire and forget, so events published while the subscriber is
+ * disconnected are lost. Entries changed in that window stay stale until
+ * they expire, which is no worse than running without the subscriber. The
+ * same goes for events that arrive while the work queue is full: they are
+ * dropped rather than holding up the subscriber behind the requests, which
+ * would only leave redis buffering more of them.
+ */
+
+//Time to wait before reconnecting after the subscription fails
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+
+//How long a read waits for an event before checking whether to stop
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+//How long to wait for redis to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct KeyspaceSubscriber {
+    client: redis::Client,
+    channel_prefix: String,
+    work_queue_tx: SyncSender<Message>,
+    metrics: Arc<ProxyMetrics>,
+    stopped: Arc<AtomicBool>,
+}
+
+/*
+ * Stops the subscriber thread when dropped. The thread notices within
+ * READ_TIMEOUT, or once it is done reconnecting, and exits on its own, so
+ * dropping this never waits on redis.
+ */
+pub struct KeyspaceSubscription {
+    stopped: Arc<AtomicBool>,
+}
+
+impl Drop for KeyspaceSubscription {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+}
+
+impl KeyspaceSubscriber {
+    pub fn new(
+        redis_url: &str,
+        work_queue_tx: SyncSender<Message>,
+    ) -> redis::RedisResult<KeyspaceSubscriber> {
+        let info = redis_url.into_connection_info()?;
+        Ok(KeyspaceSubscriber {
+            channel_prefix: format!("__keyspace@{}__:", info.db),
+            client: redis::Client::open(info)?,
+            work_queue_tx,
+            metrics: Arc::new(ProxyMetrics::default()),
+            stopped: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<ProxyMetrics>) -> KeyspaceSubscriber {
+        self.metrics = metrics;
+        self
+    }
+
+    /*
+     * Runs the subscription on a background thread, reconnecting whenever
+     * it fails. The thread exits once the returned KeyspaceSubscription is
+     * dropped, or once the work queue is found closed when passing on an
+     * event.
+     */
+    pub fn spawn(self) -> KeyspaceSubscription {
+        let subscription = KeyspaceSubscription {
+            stopped: self.stopped.clone(),
+        };
+        thread::spawn(move || {
+            self.warn_if_notifications_disabled();
+            while !self.stopped.load(Ordering::SeqCst) {
+                match self.subscribe() {
+                    Ok(()) => return,
+                    Err(err) => {
+                        eprintln!("keyspace subscription failed, reconnecting {}", err);
+                        thread::sleep(RECONNECT_BACKOFF);
+                    }
+                }
+            }
+        });
+        subscription
+    }
+
+    /*
+     * Returns Ok once told to stop or the work queue has closed, or the
+     * error that ended the subscription. Reads time out every
+     * READ_TIMEOUT so a quiet subscription still checks whether to stop.
+     */
+    fn subscribe(&self) -> redis::RedisResult<()> {
+        let mut con = self.client.get_connection_with_timeout(CONNECT_TIMEOUT)?;
+        con.set_read_timeout(Some(READ_TIMEOUT))?;
+        let mut pubsub = con.as_pubsub();
+        pubsub.psubscribe(format!("{}*", self.channel_prefix))?;
+        while !self.stopped.load(Ordering::SeqCst) {
+            let msg = match pubsub.get_message() {
+                Ok(msg) => msg,
+                Err(err) if err.is_timeout() => continue,
+                Err(err) => return Err(err),
+            };
+            //Keys are strings throughout the proxy, so a binary key can't be
+            //in the cache and its events are ignored
+            let channel: String = match msg.get_channel() {
+                Ok(channel) => channel,
+                Err(_) => continue,
+            };
+            if let Some(key) = channel.strip_prefix(&self.channel_prefix) {
+                match self
+                    .work_queue_tx
+                    .try_send(Message::Invalidate(key.to_string()))
+                {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => self.metrics.invalidations_dropped.inc(),
+                    Err(TrySendError::Disconnected(_)) => return Ok(()),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn warn_if_notifications_disabled(&self) {
+        let config: redis::RedisResult<(String, String)> = self
+            .client
+            .get_connection_with_timeout(CONNECT_TIMEOUT)
+            .and_then(|mut con| {
+                redis::cmd("CONFIG")
+                    .arg("GET")
+                    .arg("notify-keyspace-events")
+                    .query(&mut con)
+            });
+        //CONFIG is often disabled on managed redis, in which case we can't tell
+        if let Ok((_, flags)) = config {
+            if !flags.contains('K') {
+                eprintln!(
+                    "redis has keyspace notifications disabled (notify-keyspace-events \"{}\"), \
+                     cache entries will not be invalidated on write",
+                    flags
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {crate::keyspace_subscriber::*, std::sync::mpsc::sync_channel};
+
+    #[test]
+    fn test_channel_prefix_uses_db() {
+        let (tx, _rx) = sync_channel(1);
+        let subscriber = KeyspaceSubscriber::new("redis://127.0.0.1/3", tx).unwrap();
+        assert_eq!(subscriber.channel_prefix, "__keyspace@3__:");
+    }
+
+    #[test]
+    fn test_dropping_subscription_stops_subscriber() {
+        let (tx, _rx) = sync_channel(1);
+        //Nothing listens on port 1, so the thread keeps reconnecting
+        let subscriber = KeyspaceSubscriber::new("redis://127.0.0.1:1/", tx).unwrap();
+        let stopped = subscriber.stopped.clone();
+        let subscription = subscriber.spawn();
+        assert!(!stopped.load(Ordering::SeqCst));
+        drop(subscription);
+        assert!(stopped.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,266 @@
+use {
+    crate::cache_policy::EvictionPolicy,
+    crate::clock::{Clock, SystemClock},
+    crate::lru_cache::{entry_lifetime, ByteSize, Cache, CacheEntry, CacheStats},
+    std::{
+        collections::{BTreeSet, HashMap},
+        sync::Arc,
+        time::Duration,
+    },
+};
+
+/*
+ * Counts are halved once the cache has seen this many lookups and puts
+ * per entry of capacity, so the cache forgets keys that were hot once and
+ * new keys get a chance against them
+ */
+const AGING_PERIOD: usize = 10;
+
+struct LFUEntry<V> {
+    entry: CacheEntry<V>,
+    count: u32,
+    last_used: u64,
+}
+
+/*
+ * The LFUCache evicts the entry used least often, and of those the one
+ * used least recently. Entries are kept in a BTreeSet ordered by
+ * (count, last use), so the victim is always the first one and a use is
+ * O(log n).
+ *
+ * Plain LFU lets keys that were popular a long time ago hold on to the
+ * cache forever, so counts age: every AGING_PERIOD * capacity operations
+ * every count is halved. Halving rebuilds the set, which is O(n log n)
+ * once every O(n) operations.
+ */
+pub struct LFUCache<V = String> {
+    entries: HashMap<String, LFUEntry<V>>,
+    by_count: BTreeSet<(u32, u64, String)>,
+    //Ticks on every use, ordering entries with the same count
+    ticks: u64,
+    ops_since_aging: usize,
+    max_cache_entry_lifetime: Duration,
+    stale_grace: Duration,
+    clock: Arc<dyn Clock>,
+    capacity: usize,
+    stats: CacheStats,
+}
+
+impl<V: Clone> Cache<V> for LFUCache<V> {
+    fn get(&mut self, key: &str) -> Option<V> {
+        self.tick();
+        let now = self.clock.now();
+        let expired = match self.entries.get(key) {
+            Some(lfu_entry) => lfu_entry.entry.expired(now),
+            None => {
+                self.stats.misses += 1;
+                return None;
+            }
+        };
+        if expired {
+            if self.entries[key].entry.expired_for(now, self.stale_grace) {
+                self.remove(key);
+                self.stats.expirations += 1;
+            }
+            self.stats.misses += 1;
+            return None;
+        }
+        self.stats.hits += 1;
+        let ticks = self.ticks;
+        let lfu_entry = self.entries.get_mut(key).expect("checked above");
+        self.by_count
+            .remove(&(lfu_entry.count, lfu_entry.last_used, key.to_string()));
+        lfu_entry.count = lfu_entry.count.saturating_add(1);
+        lfu_entry.last_used = ticks;
+        self.by_count
+            .insert((lfu_entry.count, lfu_entry.last_used, key.to_string()));
+        Some(lfu_entry.entry.val.clone())
+    }
+
+    fn put(&mut self, key: &str, val: V, ttl: Option<Duration>) {
+        if let Some(entry) = self.entries.get(key) {
+            if !entry.entry.expired(self.clock.now()) {
+                eprintln!("unexpected double write of {} - ignoring", key);
+                return;
+            }
+            //A refresh of an entry kept through the grace window
+            self.remove(key);
+        }
+        let lifetime = match entry_lifetime(ttl, self.max_cache_entry_lifetime) {
+            Some(lifetime) => lifetime,
+            None => return,
+        };
+        self.tick();
+        while !self.entries.is_empty() && self.entries.len() >= self.capacity {
+            self.evict();
+        }
+        self.entries.insert(
+            key.to_string(),
+            LFUEntry {
+                entry: CacheEntry::new(val, lifetime, self.clock.now()),
+                count: 1,
+                last_used: self.ticks,
+            },
+        );
+        self.by_count.insert((1, self.ticks, key.to_string()));
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(lfu_entry) = self.entries.remove(key) {
+            self.by_count
+                .remove(&(lfu_entry.count, lfu_entry.last_used, key.to_string()));
+        }
+    }
+
+    fn get_stale(&mut self, key: &str) -> Option<V> {
+        let entry = &self.entries.get(key)?.entry;
+        let now = self.clock.now();
+        if entry.expired_for(now, self.stale_grace) {
+            return None;
+        }
+        let val = entry.val.clone();
+        //A fresh entry is handed back too, but isn't served stale
+        if entry.expired(now) {
+            self.stats.stale_hits += 1;
+        }
+        Some(val)
+    }
+}
+
+impl<V: Clone + ByteSize + Send> EvictionPolicy<V> for LFUCache<V> {
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len() as u64,
+            ..self.stats
+        }
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.evict();
+        }
+    }
+
+    fn set_max_lifetime(&mut self, max_cache_entry_lifetime: Duration) {
+        self.max_cache_entry_lifetime = max_cache_entry_lifetime;
+        for lfu_entry in self.entries.values_mut() {
+            lfu_entry.entry.cap_lifetime(max_cache_entry_lifetime);
+        }
+    }
+
+    fn set_stale_grace(&mut self, stale_grace: Duration) {
+        self.stale_grace = stale_grace;
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+}
+
+impl<V> LFUCache<V> {
+    pub fn new(capacity: usize, max_cache_entry_lifetime: Duration) -> LFUCache<V> {
+        LFUCache {
+            entries: HashMap::with_capacity(capacity),
+            by_count: BTreeSet::new(),
+            ticks: 0,
+            ops_since_aging: 0,
+            max_cache_entry_lifetime,
+            stale_grace: Duration::from_secs(0),
+            clock: Arc::new(SystemClock),
+            capacity,
+            stats: CacheStats::default(),
+        }
+    }
+
+    fn evict(&mut self) {
+        let victim = self
+            .by_count
+            .iter()
+            .next()
+            .cloned()
+            .expect("LFU evict called on empty cache");
+        self.by_count.remove(&victim);
+        self.entries.remove(&victim.2);
+        self.stats.evictions += 1;
+    }
+
+    fn tick(&mut self) {
+        self.ticks += 1;
+        self.ops_since_aging += 1;
+        if self.ops_since_aging >= AGING_PERIOD * self.capacity.max(1) {
+            self.age();
+        }
+    }
+
+    fn age(&mut self) {
+        self.ops_since_aging = 0;
+        self.by_count.clear();
+        for (key, lfu_entry) in self.entries.iter_mut() {
+            lfu_entry.count /= 2;
+            self.by_count
+                .insert((lfu_entry.count, lfu_entry.last_used, key.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {crate::clock::FakeClock, crate::lfu_cache::*};
+
+    #[test]
+    fn test_evicts_least_frequently_used() {
+        let mut cache = LFUCache::new(2, Duration::from_secs(60));
+        cache.put("a", String::from("a"), None);
+        cache.put("b", String::from("b"), None);
+        cache.get("a");
+        cache.get("a");
+        cache.get("b");
+
+        //b has been used less often than a, however recently
+        cache.put("c", String::from("c"), None);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("a"), Some(String::from("a")));
+
+        //Ties go to the least recently used
+        cache.put("d", String::from("d"), None);
+        assert_eq!(cache.get("c"), None);
+        assert_eq!(cache.stats().evictions, 2);
+    }
+
+    #[test]
+    fn test_aging() {
+        let mut cache = LFUCache::new(2, Duration::from_secs(60));
+        cache.put("old", String::from("old"), None);
+        for _ in 0..12 {
+            cache.get("old");
+        }
+        //Counts are halved on the 20th operation, so new overtakes old
+        //with fewer uses than old ever had
+        cache.put("new", String::from("new"), None);
+        for _ in 0..10 {
+            cache.get("new");
+        }
+        cache.put("next", String::from("next"), None);
+        assert_eq!(cache.get("old"), None);
+        assert_eq!(cache.get("new"), Some(String::from("new")));
+    }
+
+    #[test]
+    fn test_expiry_and_remove() {
+        let clock = Arc::new(FakeClock::default());
+        let mut cache = LFUCache::new(4, Duration::from_secs(10));
+        cache.set_clock(clock.clone());
+        cache.put("a", String::from("a"), None);
+        cache.put("b", String::from("b"), Some(Duration::from_secs(0)));
+        cache.put("c", String::from("c"), None);
+        cache.remove("c");
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("c"), None);
+        let stats = cache.stats();
+        assert_eq!((stats.expirations, stats.entries), (1, 0));
+        assert!(cache.by_count.is_empty());
+    }
+}
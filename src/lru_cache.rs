@@ -1,143 +1,542 @@
-use std::{
-    collections::HashMap,
-    time::{Duration, SystemTime},
-    vec::Vec,
+use {
+    crate::clock::{Clock, SystemClock},
+    crate::key_list::KeyList,
+    std::{
+        collections::{hash_map::RandomState, HashMap},
+        hash::{BuildHasher, Hasher},
+        mem,
+        sync::Arc,
+        time::{Duration, Instant},
+        vec::Vec,
+    },
 };
 
-struct CacheEntry {
-    val: String,
-    put_time: SystemTime,
-}
-
-impl CacheEntry {
-    pub fn expired(&self, max_cache_entry_lifetime: Duration) -> bool {
-        let cache_entry_lifetime = match self.put_time.elapsed() {
-            Ok(lifetime) => lifetime,
-            Err(err) => {
-                eprintln!(
-                    "LRU Cache received unexpected error from system
-                     clock when evaluating cache timeout {} 
-                     - Defaulting to expired",
-                    err
-                );
-                return false;
-            }
-        };
-        cache_entry_lifetime > max_cache_entry_lifetime
+/*
+ * A cached value with the time it was put and how long it may be served
+ * for. Shared by every eviction policy, so they all expire entries alike.
+ * Times come from the cache's Clock, which is monotonic, so the age of an
+ * entry can't go backwards.
+ */
+pub struct CacheEntry<V> {
+    pub val: V,
+    put_time: Instant,
+    lifetime: Duration,
+}
+
+impl<V> CacheEntry<V> {
+    pub fn new(val: V, lifetime: Duration, now: Instant) -> CacheEntry<V> {
+        CacheEntry {
+            val,
+            put_time: now,
+            lifetime,
+        }
+    }
+
+    //Lowering the cache's lifetime applies to entries already cached
+    pub fn cap_lifetime(&mut self, max_cache_entry_lifetime: Duration) {
+        self.lifetime = self.lifetime.min(max_cache_entry_lifetime);
+    }
+
+    pub fn expired(&self, now: Instant) -> bool {
+        self.expired_for(now, Duration::from_secs(0))
+    }
+
+    /*
+     * Whether the entry expired more than grace ago. Within the grace
+     * window an expired entry may still be served, marked as stale.
+     */
+    pub fn expired_for(&self, now: Instant, grace: Duration) -> bool {
+        self.age(now) > self.lifetime + grace
+    }
+
+    /*
+     * XFetch: whether a hit should refresh the entry before it expires.
+     * The closer the entry is to expiring the likelier it is, with a
+     * chance of e^(-remaining / window), so among many concurrent hits
+     * on a hot key one usually refreshes it well before the rest would
+     * all miss at once. sample is uniform in (0, 1].
+     */
+    pub fn refresh_early(&self, now: Instant, window: Duration, sample: f64) -> bool {
+        let age = self.age(now);
+        if age >= self.lifetime {
+            return false;
+        }
+        let remaining = self.lifetime - age;
+        remaining.as_secs_f64() <= window.as_secs_f64() * -sample.ln()
+    }
+
+    fn age(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.put_time)
     }
 }
 
+/*
+ * How long an entry put with this ttl may be cached. The global lifetime
+ * is an upper bound on any per entry ttl. None if it is not worth caching
+ * at all, as it would expire straight away.
+ */
+pub fn entry_lifetime(
+    ttl: Option<Duration>,
+    max_cache_entry_lifetime: Duration,
+) -> Option<Duration> {
+    let lifetime = match ttl {
+        Some(ttl) => ttl.min(max_cache_entry_lifetime),
+        None => max_cache_entry_lifetime,
+    };
+    if lifetime == Duration::from_secs(0) {
+        return None;
+    }
+    Some(lifetime)
+}
+
 /*
  * This trait defines the interface through which our consumer can
  * get and set data from the Cache
+ *
+ * put takes an optional per entry ttl. The cache may serve the entry for
+ * no longer than this, though it is free to expire it sooner.
+ *
+ * remove drops the entry for a key, if there is one, so the next get
+ * misses. Used when the value in redis is changed through the proxy.
+ *
+ * A cache given a grace window keeps entries that much longer after they
+ * expire. get misses on them, but get_stale still hands them back so they
+ * can be served while redis is asked for a fresh value, and a put of the
+ * same key replaces them.
+ *
+ * get_with_refresh lets a cache ask for a hit to be refreshed from redis
+ * before the entry expires, so entries cached together don't all miss
+ * together.
+ *
+ * The cache is generic over the value it holds. get hands back a clone,
+ * so the proxy's cache holds whole hashes and lists as well as strings.
+ */
+pub trait Cache<V = String> {
+    fn get(&mut self, key: &str) -> Option<V>;
+    fn put(&mut self, key: &str, val: V, ttl: Option<Duration>);
+    fn remove(&mut self, key: &str);
+
+    //Caches without a grace window never have anything stale to offer
+    fn get_stale(&mut self, _key: &str) -> Option<V> {
+        None
+    }
+
+    //Like get, but also says whether the hit should refresh the entry early
+    fn get_with_refresh(&mut self, key: &str) -> Option<(V, bool)> {
+        self.get(key).map(|val| (val, false))
+    }
+}
+
+/*
+ * Thread safe variant of the Cache trait. Implementors synchronize
+ * internally so a single instance can be shared between threads
+ * behind an Arc.
+ */
+pub trait ConcurrentCache<V = String>: Send + Sync {
+    fn get(&self, key: &str) -> Option<V>;
+    fn put(&self, key: &str, val: V, ttl: Option<Duration>);
+    fn remove(&self, key: &str);
+
+    fn get_stale(&self, _key: &str) -> Option<V> {
+        None
+    }
+
+    fn get_with_refresh(&self, key: &str) -> Option<(V, bool)> {
+        self.get(key).map(|val| (val, false))
+    }
+}
+
+/*
+ * Lets a consumer hold a handle to a shared concurrent cache wherever it
+ * expects a Cache it owns outright
+ */
+impl<V, T: ConcurrentCache<V> + ?Sized> Cache<V> for Arc<T> {
+    fn get(&mut self, key: &str) -> Option<V> {
+        ConcurrentCache::get(&**self, key)
+    }
+
+    fn put(&mut self, key: &str, val: V, ttl: Option<Duration>) {
+        ConcurrentCache::put(&**self, key, val, ttl)
+    }
+
+    fn remove(&mut self, key: &str) {
+        ConcurrentCache::remove(&**self, key)
+    }
+
+    fn get_stale(&mut self, key: &str) -> Option<V> {
+        ConcurrentCache::get_stale(&**self, key)
+    }
+
+    fn get_with_refresh(&mut self, key: &str) -> Option<(V, bool)> {
+        ConcurrentCache::get_with_refresh(&**self, key)
+    }
+}
+
+/*
+ * The memory a value owns on the heap, roughly. A cache bounded by bytes
+ * adds the key and its own bookkeeping for each entry on top of this, so
+ * it only needs to be close enough to keep the total honest.
  */
-pub trait Cache {
-    fn get(&mut self, key: &String) -> Option<String>;
-    fn put(&mut self, key: &String, val: String);
+pub trait ByteSize {
+    fn heap_bytes(&self) -> usize;
 }
 
-pub struct LRUCache {
-    //TODO evaluate what synchronization primitives are necessary
-    //to ensure there aren't weird races between the map and the vec
-    cache_elements: HashMap<String, CacheEntry>,
-    keys_ordered_by_use: Vec<String>,
+impl ByteSize for String {
+    fn heap_bytes(&self) -> usize {
+        self.len()
+    }
+}
+
+impl ByteSize for Vec<u8> {
+    fn heap_bytes(&self) -> usize {
+        self.len()
+    }
+}
+
+impl ByteSize for () {
+    fn heap_bytes(&self) -> usize {
+        0
+    }
+}
+
+/*
+ * Counts of what the cache has done since it was created, plus the number
+ * of entries it holds now and their approximate size. Expired entries count
+ * as both an expiration and a miss. oversized counts puts that were turned
+ * away because the value alone was over the largest entry allowed, and
+ * stale_hits the expired entries get_stale handed back. early_refreshes
+ * counts hits get_with_refresh asked to be refreshed early.
+ */
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub expirations: u64,
+    pub evictions: u64,
+    pub oversized: u64,
+    pub stale_hits: u64,
+    pub early_refreshes: u64,
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+impl CacheStats {
+    pub fn merge(&mut self, other: CacheStats) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.expirations += other.expirations;
+        self.evictions += other.evictions;
+        self.oversized += other.oversized;
+        self.stale_hits += other.stale_hits;
+        self.early_refreshes += other.early_refreshes;
+        self.entries += other.entries;
+        self.bytes += other.bytes;
+    }
+}
+
+//A cached entry with the size it was charged, so that removing it gives
+//back exactly that
+struct SizedEntry<V> {
+    entry: CacheEntry<V>,
+    bytes: usize,
+}
+
+/*
+ * The cache is bounded either by its number of entries, or, once given a
+ * byte budget with with_max_bytes, by the approximate size of its entries
+ * instead. In that mode capacity is not enforced and a put evicts least
+ * recently used entries until the new one fits. A single entry larger
+ * than max_entry_bytes is not cached at all, rather than emptying the
+ * cache to make room for it.
+ */
+pub struct LRUCache<V = String> {
+    entries: HashMap<String, SizedEntry<V>>,
+    //The cached keys, oldest to newest by last use
+    order: KeyList,
     max_cache_entry_lifetime: Duration,
+    //How long expired entries are kept around to be served stale
+    stale_grace: Duration,
+    //Each entry's lifetime is cut by a random amount up to this percent
+    expr_jitter_percent: usize,
+    //Hits in this last percent of an entry's lifetime may refresh it
+    early_refresh_percent: usize,
+    //xorshift state for the jitter and early refresh draws
+    rng: u64,
+    clock: Arc<dyn Clock>,
     capacity: usize,
+    max_bytes: Option<usize>,
+    max_entry_bytes: usize,
+    //Approximate size of every entry held
+    bytes: usize,
+    stats: CacheStats,
 }
 
-impl Cache for LRUCache {
-    fn get(&mut self, key: &String) -> Option<String> {
-        let entry = self.cache_elements.get(key);
-
-        match entry {
-            Some(e) => {
-                if e.expired(self.max_cache_entry_lifetime) {
-                    self.remove_expired_element(key);
-                    return None;
-                }
-                //we need to clone val and release 'entry'
-                //first because entry holds an immutable borrow to
-                //self. and mark_key_used needs a mutable borrow
-                let val = e.val.clone();
-                self.mark_key_used(key);
-                Some(val)
+impl<V: Clone + ByteSize> Cache<V> for LRUCache<V> {
+    fn get(&mut self, key: &str) -> Option<V> {
+        let now = self.clock.now();
+        let expired = match self.entries.get(key) {
+            Some(sized) => sized.entry.expired(now),
+            None => {
+                self.stats.misses += 1;
+                return None;
             }
-            None => None,
+        };
+        if expired {
+            if self.entries[key].entry.expired_for(now, self.stale_grace) {
+                self.remove_key(key);
+                self.stats.expirations += 1;
+            }
+            self.stats.misses += 1;
+            return None;
         }
+        self.stats.hits += 1;
+        self.order.push_newest(key);
+        Some(self.entries[key].entry.val.clone())
     }
 
-    fn put(&mut self, key: &String, val: String) {
-        if self.cache_elements.contains_key(key) {
-            eprintln!("unexpected double write of {} - ignoring", key);
+    fn put(&mut self, key: &str, val: V, ttl: Option<Duration>) {
+        if let Some(sized) = self.entries.get(key) {
+            if !sized.entry.expired(self.clock.now()) {
+                eprintln!("unexpected double write of {} - ignoring", key);
+                return;
+            }
+            //A refresh of an entry kept through the grace window
+            self.remove_key(key);
+        }
+        let lifetime = match entry_lifetime(ttl, self.max_cache_entry_lifetime) {
+            Some(lifetime) => self.jitter(lifetime),
+            None => return,
+        };
+        let bytes = entry_bytes::<V>(key, &val);
+        if self.max_bytes.is_some() && bytes > self.max_entry_bytes {
+            self.stats.oversized += 1;
             return;
         }
-        if self.cache_elements.len() == self.capacity {
+        while !self.order.is_empty() && !self.fits(self.entries.len() + 1, self.bytes + bytes) {
             self.remove_oldest_element();
+            self.stats.evictions += 1;
         }
 
-        let put_time = SystemTime::now();
-        self.cache_elements
-            .insert(key.clone(), CacheEntry { val, put_time });
-        //insert into the front of the vec
-        self.keys_ordered_by_use.insert(0, key.clone());
+        let sized = SizedEntry {
+            entry: CacheEntry::new(val, lifetime, self.clock.now()),
+            bytes,
+        };
+        self.bytes += bytes;
+        self.entries.insert(key.to_string(), sized);
+        self.order.push_newest(key);
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.remove_key(key);
+    }
+
+    fn get_stale(&mut self, key: &str) -> Option<V> {
+        let entry = &self.entries.get(key)?.entry;
+        let now = self.clock.now();
+        if entry.expired_for(now, self.stale_grace) {
+            return None;
+        }
+        let val = entry.val.clone();
+        //A fresh entry is handed back too, but isn't served stale
+        if entry.expired(now) {
+            self.stats.stale_hits += 1;
+        }
+        Some(val)
+    }
+
+    fn get_with_refresh(&mut self, key: &str) -> Option<(V, bool)> {
+        let val = self.get(key)?;
+        if self.early_refresh_percent == 0 {
+            return Some((val, false));
+        }
+        let sample = self.next_sample();
+        let entry = &self.entries[key].entry;
+        let window = entry
+            .lifetime
+            .mul_f64(self.early_refresh_percent as f64 / 100.0);
+        let refresh = entry.refresh_early(self.clock.now(), window, sample);
+        if refresh {
+            self.stats.early_refreshes += 1;
+        }
+        Some((val, refresh))
     }
 }
 
-impl LRUCache {
-    pub fn new(capacity: usize, max_cache_entry_lifetime: Duration) -> LRUCache {
+impl<V> LRUCache<V> {
+    pub fn new(capacity: usize, max_cache_entry_lifetime: Duration) -> LRUCache<V> {
         LRUCache {
-            cache_elements: HashMap::with_capacity(capacity),
-            keys_ordered_by_use: Vec::with_capacity(capacity),
+            entries: HashMap::with_capacity(capacity),
+            order: KeyList::default(),
             max_cache_entry_lifetime,
+            stale_grace: Duration::from_secs(0),
+            expr_jitter_percent: 0,
+            early_refresh_percent: 0,
+            rng: RandomState::new().build_hasher().finish() | 1,
+            clock: Arc::new(SystemClock),
             capacity,
+            max_bytes: None,
+            max_entry_bytes: 0,
+            bytes: 0,
+            stats: CacheStats::default(),
         }
     }
 
-    fn remove_oldest_element(&mut self) {
-        let oldest_elm_key = self
-            .keys_ordered_by_use
-            .pop()
-            .expect("LRU Remove called on empty cache");
-        self.cache_elements.remove(&oldest_elm_key);
+    //Bounds the cache by the size of its entries rather than their number
+    pub fn with_max_bytes(mut self, max_bytes: usize, max_entry_bytes: usize) -> LRUCache<V> {
+        self.set_max_bytes(max_bytes, max_entry_bytes);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> LRUCache<V> {
+        self.set_clock(clock);
+        self
+    }
+
+    pub fn with_stale_grace(mut self, stale_grace: Duration) -> LRUCache<V> {
+        self.stale_grace = stale_grace;
+        self
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len() as u64,
+            bytes: self.bytes as u64,
+            ..self.stats
+        }
     }
 
     /*
-     * When a cache entry is expired we must remove it to preserve the
-     * ordering of the keys_ordered_by_use
+     * Shrinking evicts least recently used entries until the cache fits
+     * the new capacity. Evictions made here are counted like any other.
+     * A cache bounded by bytes keeps the capacity but doesn't enforce it.
      */
-    fn remove_expired_element(&mut self, key: &String) {
-        let index_of_expired_key = self
-            .keys_ordered_by_use
-            .iter()
-            .position(|k| k == key) /* todo - whats the complexity of this */
-            .expect("Expired key missing from keys_ordered_by_use");
-        self.keys_ordered_by_use.remove(index_of_expired_key);
-        self.cache_elements.remove(key);
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict_to_fit();
     }
 
-    fn mark_key_used(&mut self, key: &String) {
-        let index_of_used_key = self
-            .keys_ordered_by_use
-            .iter()
-            .position(|k| k == key) /* todo - whats the complexity of this */
-            .expect("Used key missing from keys_ordered_by_use");
+    /*
+     * Shrinking the budget evicts down to it straight away. Entries
+     * already cached are kept even if they are over a lowered
+     * max_entry_bytes, and leave in the usual order.
+     */
+    pub fn set_max_bytes(&mut self, max_bytes: usize, max_entry_bytes: usize) {
+        self.max_bytes = Some(max_bytes);
+        self.max_entry_bytes = max_entry_bytes.min(max_bytes);
+        self.evict_to_fit();
+    }
 
-        let elm = self.keys_ordered_by_use.remove(index_of_used_key);
-        self.keys_ordered_by_use.insert(0, elm);
+    /*
+     * Cached entries are capped at the new lifetime too, so lowering it
+     * takes effect straight away. Raising it only affects new entries.
+     */
+    pub fn set_max_lifetime(&mut self, max_cache_entry_lifetime: Duration) {
+        self.max_cache_entry_lifetime = max_cache_entry_lifetime;
+        for sized in self.entries.values_mut() {
+            sized.entry.cap_lifetime(max_cache_entry_lifetime);
+        }
+    }
+
+    /*
+     * Entries already past the new window go at their next get, not
+     * straight away
+     */
+    pub fn set_stale_grace(&mut self, stale_grace: Duration) {
+        self.stale_grace = stale_grace;
+    }
+
+    //Entries already cached keep the times they were put at
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /*
+     * Spreads out the expiry of entries cached at the same time, which
+     * would otherwise all expire, and miss, in the same instant. Jitter
+     * only applies to entries put from now on, early refresh to every hit.
+     */
+    pub fn set_early_expiry(&mut self, expr_jitter_percent: usize, early_refresh_percent: usize) {
+        self.expr_jitter_percent = expr_jitter_percent.min(100);
+        self.early_refresh_percent = early_refresh_percent.min(100);
+    }
+
+    fn jitter(&mut self, lifetime: Duration) -> Duration {
+        if self.expr_jitter_percent == 0 {
+            return lifetime;
+        }
+        let cut = self.expr_jitter_percent as f64 / 100.0 * self.next_sample();
+        lifetime - lifetime.mul_f64(cut)
+    }
+
+    //Uniform in (0, 1]
+    fn next_sample(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        ((self.rng >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+
+    //Whether this many entries of this total size are within the bounds
+    fn fits(&self, entries: usize, bytes: usize) -> bool {
+        match self.max_bytes {
+            Some(max_bytes) => bytes <= max_bytes,
+            None => entries <= self.capacity,
+        }
+    }
+
+    fn evict_to_fit(&mut self) {
+        while !self.fits(self.entries.len(), self.bytes) {
+            self.remove_oldest_element();
+            self.stats.evictions += 1;
+        }
+    }
+
+    fn remove_oldest_element(&mut self) {
+        let oldest = self
+            .order
+            .pop_oldest()
+            .expect("LRU Remove called on empty cache");
+        self.remove_key(&oldest);
+    }
+
+    //Used for eviction, expiry and explicit removal
+    fn remove_key(&mut self, key: &str) {
+        if let Some(sized) = self.entries.remove(key) {
+            self.order.remove(key);
+            self.bytes -= sized.bytes;
+        }
+    }
+
+    //Newest first. Only used by tests to inspect the eviction order.
+    #[cfg(test)]
+    fn keys_ordered_by_use(&self) -> Vec<String> {
+        self.order.keys()
     }
 }
 
+/*
+ * What an entry is charged against a byte budget: the key and value, the
+ * fixed size of a map entry, and what the KeyList ordering the entries
+ * spends on the key
+ */
+fn entry_bytes<V: ByteSize>(key: &str, val: &V) -> usize {
+    key.len()
+        + val.heap_bytes()
+        + mem::size_of::<(String, SizedEntry<V>)>()
+        + KeyList::key_bytes(key)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::lru_cache::*;
+    use {crate::clock::FakeClock, crate::lru_cache::*};
 
     #[test]
     fn test_allocation() {
-        LRUCache::new(10, Duration::from_secs(1));
+        LRUCache::<String>::new(10, Duration::from_secs(1));
     }
     #[test]
     fn test_empty_get() {
-        let mut cache = LRUCache::new(10, Duration::from_secs(1));
+        let mut cache = LRUCache::<String>::new(10, Duration::from_secs(1));
         let key = String::from("foo");
         assert_eq!(cache.get(&key), None);
     }
@@ -146,7 +545,7 @@ mod tests {
         let mut cache = LRUCache::new(10, Duration::from_secs(1));
         let key = String::from("foo");
         let expected_value = String::from("bar");
-        cache.put(&key, expected_value.clone());
+        cache.put(&key, expected_value.clone(), None);
         assert_eq!(cache.get(&key), Some(expected_value));
     }
     #[test]
@@ -154,12 +553,12 @@ mod tests {
         let mut cache = LRUCache::new(1, Duration::from_secs(1));
         let key = String::from("foo");
         let expected_value = String::from("bar");
-        cache.put(&key, expected_value.clone());
+        cache.put(&key, expected_value.clone(), None);
         assert_eq!(cache.get(&key), Some(expected_value));
 
         let key2 = String::from("baz");
         let expected_value2 = String::from("bazoink!");
-        cache.put(&key2, expected_value2.clone());
+        cache.put(&key2, expected_value2.clone(), None);
         assert_eq!(cache.get(&key2), Some(expected_value2));
         assert_eq!(cache.get(&key), None);
     }
@@ -169,30 +568,350 @@ mod tests {
         let mut cache = LRUCache::new(10, Duration::from_secs(1));
         let key = String::from("foo");
         let expected_value = String::from("bar");
-        cache.put(&key, expected_value.clone());
+        cache.put(&key, expected_value, None);
 
         let key2 = String::from("baz");
         let expected_value2 = String::from("bazoink!");
-        cache.put(&key2, expected_value2.clone());
+        cache.put(&key2, expected_value2, None);
         // Expected order of keys : new [key2, key] old
-        assert_eq!(cache.keys_ordered_by_use[0], key2);
-        assert_eq!(cache.keys_ordered_by_use[1], key);
+        assert_eq!(cache.keys_ordered_by_use()[0], key2);
+        assert_eq!(cache.keys_ordered_by_use()[1], key);
 
         cache.get(&key);
         // Expected order of keys : new [key, key2] old
-        assert_eq!(cache.keys_ordered_by_use[0], key);
-        assert_eq!(cache.keys_ordered_by_use[1], key2);
+        assert_eq!(cache.keys_ordered_by_use()[0], key);
+        assert_eq!(cache.keys_ordered_by_use()[1], key2);
     }
 
     #[test]
     fn test_timeout() {
         let timeout_duration = Duration::from_secs(1);
-        let mut cache = LRUCache::new(10, timeout_duration);
+        let clock = Arc::new(FakeClock::default());
+        let mut cache = LRUCache::new(10, timeout_duration).with_clock(clock.clone());
         let key = String::from("foo");
         let expected_value = String::from("bar");
-        cache.put(&key, expected_value.clone());
+        cache.put(&key, expected_value.clone(), None);
         assert_eq!(cache.get(&key), Some(expected_value));
-        std::thread::sleep(timeout_duration);
+        clock.advance(timeout_duration);
+        assert!(cache.get(&key).is_some());
+        clock.advance(Duration::from_millis(1));
         assert_eq!(cache.get(&key), None);
     }
+
+    #[test]
+    fn test_stale_grace() {
+        let clock = Arc::new(FakeClock::default());
+        let mut cache = LRUCache::new(10, Duration::from_secs(20))
+            .with_clock(clock.clone())
+            .with_stale_grace(Duration::from_secs(200));
+        cache.put("foo", String::from("old"), None);
+        assert_eq!(cache.get_stale("foo"), Some(String::from("old")));
+        clock.advance(Duration::from_secs(40));
+
+        //Expired, but kept through the grace window
+        assert_eq!(cache.get("foo"), None);
+        assert_eq!(cache.get_stale("foo"), Some(String::from("old")));
+        cache.put("foo", String::from("new"), None);
+        assert_eq!(cache.get("foo"), Some(String::from("new")));
+
+        //A fresh entry is not replaced
+        cache.put("foo", String::from("newer"), None);
+        assert_eq!(cache.get("foo"), Some(String::from("new")));
+
+        cache.set_stale_grace(Duration::from_secs(0));
+        clock.advance(Duration::from_secs(40));
+        assert_eq!(cache.get_stale("foo"), None);
+        assert_eq!(cache.get("foo"), None);
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.stale_hits, stats.expirations, stats.entries),
+            (1, 1, 0)
+        );
+    }
+
+    #[test]
+    fn test_expr_jitter() {
+        let lifetime = Duration::from_secs(100);
+        let mut cache = LRUCache::new(100, lifetime);
+        cache.set_early_expiry(20, 0);
+        for i in 0..100 {
+            cache.put(&format!("key{}", i), String::from("val"), None);
+        }
+        let lifetimes: Vec<Duration> = cache
+            .entries
+            .values()
+            .map(|sized| sized.entry.lifetime)
+            .collect();
+        assert!(lifetimes
+            .iter()
+            .all(|l| *l <= lifetime && *l >= Duration::from_secs(80)));
+        //Spread out rather than all expiring together
+        assert!(lifetimes.iter().any(|l| *l < Duration::from_secs(90)));
+        assert!(lifetimes.iter().any(|l| *l > Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn test_early_refresh() {
+        let mut cache = LRUCache::new(10, Duration::from_secs(100));
+        cache.put("foo", String::from("bar"), None);
+        for _ in 0..100 {
+            assert_eq!(
+                cache.get_with_refresh("foo"),
+                Some((String::from("bar"), false))
+            );
+        }
+
+        //Anywhere in the lifetime is within the window, so about e^-1 of
+        //the hits on a fresh entry refresh it
+        cache.set_early_expiry(0, 100);
+        let refreshes = (0..1000)
+            .filter(|_| cache.get_with_refresh("foo").unwrap().1)
+            .count();
+        assert!(refreshes > 250 && refreshes < 500, "{}", refreshes);
+        assert_eq!(cache.stats().early_refreshes, refreshes as u64);
+
+        //Far from expiring, a narrow window practically never refreshes
+        cache.set_early_expiry(0, 1);
+        assert!((0..1000).all(|_| !cache.get_with_refresh("foo").unwrap().1));
+        assert_eq!(cache.get_with_refresh("missing"), None);
+
+        let now = Instant::now();
+        let entry = CacheEntry::new((), Duration::from_secs(10), now);
+        assert!(entry.refresh_early(now, Duration::from_secs(10), 0.1));
+        assert!(!entry.refresh_early(now, Duration::from_secs(1), 0.5));
+        assert!(entry.refresh_early(now + Duration::from_secs(9), Duration::from_secs(1), 0.1));
+    }
+
+    #[test]
+    fn test_entry_ttl_shorter_than_global() {
+        let clock = Arc::new(FakeClock::default());
+        let mut cache = LRUCache::new(10, Duration::from_secs(60)).with_clock(clock.clone());
+        let ttl = Duration::from_secs(5);
+        cache.put("foo", String::from("bar"), Some(ttl));
+        assert_eq!(cache.get("foo"), Some(String::from("bar")));
+        clock.advance(ttl * 2);
+        assert_eq!(cache.get("foo"), None);
+    }
+
+    #[test]
+    fn test_global_lifetime_bounds_entry_ttl() {
+        let clock = Arc::new(FakeClock::default());
+        let timeout_duration = Duration::from_secs(5);
+        let mut cache = LRUCache::new(10, timeout_duration).with_clock(clock.clone());
+        cache.put("foo", String::from("bar"), Some(Duration::from_secs(60)));
+        clock.advance(timeout_duration * 2);
+        assert_eq!(cache.get("foo"), None);
+    }
+
+    #[test]
+    fn test_zero_ttl_not_cached() {
+        let mut cache = LRUCache::new(10, Duration::from_secs(60));
+        cache.put("foo", String::from("bar"), Some(Duration::from_secs(0)));
+        assert_eq!(cache.get("foo"), None);
+        assert!(cache.keys_ordered_by_use().is_empty());
+    }
+
+    #[test]
+    fn test_set_capacity() {
+        let mut cache = LRUCache::new(4, Duration::from_secs(60));
+        for key in &["a", "b", "c", "d"] {
+            cache.put(key, key.to_string(), None);
+        }
+        cache.get("a");
+
+        cache.set_capacity(2);
+        assert_eq!(cache.keys_ordered_by_use(), vec!["a", "d"]);
+        assert_eq!(cache.stats().evictions, 2);
+
+        cache.set_capacity(3);
+        cache.put("e", String::from("e"), None);
+        assert_eq!(cache.keys_ordered_by_use(), vec!["e", "a", "d"]);
+    }
+
+    #[test]
+    fn test_set_max_lifetime() {
+        let clock = Arc::new(FakeClock::default());
+        let mut cache = LRUCache::new(10, Duration::from_secs(60)).with_clock(clock.clone());
+        cache.put("foo", String::from("bar"), None);
+        cache.set_max_lifetime(Duration::from_secs(10));
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(cache.get("foo"), None);
+
+        cache.set_max_lifetime(Duration::from_secs(60));
+        cache.put("foo", String::from("bar"), None);
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(cache.get("foo"), Some(String::from("bar")));
+    }
+
+    #[test]
+    fn test_stats() {
+        let clock = Arc::new(FakeClock::default());
+        let mut cache = LRUCache::new(1, Duration::from_secs(10)).with_clock(clock.clone());
+        cache.put("foo", String::from("bar"), None);
+        cache.get("foo");
+        cache.get("missing");
+        cache.put("baz", String::from("bazoink!"), None);
+        clock.advance(Duration::from_secs(20));
+        cache.get("baz");
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 2,
+                expirations: 1,
+                evictions: 1,
+                oversized: 0,
+                stale_hits: 0,
+                early_refreshes: 0,
+                entries: 0,
+                bytes: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_max_bytes() {
+        let entry = entry_bytes("k0", &String::from("0123456789"));
+        let mut cache =
+            LRUCache::new(1, Duration::from_secs(60)).with_max_bytes(entry * 3, entry * 2);
+        //The entry count is not enforced once bounded by bytes
+        for key in &["k0", "k1", "k2"] {
+            cache.put(key, String::from("0123456789"), None);
+        }
+        assert_eq!(cache.stats().bytes, entry as u64 * 3);
+        cache.get("k0");
+
+        //A bigger value evicts as many of the oldest as it needs to
+        cache.put("k3", String::from("01234"), None);
+        cache.put("k4", String::from("0123456789abcdefghij"), None);
+        assert_eq!(cache.keys_ordered_by_use(), vec!["k4", "k3"]);
+
+        //A value over the largest entry is not cached at all
+        cache.put("k5", "x".repeat(entry * 2), None);
+        assert_eq!(cache.get("k5"), None);
+        assert_eq!(cache.keys_ordered_by_use(), vec!["k4", "k3"]);
+
+        cache.remove("k3");
+        let stats = cache.stats();
+        assert_eq!((stats.evictions, stats.oversized), (3, 1));
+        assert_eq!(
+            stats.bytes,
+            entry_bytes("k4", &String::from("0123456789abcdefghij")) as u64
+        );
+
+        cache.set_max_bytes(entry / 2, entry * 2);
+        assert_eq!(cache.stats().entries, 0);
+        assert_eq!(cache.stats().bytes, 0);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut cache = LRUCache::new(2, Duration::from_secs(60));
+        cache.put("foo", String::from("bar"), None);
+        cache.put("baz", String::from("bazoink!"), None);
+        cache.remove("foo");
+        cache.remove("missing");
+        assert_eq!(cache.get("foo"), None);
+        assert_eq!(cache.keys_ordered_by_use(), vec![String::from("baz")]);
+
+        //A removed key can be written again
+        cache.put("foo", String::from("new"), None);
+        assert_eq!(cache.get("foo"), Some(String::from("new")));
+    }
+
+    #[test]
+    fn test_put_after_expiry() {
+        let clock = Arc::new(FakeClock::default());
+        let timeout_duration = Duration::from_secs(10);
+        let mut cache = LRUCache::new(2, timeout_duration).with_clock(clock.clone());
+        cache.put("foo", String::from("bar"), None);
+        clock.advance(timeout_duration * 2);
+        assert_eq!(cache.get("foo"), None);
+
+        cache.put("baz", String::from("bazoink!"), None);
+        assert_eq!(cache.entries.len(), 1);
+        assert_eq!(cache.keys_ordered_by_use(), vec![String::from("baz")]);
+    }
+
+    /*
+     * The naive model is the original Vec backed implementation: correct
+     * but O(n). Front of the Vec is the most recently used key.
+     */
+    struct NaiveLRU {
+        capacity: usize,
+        entries: Vec<(String, String)>,
+    }
+
+    impl NaiveLRU {
+        fn get(&mut self, key: &str) -> Option<String> {
+            let index = self.entries.iter().position(|(k, _)| k == key)?;
+            let entry = self.entries.remove(index);
+            let val = entry.1.clone();
+            self.entries.insert(0, entry);
+            Some(val)
+        }
+
+        fn put(&mut self, key: &str, val: String) {
+            if self.entries.iter().any(|(k, _)| k == key) {
+                return;
+            }
+            if self.entries.len() == self.capacity {
+                self.entries.pop();
+            }
+            self.entries.insert(0, (key.to_string(), val));
+        }
+
+        fn remove(&mut self, key: &str) {
+            self.entries.retain(|(k, _)| k != key);
+        }
+    }
+
+    //xorshift keeps the test deterministic without pulling in a rand crate
+    fn next_rand(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn test_matches_naive_model() {
+        for seed in 1..=20u64 {
+            let mut rng = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+            let capacity = (next_rand(&mut rng) % 16 + 1) as usize;
+            let key_space = capacity as u64 * 2 + 1;
+            let mut cache = LRUCache::new(capacity, Duration::from_secs(60));
+            let mut model = NaiveLRU {
+                capacity,
+                entries: Vec::new(),
+            };
+
+            for op in 0..2000 {
+                let key = format!("key{}", next_rand(&mut rng) % key_space);
+                let choice = next_rand(&mut rng) % 5;
+                if choice < 2 {
+                    assert_eq!(
+                        cache.get(&key),
+                        model.get(&key),
+                        "seed {} op {} get {}",
+                        seed,
+                        op,
+                        key
+                    );
+                } else if choice < 4 {
+                    let val = format!("val{}", op);
+                    cache.put(&key, val.clone(), None);
+                    model.put(&key, val);
+                } else {
+                    cache.remove(&key);
+                    model.remove(&key);
+                }
+                let model_keys: Vec<String> =
+                    model.entries.iter().map(|(k, _)| k.clone()).collect();
+                assert_eq!(cache.keys_ordered_by_use(), model_keys, "seed {}", seed);
+                assert_eq!(cache.entries.len(), model.entries.len());
+                assert_eq!(cache.order.len(), model.entries.len());
+            }
+        }
+    }
 }
@@ -5,165 +5,701 @@
 extern crate rocket;
 extern crate redis;
 
+mod arc_cache;
+mod cache_policy;
+mod cache_value;
+mod clock;
+mod config;
+mod http_response;
+mod key_list;
+mod keyspace_subscriber;
+mod lfu_cache;
 mod lru_cache;
+mod metrics;
 mod redis_consumer;
+mod redis_pool;
 mod redis_request;
+mod reload;
+mod resp;
+mod resp_server;
+mod sharded_cache;
+mod single_flight;
+mod tiny_lfu_cache;
 
 use {
-    lru_cache::LRUCache,
-    redis_consumer::{RedisClientWrapper, RedisConsumer},
-    redis_request::{Message, RedisRequest},
-    rocket::State,
-    std::sync::mpsc::{sync_channel, Receiver, SyncSender},
+    cache_value::CacheValue,
+    config::{ProxyConfig, Startup},
+    http_response::{HttpMetrics, ProxyResponse, TypedView},
+    keyspace_subscriber::KeyspaceSubscriber,
+    lru_cache::CacheStats,
+    metrics::ProxyMetrics,
+    redis_consumer::{
+        wait_for_redis, RedisClientWrapper, RedisConsumer, RedisProvider, ReloadableProvider,
+    },
+    redis_request::{Message, ProxyResult, RedisRequest},
+    reload::{ReloadError, Reloader},
+    resp_server::RespServer,
+    rocket::{
+        config::{Config, Environment},
+        http::{RawStr, Status},
+        request::{self, FromRequest, Request},
+        response::content,
+        Data, Outcome, State,
+    },
+    sharded_cache::ShardedCache,
+    single_flight::SingleFlight,
+    std::{
+        collections::{HashMap, HashSet},
+        io::Read,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+            Arc, Mutex,
+        },
+        thread,
+        time::{Duration, Instant},
+    },
 };
 
+//Number of independently locked shards the cache capacity is split across
+const CACHE_SHARDS: usize = 16;
+
+//Redis refuses string values larger than this
+const MAX_VALUE_BYTES: u64 = 512 * 1024 * 1024;
+
+//Most keys a single batch get may ask for, so one request can't hold up
+//a consumer for too long
+const MAX_BATCH_KEYS: usize = 1000;
+
+//POST /mget bodies larger than this are refused
+const MAX_BATCH_BODY_BYTES: u64 = 1024 * 1024;
+
+//How long /readyz waits for redis to answer a PING
+const READY_TIMEOUT: Duration = Duration::from_secs(1);
+
+//How often a request waiting on a full work queue tries again
+const ENQUEUE_RETRY: Duration = Duration::from_millis(1);
+
 /*
  * This defines the producer of RedisRequests and is responsible
- * for passing incoming web requests to the RedisConsumer
+ * for passing incoming web requests to the RedisConsumer. Every
+ * request without a deadline of its own is given one request_timeout
+ * from now.
  */
 
 #[derive(Clone)]
-struct RedisProducer {
+pub struct RedisProducer {
     work_queue_tx: SyncSender<Message>,
+    request_timeout: Duration,
+    metrics: Arc<ProxyMetrics>,
 }
 
 impl RedisProducer {
-    pub fn new(work_queue_tx: SyncSender<Message>) -> RedisProducer {
-        RedisProducer { work_queue_tx }
+    pub fn new(work_queue_tx: SyncSender<Message>, request_timeout: Duration) -> RedisProducer {
+        RedisProducer {
+            work_queue_tx,
+            request_timeout,
+            metrics: Arc::new(ProxyMetrics::default()),
+        }
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<ProxyMetrics>) -> RedisProducer {
+        self.metrics = metrics;
+        self
     }
 
     pub fn produce_requests(&self, key: String) -> RedisRequest {
-        let request = RedisRequest::new(key);
-        self.work_queue_tx
-            .send(Message::Request(request.clone()))
-            .expect("producer channel failed");
+        self.produce(RedisRequest::new(key))
+    }
+
+    /*
+     * If the consumers have already shut down the request is completed
+     * straight away with ProxyResult::Shutdown, and if the queue stays
+     * full until its deadline, with ProxyResult::Timeout
+     */
+    pub fn produce(&self, request: RedisRequest) -> RedisRequest {
+        let mut request = match request.deadline {
+            Some(_) => request,
+            None => request.with_timeout(self.request_timeout),
+        };
+        //Counted before sending so a consumer can never see the queue
+        //depth go negative
+        self.metrics.queue_depth.inc();
+        let start = Instant::now();
+        let sent = self.enqueue(&request);
+        self.metrics.queue_enqueue_wait.observe(start.elapsed());
+        if let Err(result) = sent {
+            self.metrics.queue_depth.dec();
+            request.set_result(result);
+        }
         request
     }
+
+    /*
+     * A full queue is retried rather than blocked on, so stalled consumers
+     * time requests out instead of holding every front end thread forever
+     */
+    fn enqueue(&self, request: &RedisRequest) -> Result<(), ProxyResult> {
+        let mut message = Message::Request(request.clone());
+        loop {
+            match self.work_queue_tx.try_send(message) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(_)) => return Err(ProxyResult::Shutdown),
+                Err(TrySendError::Full(_)) if request.expired() => {
+                    return Err(ProxyResult::Timeout)
+                }
+                Err(TrySendError::Full(unsent)) => {
+                    message = unsent;
+                    thread::sleep(ENQUEUE_RETRY);
+                }
+            }
+        }
+    }
+}
+
+//The cache of redis misses, None unless --negative_cache_size is set
+type NegativeCache = Option<Arc<ShardedCache<()>>>;
+
+//Options that change how the HTTP front end answers requests
+pub struct HttpOptions {
+    legacy_empty_miss: bool,
+}
+
+//Static routes take priority over /<key>, so keys named metrics, healthz,
+//readyz or mget can't be read over HTTP
+#[get("/metrics")]
+fn metrics(
+    cache: State<Arc<ShardedCache<CacheValue>>>,
+    negative_cache: State<NegativeCache>,
+    metrics: State<Arc<ProxyMetrics>>,
+) -> content::Plain<String> {
+    let negative_stats = match negative_cache.inner() {
+        Some(negative_cache) => negative_cache.stats(),
+        None => CacheStats::default(),
+    };
+    content::Plain(metrics.render(cache.stats(), negative_stats))
+}
+
+/*
+ * Liveness: the process is up and every consumer thread is still running.
+ * A consumer that panicked never comes back, so this is 503 from then on.
+ */
+#[get("/healthz")]
+fn healthz(worker: State<RedisWorker>) -> ProxyResponse {
+    let (consumers, alive) = (worker.consumers(), worker.live_consumers());
+    if alive < consumers {
+        return ProxyResponse::error(
+            Status::ServiceUnavailable,
+            "consumer_died",
+            &format!("{} of {} consumers are running", alive, consumers),
+        );
+    }
+    ProxyResponse::json(
+        Status::Ok,
+        format!(
+            "{{\"status\":\"ok\",\"consumers\":{},\"consumers_alive\":{}}}",
+            consumers, alive
+        ),
+    )
+}
+
+/*
+ * Readiness: a PING sent through the work queue comes back from redis
+ * within READY_TIMEOUT, which shows both that a consumer is taking work
+ * and that redis is reachable
+ */
+#[get("/readyz")]
+fn readyz(request_producer: State<RedisProducer>) -> ProxyResponse {
+    let request = request_producer.produce(RedisRequest::ping().with_timeout(READY_TIMEOUT));
+    match request.get_result() {
+        ProxyResult::Pong => {
+            ProxyResponse::json(Status::Ok, String::from("{\"status\":\"ready\"}"))
+        }
+        ProxyResult::Timeout => ProxyResponse::error(
+            Status::ServiceUnavailable,
+            "not_ready",
+            "no answer from redis in time",
+        ),
+        ProxyResult::BackendError(err) => {
+            ProxyResponse::error(Status::ServiceUnavailable, "not_ready", &err.to_string())
+        }
+        _ => ProxyResponse::error(
+            Status::ServiceUnavailable,
+            "not_ready",
+            "the proxy is shutting down",
+        ),
+    }
+}
+
+//Applies changes to the config file without a restart, the same as SIGHUP
+#[post("/admin/reload")]
+fn reload(reloader: State<Arc<Reloader<RedisClientWrapper>>>) -> ProxyResponse {
+    match reloader.reload() {
+        Ok(report) => ProxyResponse::json(Status::Ok, report.to_json()),
+        Err(ReloadError::Config(err)) => ProxyResponse::error(
+            Status::InternalServerError,
+            "invalid_config",
+            &err.to_string(),
+        ),
+        Err(ReloadError::Redis(err)) => ProxyResponse::error(
+            Status::ServiceUnavailable,
+            "backend_unavailable",
+            &err.to_string(),
+        ),
+    }
 }
 
 #[get("/<key>")]
-fn get(key: String, request_producer: State<RedisProducer>) -> String {
+fn get(
+    key: String,
+    request_producer: State<RedisProducer>,
+    options: State<HttpOptions>,
+) -> ProxyResponse {
     let request = request_producer.produce_requests(key);
-    match request.get_result() {
-        Some(val) => val,
-        None => "".to_string(),
+    ProxyResponse::from_proxy_result(request.get_result(), options.legacy_empty_miss)
+}
+
+/*
+ * Batch get. Takes a JSON array of keys and answers with a JSON object
+ * from each key to its value, or null if it was not found.
+ */
+#[post("/mget", data = "<body>")]
+fn mget(body: Data, request_producer: State<RedisProducer>) -> ProxyResponse {
+    let mut json = String::new();
+    if let Err(err) = body
+        .open()
+        .take(MAX_BATCH_BODY_BYTES + 1)
+        .read_to_string(&mut json)
+    {
+        return ProxyResponse::error(Status::BadRequest, "bad_request", &err.to_string());
+    }
+    if json.len() as u64 > MAX_BATCH_BODY_BYTES {
+        return ProxyResponse::error(
+            Status::PayloadTooLarge,
+            "payload_too_large",
+            "batch requests are limited to 1MB",
+        );
+    }
+    match serde_json::from_str::<Vec<String>>(&json) {
+        Ok(keys) => batch_get(keys, &request_producer),
+        Err(err) => ProxyResponse::error(
+            Status::BadRequest,
+            "bad_request",
+            &format!("expected a JSON array of keys, {}", err),
+        ),
+    }
+}
+
+//The same batch get as POST /mget, with the keys given as /mget?k=foo&k=bar
+#[get("/mget")]
+fn mget_query(keys: QueryKeys, request_producer: State<RedisProducer>) -> ProxyResponse {
+    match keys.0 {
+        Ok(keys) => batch_get(keys, &request_producer),
+        Err(err) => ProxyResponse::error(Status::BadRequest, "bad_request", &err),
     }
 }
 
+fn batch_get(keys: Vec<String>, request_producer: &RedisProducer) -> ProxyResponse {
+    //A key asked for twice is fetched once and appears once in the answer
+    let mut seen = HashSet::new();
+    let keys: Vec<String> = keys
+        .into_iter()
+        .filter(|key| seen.insert(key.clone()))
+        .collect();
+    if keys.len() > MAX_BATCH_KEYS {
+        return ProxyResponse::error(
+            Status::BadRequest,
+            "bad_request",
+            &format!("at most {} keys can be fetched at once", MAX_BATCH_KEYS),
+        );
+    }
+    let request = request_producer.produce(RedisRequest::get_many(keys.clone()));
+    ProxyResponse::from_batch(&keys, request.get_result())
+}
+
 /*
- * The Redis Worker takes ownership of a redisConsumer and begins a new thread
- * to run consume requests on.
+ * Typed gets of keys holding a hash, list, set or sorted set. The whole
+ * value is cached, and each route answers with the part it asks for. A
+ * hash field or list range that isn't cached is read from redis alone.
+ */
+#[get("/hash/<key>/<field>")]
+fn hash_field(key: String, field: String, request_producer: State<RedisProducer>) -> ProxyResponse {
+    typed_get(key, TypedView::HashField(field), &request_producer)
+}
+
+//The whole hash as a JSON object
+#[get("/hash/<key>")]
+fn hash(key: String, request_producer: State<RedisProducer>) -> ProxyResponse {
+    typed_get(key, TypedView::Hash, &request_producer)
+}
+
+//start and stop are counted as LRANGE counts them, and default to the whole list
+#[get("/list/<key>?<start>&<stop>")]
+fn list(
+    key: String,
+    start: Option<Result<i64, &RawStr>>,
+    stop: Option<Result<i64, &RawStr>>,
+    request_producer: State<RedisProducer>,
+) -> ProxyResponse {
+    match (start.unwrap_or(Ok(0)), stop.unwrap_or(Ok(-1))) {
+        (Ok(start), Ok(stop)) => typed_get(key, TypedView::List { start, stop }, &request_producer),
+        _ => ProxyResponse::error(
+            Status::BadRequest,
+            "bad_request",
+            "start and stop must be whole numbers",
+        ),
+    }
+}
+
+#[get("/set/<key>")]
+fn set(key: String, request_producer: State<RedisProducer>) -> ProxyResponse {
+    typed_get(key, TypedView::Set, &request_producer)
+}
+
+//Members lowest score first, with their scores if ?withscores is given
+#[get("/zset/<key>?<withscores>")]
+fn zset(
+    key: String,
+    withscores: Option<&RawStr>,
+    request_producer: State<RedisProducer>,
+) -> ProxyResponse {
+    let view = TypedView::ZSet {
+        withscores: withscores.is_some(),
+    };
+    typed_get(key, view, &request_producer)
+}
+
+fn typed_get(key: String, view: TypedView, request_producer: &RedisProducer) -> ProxyResponse {
+    let request = match view.part() {
+        Some(part) => RedisRequest::part(key, part),
+        None => RedisRequest::typed(key, view.kind()),
+    };
+    let request = request_producer.produce(request);
+    ProxyResponse::from_typed(request.get_result(), &view)
+}
+
+/*
+ * Every k parameter of the query string, in order. Rocket 0.4 can't
+ * collect a repeated parameter, so the query is read directly. Holds an
+ * error if a key is not valid UTF-8 once decoded.
+ */
+struct QueryKeys(Result<Vec<String>, String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for QueryKeys {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<QueryKeys, ()> {
+        let query = request.uri().query().unwrap_or("");
+        let keys = query
+            .split('&')
+            .filter_map(|param| param.strip_prefix("k="))
+            .map(|key| {
+                RawStr::from_str(&key.replace('+', " "))
+                    .url_decode()
+                    .map_err(|err| format!("invalid key in query {}", err))
+            })
+            .collect();
+        Outcome::Success(QueryKeys(keys))
+    }
+}
+
+/*
+ * Writes the request body to redis as the value of the key, byte for
+ * byte whatever its content type. ttl is an optional expiry in seconds.
+ */
+#[put("/<key>?<ttl>", data = "<body>")]
+fn put(
+    key: String,
+    ttl: Option<Result<u64, &RawStr>>,
+    body: Data,
+    request_producer: State<RedisProducer>,
+) -> ProxyResponse {
+    let ttl = match ttl {
+        None => None,
+        Some(Ok(secs)) if secs > 0 => Some(Duration::from_secs(secs)),
+        Some(_) => {
+            return ProxyResponse::error(
+                Status::BadRequest,
+                "bad_request",
+                "ttl must be a positive number of seconds",
+            )
+        }
+    };
+
+    let mut val = Vec::new();
+    if let Err(err) = body.open().take(MAX_VALUE_BYTES + 1).read_to_end(&mut val) {
+        return ProxyResponse::error(Status::BadRequest, "bad_request", &err.to_string());
+    }
+    if val.len() as u64 > MAX_VALUE_BYTES {
+        return ProxyResponse::error(
+            Status::PayloadTooLarge,
+            "payload_too_large",
+            "values are limited to 512MB",
+        );
+    }
+
+    let request = request_producer.produce(RedisRequest::put(key, val, ttl));
+    ProxyResponse::from_proxy_result(request.get_result(), false)
+}
+
+#[delete("/<key>")]
+fn delete(key: String, request_producer: State<RedisProducer>) -> ProxyResponse {
+    let request = request_producer.produce(RedisRequest::delete(key));
+    ProxyResponse::from_proxy_result(request.get_result(), false)
+}
+
+/*
+ * The Redis Worker takes ownership of a pool of redisConsumers and begins
+ * a new thread for each to consume requests on. The consumers share one
+ * work queue, so each request is handled by whichever consumer is idle.
  *
- * Implements the Drop trait which will trigger the worker thread to shutdown
+ * Implements the Drop trait which will trigger the worker threads to shutdown
  * when the RedisWorker goes out of scope on shutdown
+ *
+ * Each thread holds a LiveConsumer guard for as long as it runs, so
+ * live_consumers also drops when a consumer panics.
  */
 
+type ProxyConsumer =
+    RedisConsumer<Arc<ShardedCache<CacheValue>>, Arc<ReloadableProvider<RedisClientWrapper>>>;
+
 pub struct RedisWorker {
-    worker_handle: Option<std::thread::JoinHandle<()>>,
+    worker_handles: Vec<std::thread::JoinHandle<()>>,
     msg_queue_for_shutdown: SyncSender<Message>,
+    live_consumers: Arc<AtomicUsize>,
+}
+
+struct LiveConsumer(Arc<AtomicUsize>);
+
+impl Drop for LiveConsumer {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl Drop for RedisWorker {
     fn drop(&mut self) {
-        self.msg_queue_for_shutdown
-            .send(Message::Shutdown)
-            .expect("shutdown failed");
-        self.worker_handle
-            .take()
-            .unwrap()
-            .join()
-            .expect("worker thread join failed");
+        //Each consumer exits after the first Shutdown it receives, so we
+        //send one per consumer before joining them all. The send only fails
+        //if every consumer has already died.
+        for _ in &self.worker_handles {
+            let _ = self.msg_queue_for_shutdown.send(Message::Shutdown);
+        }
+        for handle in self.worker_handles.drain(..) {
+            //A consumer that panicked has already been reported by /healthz
+            let _ = handle.join();
+        }
     }
 }
 
 impl RedisWorker {
     pub fn new(
-        consumer: RedisConsumer<LRUCache, RedisClientWrapper>,
+        consumers: Vec<ProxyConsumer>,
         msg_queue_for_shutdown: SyncSender<Message>,
     ) -> RedisWorker {
+        let live_consumers = Arc::new(AtomicUsize::new(consumers.len()));
         RedisWorker {
-            worker_handle: Some(std::thread::spawn(move || consumer.consume_requests())),
+            worker_handles: consumers
+                .into_iter()
+                .map(|consumer| {
+                    let live = LiveConsumer(live_consumers.clone());
+                    std::thread::spawn(move || {
+                        let _live = live;
+                        consumer.consume_requests()
+                    })
+                })
+                .collect(),
             msg_queue_for_shutdown,
+            live_consumers,
         }
     }
+
+    pub fn consumers(&self) -> usize {
+        self.worker_handles.len()
+    }
+
+    pub fn live_consumers(&self) -> usize {
+        self.live_consumers.load(Ordering::SeqCst)
+    }
 }
 
-fn help() {
-    println!(
-        "Usage:
-    redis_proxy [options]
-    
-    Options:
-    --cache_expr_sec sets the time in seconds that values will remain in the cache
-    --cache_size     sets the Size of the internal LRU cache
-    --redis_addr     the address of the backing redis"
-    )
+/*
+ * Connects to redis for a config reload. Unlike at startup there is no
+ * retry: if the new redis doesn't answer the reload is abandoned.
+ */
+fn connect_redis(config: &ProxyConfig) -> redis::RedisResult<RedisClientWrapper> {
+    let provider = RedisClientWrapper::new(
+        config.redis_addr.clone(),
+        config.redis_pool_size,
+        config.redis_pool_idle,
+        config.request_timeout,
+    )?;
+    provider.ping()?;
+    Ok(provider)
 }
 
-fn parse_args() -> Option<(std::time::Duration, usize, String)> {
+fn main() {
+    //Before any thread is started, so only the reload thread sees SIGHUP
+    reload::block_sighup();
+
     let args: Vec<String> = std::env::args().collect();
-    match args.len() {
-        1 | 3 | 5 | 7 => println!("parsing args"),
-        _ => {
-            println!("Unexpected args {:?}", args);
-            help();
-            return None;
+    let env: HashMap<String, String> = std::env::vars().collect();
+    let config = match ProxyConfig::load(&args, &env) {
+        Ok(Startup::Run(config)) => config,
+        Ok(Startup::PrintConfig(config)) => {
+            print!("{}", config.to_toml());
+            return;
         }
-    }
-
-    if let Some(_) = args.iter().find(|arg| (*arg).eq("--help")) {
-        help();
-        return None;
-    }
-
-    let expr = match args.iter().position(|arg| (*arg).eq("--cache_expr_sec")) {
-        Some(arg_pos) => std::time::Duration::from_secs(args[arg_pos + 1].parse::<u64>().unwrap()),
-        None => {
-            println!("using default duration 10 sec");
-            std::time::Duration::from_secs(10)
+        Ok(Startup::Help) => {
+            config::help();
+            return;
+        }
+        Err(err) => {
+            eprintln!("invalid configuration {}", err);
+            eprintln!("run with --help to list the options");
+            std::process::exit(2);
         }
     };
 
-    let size = match args.iter().position(|arg| (*arg).eq("--cache_size")) {
-        Some(arg_pos) => args[arg_pos + 1].parse::<usize>().unwrap(),
-        None => {
-            println!("using default cache size 100");
-            100
+    let metrics = Arc::new(ProxyMetrics::default());
+    let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(100);
+    let producer =
+        RedisProducer::new(tx.clone(), config.request_timeout).with_metrics(metrics.clone());
+
+    let work_queue_rx = Arc::new(Mutex::new(rx));
+    let mut cache = ShardedCache::with_policy(
+        config.cache_policy,
+        CACHE_SHARDS,
+        config.cache_size,
+        config.cache_expr,
+    )
+    .with_stale_grace(config.cache_grace)
+    .with_early_expiry(
+        config.cache_expr_jitter_percent,
+        config.cache_early_refresh_percent,
+    );
+    if config.cache_max_bytes > 0 {
+        cache = cache.with_max_bytes(config.cache_max_bytes, config.cache_max_entry_bytes());
+    }
+    let cache = Arc::new(cache);
+    let negative_cache: NegativeCache = if config.negative_cache_size > 0 {
+        Some(Arc::new(ShardedCache::new(
+            CACHE_SHARDS,
+            config.negative_cache_size,
+            config.negative_cache_expr,
+        )))
+    } else {
+        None
+    };
+    let redis_provider = match RedisClientWrapper::new(
+        config.redis_addr.clone(),
+        config.redis_pool_size,
+        config.redis_pool_idle,
+        config.request_timeout,
+    ) {
+        Ok(provider) => provider,
+        Err(err) => {
+            eprintln!("invalid redis address {} {}", config.redis_addr, err);
+            std::process::exit(1);
         }
     };
-
-    let addr = match args.iter().position(|arg| (*arg).eq("--redis_addr")) {
-        Some(arg_pos) => args[arg_pos + 1].clone(),
-        None => {
-            let default_addr = "redis://127.0.0.1/".to_string();
-            println!("using default redis addr {}", default_addr);
-            default_addr
+    if let Err(err) = wait_for_redis(&redis_provider, config.redis_startup_timeout) {
+        eprintln!(
+            "giving up on redis at {} after {:?} {}",
+            config.redis_addr, config.redis_startup_timeout, err
+        );
+        std::process::exit(1);
+    }
+    let redis_provider = Arc::new(ReloadableProvider::new(redis_provider));
+    let mut reloader = Reloader::new(
+        args,
+        env,
+        config.clone(),
+        cache.clone(),
+        redis_provider.clone(),
+        Box::new(connect_redis),
+    );
+    if let Some(ref negative_cache) = negative_cache {
+        reloader = reloader.with_negative_cache(negative_cache.clone());
+    }
+    let reloader = Arc::new(reloader);
+    reloader.clone().spawn_sighup_handler();
+    let single_flight = Arc::new(SingleFlight::default());
+    let consumers = (0..config.workers)
+        .map(|_| {
+            let consumer =
+                RedisConsumer::new(work_queue_rx.clone(), cache.clone(), redis_provider.clone())
+                    .with_single_flight(single_flight.clone())
+                    .with_metrics(metrics.clone());
+            match negative_cache {
+                Some(ref negative_cache) => consumer.with_negative_cache(negative_cache.clone()),
+                None => consumer,
+            }
+        })
+        .collect();
+    let subscription = if config.keyspace_invalidation {
+        println!("Invalidating cache entries on redis keyspace events");
+        match KeyspaceSubscriber::new(&config.redis_addr, tx.clone()) {
+            Ok(subscriber) => Some(subscriber.with_metrics(metrics.clone()).spawn()),
+            Err(err) => {
+                eprintln!("invalid redis address {} {}", config.redis_addr, err);
+                std::process::exit(1);
+            }
         }
+    } else {
+        None
     };
+    let worker = RedisWorker::new(consumers, tx);
 
-    Some((expr, size, addr))
-}
+    if let Some(port) = config.resp_port {
+        let addr = format!("{}:{}", config.address, port);
+        println!("Serving the redis protocol on {}", addr);
+        match RespServer::bind(&addr, producer.clone()) {
+            Ok(server) => {
+                server.spawn();
+            }
+            Err(err) => {
+                eprintln!("failed to bind redis protocol listener {} {}", addr, err);
+                std::process::exit(2);
+            }
+        }
+    }
 
-fn main() {
-    let (expr, size, addr) = match parse_args() {
-        Some(args) => args,
-        None => return,
+    //Apart from the listen address these are the default web server configs,
+    //which gives us 16 worker threads for incoming connections. Building
+    //the config ourselves means Rocket.toml and ROCKET_* other than
+    //ROCKET_ENV are not read
+    let environment = Environment::active().unwrap_or(Environment::Development);
+    let rocket_config = match Config::build(environment)
+        .address(config.address.clone())
+        .port(config.port)
+        .finalize()
+    {
+        Ok(rocket_config) => rocket_config,
+        Err(err) => {
+            eprintln!("invalid listen address {} {}", config.address, err);
+            std::process::exit(2);
+        }
     };
-
-    let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(100);
-    let producer = RedisProducer::new(tx.clone());
-
-    let lru = LRUCache::new(size, expr);
-    let redis_provider = RedisClientWrapper::new(addr);
-    let consumer = RedisConsumer::new(rx, lru, redis_provider);
-    let worker = RedisWorker::new(consumer, tx.clone());
-
-    //Using the default web server configs - which gives us 16 worker threads
-    //for incoming connections
-    rocket::ignite()
+    rocket::custom(rocket_config)
         .manage(producer)
+        .manage(HttpOptions {
+            legacy_empty_miss: config.legacy_empty_miss,
+        })
         .manage(worker) /* passing ownership to rocket triggers cleanup of worker on shutdown */
-        .mount("/", routes![get])
+        .manage(subscription)
+        .manage(cache)
+        .manage(negative_cache)
+        .manage(reloader)
+        .manage(metrics.clone())
+        .attach(HttpMetrics::new(metrics))
+        .mount(
+            "/",
+            routes![
+                metrics, healthz, readyz, reload, mget, mget_query, hash_field, hash, list, set,
+                zset, get, put, delete
+            ],
+        )
         .launch();
     println!("end");
 }
@@ -0,0 +1,386 @@
+use {
+    crate::lru_cache::CacheStats,
+    std::{
+        fmt::Write,
+        sync::atomic::{AtomicI64, AtomicU64, Ordering},
+        time::Duration,
+    },
+};
+
+/*
+ * ProxyMetrics holds the counters and histograms the proxy exposes on
+ * GET /metrics in the Prometheus text format. One instance is shared
+ * behind an Arc by the producers, the consumers and the HTTP front end.
+ *
+ * Everything is a plain atomic, so recording a metric never takes a lock.
+ * Cache stats are not recorded here: the cache keeps its own counts under
+ * its shard locks and they are read when the metrics are rendered.
+ */
+
+//Bucket upper bounds in seconds. Redis round trips are usually well under
+//a millisecond, so the buckets start finer than Prometheus' defaults.
+const LATENCY_BUCKETS: [f64; 14] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+//HTTP status codes run from 100 to 599
+const STATUS_CODES: usize = 600;
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/*
+ * Each bucket counts only the observations that fell into it. They are
+ * summed into Prometheus' cumulative buckets when rendered.
+ */
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    sum_nanos: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            //The extra bucket is +Inf
+            buckets: (0..=LATENCY_BUCKETS.len())
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            sum_nanos: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or_else(|| LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub struct ProxyMetrics {
+    pub redis_fetch_duration: Histogram,
+    pub redis_errors: Counter,
+    //Keyspace events not passed on to the consumers as the work queue was
+    //full
+    pub invalidations_dropped: Counter,
+    //Requests sent to the consumers that no consumer has picked up yet
+    pub queue_depth: Gauge,
+    //Time producers spend blocked sending to a full work queue
+    pub queue_enqueue_wait: Histogram,
+    pub http_request_duration: Histogram,
+    http_responses: Vec<AtomicU64>,
+}
+
+impl Default for ProxyMetrics {
+    fn default() -> ProxyMetrics {
+        ProxyMetrics {
+            redis_fetch_duration: Histogram::default(),
+            redis_errors: Counter::default(),
+            invalidations_dropped: Counter::default(),
+            queue_depth: Gauge::default(),
+            queue_enqueue_wait: Histogram::default(),
+            http_request_duration: Histogram::default(),
+            http_responses: (0..STATUS_CODES).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+}
+
+impl ProxyMetrics {
+    pub fn record_http_response(&self, status: u16, elapsed: Duration) {
+        if let Some(count) = self.http_responses.get(status as usize) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+        self.http_request_duration.observe(elapsed);
+    }
+
+    /*
+     * A lookup the negative cache answers has already missed the value
+     * cache, so it is counted in both cache_misses_total and
+     * negative_cache_hits_total
+     */
+    pub fn render(&self, cache: CacheStats, negative_cache: CacheStats) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "cache_hits_total",
+                "Cache lookups that found a value",
+                cache.hits,
+            ),
+            (
+                "cache_misses_total",
+                "Cache lookups that found no value",
+                cache.misses,
+            ),
+            (
+                "cache_expirations_total",
+                "Cache entries dropped on lookup because they had expired",
+                cache.expirations,
+            ),
+            (
+                "cache_evictions_total",
+                "Cache entries evicted to make room for new ones",
+                cache.evictions,
+            ),
+            (
+                "cache_oversized_total",
+                "Values not cached because they were over the largest entry allowed",
+                cache.oversized,
+            ),
+            (
+                "cache_stale_hits_total",
+                "Expired values served from the grace window while being refreshed",
+                cache.stale_hits,
+            ),
+            (
+                "cache_early_refreshes_total",
+                "Hits that refreshed their entry from redis before it expired",
+                cache.early_refreshes,
+            ),
+            (
+                "negative_cache_hits_total",
+                "Lookups answered as missing without asking redis",
+                negative_cache.hits,
+            ),
+            (
+                "negative_cache_evictions_total",
+                "Remembered misses evicted to make room for new ones",
+                negative_cache.evictions,
+            ),
+            (
+                "redis_errors_total",
+                "Redis commands that failed",
+                self.redis_errors.get(),
+            ),
+            (
+                "invalidations_dropped_total",
+                "Keyspace events dropped because the work queue was full",
+                self.invalidations_dropped.get(),
+            ),
+        ];
+        for (name, help, val) in counters.iter() {
+            write_header(&mut out, name, help, "counter");
+            writeln!(out, "redis_proxy_{} {}", name, val).unwrap();
+        }
+
+        write_header(&mut out, "cache_entries", "Entries in the cache", "gauge");
+        writeln!(out, "redis_proxy_cache_entries {}", cache.entries).unwrap();
+        write_header(
+            &mut out,
+            "cache_bytes",
+            "Approximate size of the cache entries in bytes",
+            "gauge",
+        );
+        writeln!(out, "redis_proxy_cache_bytes {}", cache.bytes).unwrap();
+        write_header(
+            &mut out,
+            "negative_cache_entries",
+            "Misses remembered in the negative cache",
+            "gauge",
+        );
+        writeln!(
+            out,
+            "redis_proxy_negative_cache_entries {}",
+            negative_cache.entries
+        )
+        .unwrap();
+        write_header(
+            &mut out,
+            "queue_depth",
+            "Requests waiting for a consumer",
+            "gauge",
+        );
+        writeln!(out, "redis_proxy_queue_depth {}", self.queue_depth.get()).unwrap();
+
+        write_header(
+            &mut out,
+            "http_responses_total",
+            "HTTP responses by status code",
+            "counter",
+        );
+        for (code, count) in self.http_responses.iter().enumerate() {
+            let count = count.load(Ordering::Relaxed);
+            if count > 0 {
+                writeln!(
+                    out,
+                    "redis_proxy_http_responses_total{{code=\"{}\"}} {}",
+                    code, count
+                )
+                .unwrap();
+            }
+        }
+
+        let histograms = [
+            (
+                "redis_fetch_duration_seconds",
+                "Time taken to fetch a key from redis",
+                &self.redis_fetch_duration,
+            ),
+            (
+                "queue_enqueue_wait_seconds",
+                "Time spent waiting to send a request to the consumers",
+                &self.queue_enqueue_wait,
+            ),
+            (
+                "http_request_duration_seconds",
+                "Time taken to answer an HTTP request",
+                &self.http_request_duration,
+            ),
+        ];
+        for (name, help, histogram) in histograms.iter() {
+            write_header(&mut out, name, help, "histogram");
+            write_histogram(&mut out, name, histogram);
+        }
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP redis_proxy_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE redis_proxy_{} {}", name, kind).unwrap();
+}
+
+fn write_histogram(out: &mut String, name: &str, histogram: &Histogram) {
+    let mut cumulative = 0;
+    for (i, bucket) in histogram.buckets.iter().enumerate() {
+        cumulative += bucket.load(Ordering::Relaxed);
+        let bound = match LATENCY_BUCKETS.get(i) {
+            Some(bound) => bound.to_string(),
+            None => String::from("+Inf"),
+        };
+        writeln!(
+            out,
+            "redis_proxy_{}_bucket{{le=\"{}\"}} {}",
+            name, bound, cumulative
+        )
+        .unwrap();
+    }
+    let sum = histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+    writeln!(out, "redis_proxy_{}_sum {}", name, sum).unwrap();
+    writeln!(
+        out,
+        "redis_proxy_{}_count {}",
+        name,
+        histogram.count.load(Ordering::Relaxed)
+    )
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::*;
+
+    #[test]
+    fn test_histogram_buckets_cumulative() {
+        let metrics = ProxyMetrics::default();
+        metrics
+            .redis_fetch_duration
+            .observe(Duration::from_micros(300));
+        metrics
+            .redis_fetch_duration
+            .observe(Duration::from_millis(3));
+        metrics
+            .redis_fetch_duration
+            .observe(Duration::from_secs(60));
+
+        let out = metrics.render(CacheStats::default(), CacheStats::default());
+        let prefix = "redis_proxy_redis_fetch_duration_seconds";
+        assert!(out.contains(&format!("{}_bucket{{le=\"0.0005\"}} 1\n", prefix)));
+        assert!(out.contains(&format!("{}_bucket{{le=\"0.005\"}} 2\n", prefix)));
+        assert!(out.contains(&format!("{}_bucket{{le=\"10\"}} 2\n", prefix)));
+        assert!(out.contains(&format!("{}_bucket{{le=\"+Inf\"}} 3\n", prefix)));
+        assert!(out.contains(&format!("{}_count 3\n", prefix)));
+        assert!(out.contains(&format!("{}_sum 60.0033\n", prefix)));
+    }
+
+    #[test]
+    fn test_render_counts() {
+        let metrics = ProxyMetrics::default();
+        metrics.record_http_response(200, Duration::from_millis(1));
+        metrics.record_http_response(200, Duration::from_millis(1));
+        metrics.record_http_response(404, Duration::from_millis(1));
+        metrics.queue_depth.inc();
+        metrics.redis_errors.inc();
+        let cache = CacheStats {
+            hits: 5,
+            misses: 2,
+            expirations: 1,
+            evictions: 3,
+            oversized: 6,
+            stale_hits: 5,
+            early_refreshes: 8,
+            entries: 7,
+            bytes: 2048,
+        };
+        let negative_cache = CacheStats {
+            hits: 4,
+            misses: 2,
+            expirations: 0,
+            evictions: 1,
+            oversized: 0,
+            stale_hits: 0,
+            early_refreshes: 0,
+            entries: 2,
+            bytes: 0,
+        };
+
+        let out = metrics.render(cache, negative_cache);
+        for line in &[
+            "redis_proxy_cache_hits_total 5",
+            "redis_proxy_cache_misses_total 2",
+            "redis_proxy_cache_expirations_total 1",
+            "redis_proxy_cache_evictions_total 3",
+            "redis_proxy_cache_entries 7",
+            "redis_proxy_cache_oversized_total 6",
+            "redis_proxy_cache_stale_hits_total 5",
+            "redis_proxy_cache_early_refreshes_total 8",
+            "redis_proxy_cache_bytes 2048",
+            "redis_proxy_negative_cache_hits_total 4",
+            "redis_proxy_negative_cache_evictions_total 1",
+            "redis_proxy_negative_cache_entries 2",
+            "redis_proxy_redis_errors_total 1",
+            "redis_proxy_queue_depth 1",
+            "redis_proxy_http_responses_total{code=\"200\"} 2",
+            "redis_proxy_http_responses_total{code=\"404\"} 1",
+            "# TYPE redis_proxy_http_request_duration_seconds histogram",
+        ] {
+            assert!(out.contains(&format!("{}\n", line)), "missing {}", line);
+        }
+        assert!(!out.contains("code=\"500\""));
+    }
+}
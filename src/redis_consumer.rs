@@ -1,14 +1,172 @@
 use {
-    crate::lru_cache::Cache, crate::redis_request::Message, redis::Commands,
-    std::sync::mpsc::Receiver,
+    crate::cache_value::{CacheValue, ValueKind, ValuePart},
+    crate::lru_cache::Cache,
+    crate::metrics::ProxyMetrics,
+    crate::redis_pool::ConnectionPool,
+    crate::redis_request::{Message, Operation, ProxyResult, RedisRequest, SharedReceiver},
+    crate::single_flight::SingleFlight,
+    std::{
+        fmt::Display,
+        sync::{Arc, RwLock},
+        thread,
+        time::{Duration, Instant},
+    },
 };
 
+/*
+ * A value fetched from the backing redis along with the remaining time
+ * to live of its key. ttl is None when the key has no expiry set.
+ */
+#[derive(Debug, PartialEq)]
+pub struct FetchedValue {
+    pub val: CacheValue,
+    pub ttl: Option<Duration>,
+}
+
+//What a fetch found for a key
+#[derive(Debug, PartialEq)]
+pub enum Fetched {
+    Value(FetchedValue),
+    Missing,
+    //The key holds this other kind of value, which was not read
+    WrongType(ValueKind),
+}
+
 /*
  * This trait defines the interface through which our consumer can
- * get data from the backing redis
+ * get data from, and write data to, the backing redis
+ *
+ * fetch reads a key expected to hold the given kind of value. If the key
+ * holds some other kind only its kind is looked up, as reading a whole
+ * collection just to refuse it could cost megabytes.
+ *
+ * fetch_part reads one part of a collection, along with how many members
+ * the whole collection has. By default it fetches the whole value and
+ * picks the part out of it, so a provider that can read the part on its
+ * own should override it.
+ *
+ * fetch_many returns a value for each key, in order. By default it
+ * fetches them one at a time, so a provider that can batch them into one
+ * round trip should override it. delete returns whether the key existed,
+ * and ping checks that redis is reachable and answering.
  */
 pub trait RedisProvider {
-    fn fetch(&self, key: &String) -> Result<Option<String>, redis::RedisError>;
+    fn fetch(&self, key: &str, kind: ValueKind) -> Result<Fetched, redis::RedisError>;
+    fn fetch_part(
+        &self,
+        key: &str,
+        part: &ValuePart,
+    ) -> Result<(Fetched, usize), redis::RedisError> {
+        Ok(match self.fetch(key, part.kind())? {
+            Fetched::Value(FetchedValue { val, ttl }) => match part.select(&val) {
+                Some(selected) => (
+                    Fetched::Value(FetchedValue { val: selected, ttl }),
+                    val.members(),
+                ),
+                None => (Fetched::WrongType(val.kind()), 0),
+            },
+            fetched => (fetched, 0),
+        })
+    }
+    fn fetch_many(&self, keys: &[&str]) -> Result<Vec<Fetched>, redis::RedisError> {
+        keys.iter()
+            .map(|key| self.fetch(key, ValueKind::Str))
+            .collect()
+    }
+    fn store(&self, key: &str, val: &[u8], ttl: Option<Duration>) -> Result<(), redis::RedisError>;
+    fn delete(&self, key: &str) -> Result<bool, redis::RedisError>;
+    fn ping(&self) -> Result<(), redis::RedisError>;
+}
+
+/*
+ * Lets a pool of consumers share a single provider
+ */
+impl<T: RedisProvider + ?Sized> RedisProvider for Arc<T> {
+    fn fetch(&self, key: &str, kind: ValueKind) -> Result<Fetched, redis::RedisError> {
+        (**self).fetch(key, kind)
+    }
+
+    fn fetch_part(
+        &self,
+        key: &str,
+        part: &ValuePart,
+    ) -> Result<(Fetched, usize), redis::RedisError> {
+        (**self).fetch_part(key, part)
+    }
+
+    fn fetch_many(&self, keys: &[&str]) -> Result<Vec<Fetched>, redis::RedisError> {
+        (**self).fetch_many(keys)
+    }
+
+    fn store(&self, key: &str, val: &[u8], ttl: Option<Duration>) -> Result<(), redis::RedisError> {
+        (**self).store(key, val, ttl)
+    }
+
+    fn delete(&self, key: &str) -> Result<bool, redis::RedisError> {
+        (**self).delete(key)
+    }
+
+    fn ping(&self) -> Result<(), redis::RedisError> {
+        (**self).ping()
+    }
+}
+
+/*
+ * Wraps a provider so it can be replaced while the proxy is running, e.g.
+ * to point it at a new redis address. Each command runs against whichever
+ * provider was current when it started. The provider it replaced is
+ * dropped, closing its connections, once the commands still running
+ * against it have finished, so in-flight requests are never cut off.
+ */
+pub struct ReloadableProvider<T: RedisProvider> {
+    current: RwLock<Arc<T>>,
+}
+
+impl<T: RedisProvider> ReloadableProvider<T> {
+    pub fn new(provider: T) -> ReloadableProvider<T> {
+        ReloadableProvider {
+            current: RwLock::new(Arc::new(provider)),
+        }
+    }
+
+    //Returns the provider that was replaced
+    pub fn replace(&self, provider: T) -> Arc<T> {
+        std::mem::replace(&mut *self.current.write().unwrap(), Arc::new(provider))
+    }
+
+    fn current(&self) -> Arc<T> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+impl<T: RedisProvider> RedisProvider for ReloadableProvider<T> {
+    fn fetch(&self, key: &str, kind: ValueKind) -> Result<Fetched, redis::RedisError> {
+        self.current().fetch(key, kind)
+    }
+
+    fn fetch_part(
+        &self,
+        key: &str,
+        part: &ValuePart,
+    ) -> Result<(Fetched, usize), redis::RedisError> {
+        self.current().fetch_part(key, part)
+    }
+
+    fn fetch_many(&self, keys: &[&str]) -> Result<Vec<Fetched>, redis::RedisError> {
+        self.current().fetch_many(keys)
+    }
+
+    fn store(&self, key: &str, val: &[u8], ttl: Option<Duration>) -> Result<(), redis::RedisError> {
+        self.current().store(key, val, ttl)
+    }
+
+    fn delete(&self, key: &str) -> Result<bool, redis::RedisError> {
+        self.current().delete(key)
+    }
+
+    fn ping(&self) -> Result<(), redis::RedisError> {
+        self.current().ping()
+    }
 }
 
 /*
@@ -16,36 +174,308 @@ pub trait RedisProvider {
  * of getting results from the redis client. This implements the
  * RedisProvider trait so that we can pass this into our redis
  * consumer, allowing it to get results from the backing redis
+ *
+ * Connections are kept open in a bounded ConnectionPool and shared
+ * by every consumer, rather than opened for each fetch.
  */
 
 pub struct RedisClientWrapper {
-    redis_url: String,
+    pool: ConnectionPool<redis::Connection>,
 }
 
 impl RedisProvider for RedisClientWrapper {
-    fn fetch(&self, key: &String) -> Result<Option<String>, redis::RedisError> {
-        let client = redis::Client::open(self.redis_url.clone())?;
-        let mut con = client.get_connection()?;
-        con.get(key)
+    //Redis answers WRONGTYPE if the key holds another kind of value
+    fn fetch(&self, key: &str, kind: ValueKind) -> Result<Fetched, redis::RedisError> {
+        match self.fetch_kind(key, kind) {
+            Err(err) if err.code() == Some("WRONGTYPE") => self.held_kind(key),
+            result => result,
+        }
+    }
+
+    /*
+     * Reads the length along with the part, in one round trip. Both fail
+     * with WRONGTYPE on another kind of value, and the client stops
+     * reading a pipeline at the first error reply, so the replies are read
+     * one by one to leave neither behind on the connection. The length is
+     * 0 only for a key that doesn't exist. The ttl isn't read, as a part
+     * is never cached.
+     */
+    fn fetch_part(
+        &self,
+        key: &str,
+        part: &ValuePart,
+    ) -> Result<(Fetched, usize), redis::RedisError> {
+        let mut pipe = redis::pipe();
+        match part {
+            ValuePart::HashField(field) => {
+                pipe.cmd("HLEN").arg(key).cmd("HGET").arg(key).arg(field)
+            }
+            ValuePart::ListRange { start, stop } => pipe
+                .cmd("LLEN")
+                .arg(key)
+                .cmd("LRANGE")
+                .arg(key)
+                .arg(*start)
+                .arg(*stop),
+        };
+        let replies = self.with_connection(|con| {
+            con.send_packed_command(&pipe.get_packed_pipeline())?;
+            match con.recv_response() {
+                Err(err) if err.is_io_error() => Err(err),
+                members => match con.recv_response() {
+                    Err(err) if err.is_io_error() => Err(err),
+                    part => Ok(members.and_then(|members| Ok(vec![members, part?]))),
+                },
+            }
+        });
+        let replies = match replies.and_then(|replies| replies) {
+            Err(err) if err.code() == Some("WRONGTYPE") => return Ok((self.held_kind(key)?, 0)),
+            replies => replies?,
+        };
+        let members: usize = redis::from_redis_value(&replies[0])?;
+        if members == 0 {
+            return Ok((Fetched::Missing, 0));
+        }
+        let val = match part {
+            ValuePart::HashField(field) => {
+                let val: Option<String> = redis::from_redis_value(&replies[1])?;
+                CacheValue::Hash(val.map(|val| (field.clone(), val)).into_iter().collect())
+            }
+            ValuePart::ListRange { .. } => CacheValue::List(redis::from_redis_value(&replies[1])?),
+        };
+        Ok((Fetched::Value(FetchedValue { val, ttl: None }), members))
+    }
+
+    /*
+     * One MGET for the values, pipelined with a PTTL for each key, so the
+     * whole batch takes a single round trip
+     */
+    fn fetch_many(&self, keys: &[&str]) -> Result<Vec<Fetched>, redis::RedisError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let replies: Vec<redis::Value> = self.with_connection(|con| {
+            let mut pipe = redis::pipe();
+            pipe.cmd("MGET").arg(keys);
+            for key in keys {
+                pipe.cmd("PTTL").arg(*key);
+            }
+            pipe.query(con)
+        })?;
+        let vals: Vec<Option<Vec<u8>>> = redis::from_redis_value(&replies[0])?;
+        let mut fetched = Vec::with_capacity(keys.len());
+        for ((key, val), pttl) in keys.iter().zip(vals).zip(&replies[1..]) {
+            let pttl: i64 = redis::from_redis_value(pttl)?;
+            fetched.push(match val {
+                Some(val) => Fetched::Value(FetchedValue {
+                    val: CacheValue::Str(val),
+                    ttl: pttl_to_ttl(pttl),
+                }),
+                //MGET answers nil for a key holding a hash, list or set as
+                //well as for a missing one, but only a missing key has no ttl
+                None if pttl != -2 => self.fetch(key, ValueKind::Str)?,
+                None => Fetched::Missing,
+            });
+        }
+        Ok(fetched)
+    }
+
+    fn store(&self, key: &str, val: &[u8], ttl: Option<Duration>) -> Result<(), redis::RedisError> {
+        self.with_connection(|con| {
+            let mut set = redis::cmd("SET");
+            set.arg(key).arg(val);
+            if let Some(ttl) = ttl {
+                set.arg("PX").arg(ttl.as_millis() as u64);
+            }
+            set.query(con)
+        })
+    }
+
+    fn delete(&self, key: &str) -> Result<bool, redis::RedisError> {
+        let deleted: i64 = self.with_connection(|con| redis::cmd("DEL").arg(key).query(con))?;
+        Ok(deleted > 0)
+    }
+
+    fn ping(&self) -> Result<(), redis::RedisError> {
+        let _: String = self.with_connection(|con| redis::cmd("PING").query(con))?;
+        Ok(())
+    }
+}
+
+/*
+ * PTTL replies with the remaining lifetime in milliseconds, -1 if the key
+ * has no expiry, or -2 if the key does not exist. The key can come and
+ * go between our PTTL and reading its value, in which case a zero ttl
+ * stops us caching it.
+ */
+fn pttl_to_ttl(pttl: i64) -> Option<Duration> {
+    match pttl {
+        -1 => None,
+        pttl if pttl < 0 => Some(Duration::from_millis(0)),
+        pttl => Some(Duration::from_millis(pttl as u64)),
     }
 }
 
 impl RedisClientWrapper {
-    pub fn new(redis_url: String) -> RedisClientWrapper {
-        //TODO sleep and Retry once on failure, its possible the
-        //proxy started up before redis
+    /*
+     * Reads the whole value along with its PTTL, so we learn how long
+     * redis will keep the key for in the same round trip. The value
+     * command goes last in the pipeline: the client stops reading at an
+     * error reply such as WRONGTYPE, and any reply after it would be left
+     * behind for the next command on the connection.
+     */
+    fn fetch_kind(&self, key: &str, kind: ValueKind) -> Result<Fetched, redis::RedisError> {
+        let replies: Vec<redis::Value> = self.with_connection(|con| {
+            let mut pipe = redis::pipe();
+            pipe.cmd("PTTL").arg(key);
+            match kind {
+                ValueKind::Str => pipe.cmd("GET").arg(key),
+                ValueKind::Hash => pipe.cmd("HGETALL").arg(key),
+                ValueKind::List => pipe.cmd("LRANGE").arg(key).arg(0).arg(-1),
+                ValueKind::Set => pipe.cmd("SMEMBERS").arg(key),
+                ValueKind::ZSet => pipe.cmd("ZRANGE").arg(key).arg(0).arg(-1).arg("WITHSCORES"),
+            }
+            .query(con)
+        })?;
+        let pttl: i64 = redis::from_redis_value(&replies[0])?;
+        Ok(match CacheValue::from_reply(kind, &replies[1])? {
+            Some(val) => Fetched::Value(FetchedValue {
+                val,
+                ttl: pttl_to_ttl(pttl),
+            }),
+            None => Fetched::Missing,
+        })
+    }
+
+    //What a key that redis answered WRONGTYPE for holds instead
+    fn held_kind(&self, key: &str) -> Result<Fetched, redis::RedisError> {
+        let type_name: String =
+            self.with_connection(|con| redis::cmd("TYPE").arg(key).query(con))?;
+        match ValueKind::from_type_name(&type_name) {
+            Some(kind) => Ok(Fetched::WrongType(kind)),
+            //Deleted since
+            None if type_name == "none" => Ok(Fetched::Missing),
+            None => Err(redis::RedisError::from((
+                redis::ErrorKind::TypeError,
+                "key holds a type the proxy can't serve",
+                type_name,
+            ))),
+        }
+    }
+
+    /*
+     * Only fails if the url is invalid. Connections are opened lazily, so
+     * whether redis is actually reachable is checked separately with
+     * wait_for_redis.
+     *
+     * Connecting, every read and write on a connection, and waiting for a
+     * connection when the pool is exhausted give up after io_timeout, so a
+     * redis that hangs rather than refusing connections can't hold the
+     * consumers forever. A timed out connection is discarded, as its reply
+     * may still arrive.
+     */
+    pub fn new(
+        redis_url: String,
+        pool_size: usize,
+        idle_timeout: Duration,
+        io_timeout: Duration,
+    ) -> redis::RedisResult<RedisClientWrapper> {
         println!("Initializing redis client at addr {:?}", redis_url);
-        let client = redis::Client::open(redis_url.clone()).unwrap();
-        let mut con = client
-            .get_connection()
-            .expect("RedisClientWrapper failed to connect to redis ");
-        let pong: Option<String> = redis::cmd("PING").query(&mut con).unwrap();
-        assert_eq!(pong, Some("PONG".to_string()));
+        let client = redis::Client::open(redis_url)?;
+        let pool = ConnectionPool::new(
+            Box::new(move || {
+                let con = client.get_connection_with_timeout(io_timeout)?;
+                con.set_read_timeout(Some(io_timeout))?;
+                con.set_write_timeout(Some(io_timeout))?;
+                Ok(con)
+            }),
+            pool_size,
+            idle_timeout,
+        )
+        .with_wait_timeout(io_timeout);
+        Ok(RedisClientWrapper { pool })
+    }
+
+    /*
+     * Runs a command on a pooled connection. A connection that fails with
+     * an IO error is discarded rather than returned to the pool, and if the
+     * connection had been dropped (a broken pipe, or redis closing idle
+     * clients) the command is retried once on a fresh connection.
+     */
+    fn with_connection<T, F>(&self, command: F) -> redis::RedisResult<T>
+    where
+        F: Fn(&mut redis::Connection) -> redis::RedisResult<T>,
+    {
+        let mut con = self.pool.get()?;
+        match command(&mut con) {
+            Err(err) if err.is_connection_dropped() => {
+                con.discard();
+                let mut con = self.pool.get()?;
+                let result = command(&mut con);
+                if let Err(ref err) = result {
+                    if err.is_io_error() {
+                        con.discard();
+                    }
+                }
+                result
+            }
+            Err(err) if err.is_io_error() => {
+                con.discard();
+                Err(err)
+            }
+            result => result,
+        }
+    }
+}
+
+//Delays between attempts to reach redis at startup
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/*
+ * The proxy may well start before redis does, so rather than failing on
+ * the first refused connection we PING with backoff until redis answers
+ * or give_up_after has passed
+ */
+pub fn wait_for_redis<TProvider: RedisProvider>(
+    provider: &TProvider,
+    give_up_after: Duration,
+) -> Result<(), redis::RedisError> {
+    retry_with_backoff(INITIAL_BACKOFF, MAX_BACKOFF, give_up_after, || {
+        provider.ping()
+    })
+}
 
-        RedisClientWrapper { redis_url }
+/*
+ * Calls attempt until it succeeds, doubling the delay between attempts
+ * from initial up to max. Once give_up_after has passed the last error
+ * is returned.
+ */
+fn retry_with_backoff<T, E: Display>(
+    initial: Duration,
+    max: Duration,
+    give_up_after: Duration,
+    mut attempt: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let start = Instant::now();
+    let mut delay = initial;
+    loop {
+        match attempt() {
+            Ok(val) => return Ok(val),
+            Err(err) if start.elapsed() + delay > give_up_after => return Err(err),
+            Err(err) => {
+                eprintln!("redis not reachable, retrying in {:?} {}", delay, err);
+                thread::sleep(delay);
+                delay = (delay * 2).min(max);
+            }
+        }
     }
 }
 
+//A collection read a part at a time is only fetched whole, and cached, if
+//it has at most this many members
+const MAX_CACHED_MEMBERS: usize = 1000;
+
 /*
  * Generic Consumer of RedisRequests intended to provide loose coupling
  * between our Request consumer, cache implementation, and backing redis
@@ -53,10 +483,13 @@ impl RedisClientWrapper {
  * dependencies and test this code
  */
 
-pub struct RedisConsumer<TCache: Cache, TProvider: RedisProvider> {
-    work_queue_rx: Receiver<Message>,
+pub struct RedisConsumer<TCache: Cache<CacheValue>, TProvider: RedisProvider> {
+    work_queue_rx: SharedReceiver,
     redis_provider: TProvider,
     cache: TCache,
+    negative_cache: Option<Box<dyn Cache<()> + Send>>,
+    single_flight: Arc<SingleFlight>,
+    metrics: Arc<ProxyMetrics>,
 }
 
 /*
@@ -74,11 +507,11 @@ pub struct RedisConsumer<TCache: Cache, TProvider: RedisProvider> {
  */
 impl<TCache, TProvider> RedisConsumer<TCache, TProvider>
 where
-    TCache: Cache + Send + 'static,
+    TCache: Cache<CacheValue> + Send + 'static,
     TProvider: RedisProvider + Send + 'static,
 {
     pub fn new(
-        work_queue_rx: Receiver<Message>,
+        work_queue_rx: SharedReceiver,
         cache: TCache,
         redis_provider: TProvider,
     ) -> RedisConsumer<TCache, TProvider> {
@@ -86,73 +519,526 @@ where
             work_queue_rx,
             redis_provider,
             cache,
+            negative_cache: None,
+            single_flight: Arc::new(SingleFlight::default()),
+            metrics: Arc::new(ProxyMetrics::default()),
         }
     }
 
+    /*
+     * Concurrent misses on a key are only coalesced between consumers that
+     * share a SingleFlight, so a pool of consumers should all be given
+     * the same one
+     */
+    pub fn with_single_flight(
+        mut self,
+        single_flight: Arc<SingleFlight>,
+    ) -> RedisConsumer<TCache, TProvider> {
+        self.single_flight = single_flight;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<ProxyMetrics>) -> RedisConsumer<TCache, TProvider> {
+        self.metrics = metrics;
+        self
+    }
+
+    /*
+     * Remembers keys redis had no value for, so repeated lookups of a
+     * missing key are answered without a round trip. The misses are kept
+     * apart from the values, with their own capacity and lifetime, so a
+     * run of lookups for missing keys can't push real values out.
+     */
+    pub fn with_negative_cache<TNegative>(
+        mut self,
+        negative_cache: TNegative,
+    ) -> RedisConsumer<TCache, TProvider>
+    where
+        TNegative: Cache<()> + Send + 'static,
+    {
+        self.negative_cache = Some(Box::new(negative_cache));
+        self
+    }
+
     pub fn consume_requests(mut self) {
-        for msg in self.work_queue_rx {
+        loop {
+            //The queue lock guard is a temporary, so it is released as soon
+            //as recv returns and other consumers can pull the next message
+            //while we process this one
+            let msg = self.work_queue_rx.lock().unwrap().recv();
             match msg {
-                Message::Shutdown => return,
-                Message::Request(mut request) => {
-                    let key = request.key.clone();
-                    let cached_get = self.cache.get(&key);
-                    match cached_get {
-                        Some(val) => request.set_result(Ok(Some(val))),
-                        None => {
-                            let redis_get = self.redis_provider.fetch(&key);
-                            //Only fill cache on successful redis response
-                            if let Ok(Some(ref val)) = redis_get {
-                                self.cache.put(&key, val.clone());
+                Ok(Message::Shutdown) | Err(_) => return,
+                Ok(Message::Request(request)) => {
+                    self.metrics.queue_depth.dec();
+                    self.handle_request(request)
+                }
+                Ok(Message::Invalidate(key)) => self.invalidate(&key),
+            }
+        }
+    }
+
+    fn handle_request(&mut self, mut request: RedisRequest) {
+        //The producer has already given up on this request
+        if request.expired() {
+            request.set_result(ProxyResult::Timeout);
+            return;
+        }
+        match request.op {
+            Operation::Get => self.handle_get(request, ValueKind::Str),
+            Operation::GetTyped(kind) => self.handle_get(request, kind),
+            Operation::GetPart(ref part) => {
+                let part = part.clone();
+                self.handle_get_part(request, part)
+            }
+            Operation::GetMany(_) => self.handle_get_many(request),
+            Operation::Put { .. } | Operation::Delete => self.handle_write(request),
+            Operation::Ping => {
+                let result = match self.redis_provider.ping() {
+                    Ok(()) => ProxyResult::Pong,
+                    Err(err) => ProxyResult::from(Err(err)),
+                };
+                request.set_result(result);
+            }
+        }
+    }
+
+    /*
+     * Writes go to redis first and the cache is only changed once redis
+     * has accepted them. The key is claimed in the SingleFlight for the
+     * whole write so no fetch of the old value can land in the cache
+     * afterwards. If redis fails the write may or may not have been
+     * applied, so the cached entry is dropped either way.
+     */
+    fn handle_write(&mut self, mut request: RedisRequest) {
+        let key = request.key.clone();
+        self.single_flight.claim(&key);
+        let op = std::mem::replace(&mut request.op, Operation::Get);
+        let (result, parked_result) = match op {
+            Operation::Put { val, ttl } => match self.redis_provider.store(&key, &val, ttl) {
+                Ok(()) => {
+                    self.cache.remove(&key);
+                    self.forget_miss(&key);
+                    self.cache.put(&key, CacheValue::Str(val.clone()), ttl);
+                    (ProxyResult::Stored, ProxyResult::Hit(val))
+                }
+                Err(err) => self.write_failed(&key, err),
+            },
+            Operation::Delete => match self.redis_provider.delete(&key) {
+                Ok(existed) => {
+                    self.cache.remove(&key);
+                    self.remember_miss(&key);
+                    (ProxyResult::Deleted(existed), ProxyResult::Miss)
+                }
+                Err(err) => self.write_failed(&key, err),
+            },
+            Operation::Get
+            | Operation::GetTyped(_)
+            | Operation::GetPart(_)
+            | Operation::GetMany(_)
+            | Operation::Ping => unreachable!("handle_write called with a read"),
+        };
+        self.single_flight.finish(&key, parked_result);
+        request.set_result(result);
+    }
+
+    fn write_failed(&mut self, key: &str, err: redis::RedisError) -> (ProxyResult, ProxyResult) {
+        self.metrics.redis_errors.inc();
+        self.cache.remove(key);
+        self.forget_miss(key);
+        let result = ProxyResult::from(Err(err));
+        (result.clone(), result)
+    }
+
+    /*
+     * A fetch that is outstanding when the invalidation arrives may have
+     * read the old value, so we wait for it to land in the cache before
+     * dropping the entry
+     */
+    fn invalidate(&mut self, key: &str) {
+        let cache = &mut self.cache;
+        let negative_cache = &mut self.negative_cache;
+        self.single_flight.run_exclusive(key, || {
+            cache.remove(key);
+            if let Some(negative_cache) = negative_cache {
+                negative_cache.remove(key);
+            }
+        });
+    }
+
+    //Whether redis recently had no value for the key
+    fn known_miss(&mut self, key: &str) -> bool {
+        match self.negative_cache {
+            Some(ref mut negative_cache) => negative_cache.get(key).is_some(),
+            None => false,
+        }
+    }
+
+    fn remember_miss(&mut self, key: &str) {
+        if let Some(ref mut negative_cache) = self.negative_cache {
+            negative_cache.put(key, (), None);
+        }
+    }
+
+    fn forget_miss(&mut self, key: &str) {
+        if let Some(ref mut negative_cache) = self.negative_cache {
+            negative_cache.remove(key);
+        }
+    }
+
+    /*
+     * Whatever kind of value the key holds is passed back, from the cache
+     * or from redis. It is up to the front end to decide what to do when
+     * that isn't the kind it asked for.
+     */
+    fn handle_get(&mut self, mut request: RedisRequest, kind: ValueKind) {
+        if self.answer_from_cache(&mut request, kind) {
+            return;
+        }
+        //Another consumer is already fetching this key and will complete
+        //our request along with its own
+        if !self.single_flight.join(&request) {
+            return;
+        }
+        let key = request.key.clone();
+        let result = self.fetch(&key, kind);
+        self.single_flight.complete(&mut request, result);
+    }
+
+    /*
+     * A cached value answers a read of one part of it as it would any
+     * other get. Otherwise only the part is read from redis, so one field
+     * of a huge hash doesn't pull in the whole of it. A collection small
+     * enough to cache is then fetched whole once the client has its
+     * answer, so the reads after it are hits.
+     *
+     * The part isn't led through the SingleFlight, whose followers all
+     * get the leader's result, as other reads of the key may want other
+     * parts. Nothing read here reaches either cache, so it can't outlive
+     * a write the way an unordered fetch could, and for the same reason a
+     * missing key isn't remembered as a miss.
+     */
+    fn handle_get_part(&mut self, mut request: RedisRequest, part: ValuePart) {
+        let kind = part.kind();
+        if self.answer_from_cache(&mut request, kind) {
+            return;
+        }
+        let key = request.key.clone();
+        let start = Instant::now();
+        let redis_get = self.redis_provider.fetch_part(&key, &part);
+        self.metrics.redis_fetch_duration.observe(start.elapsed());
+        let (result, members) = match redis_get {
+            Ok((Fetched::Value(fetched), members)) => (ProxyResult::Part(fetched.val), members),
+            Ok((Fetched::Missing, _)) => (ProxyResult::Miss, 0),
+            Ok((Fetched::WrongType(held), _)) => (ProxyResult::WrongType(held), 0),
+            Err(err) => {
+                self.metrics.redis_errors.inc();
+                (ProxyResult::from(Err(err)), 0)
+            }
+        };
+        request.set_result(result);
+        if members > 0 && members <= MAX_CACHED_MEMBERS {
+            self.refresh(&key, kind);
+        }
+    }
+
+    /*
+     * Answers the request from the cache if it can: with a fresh value,
+     * refreshing it first if the cache asks to, with a known miss, or with
+     * a stale value while it is refreshed. Returns whether it answered.
+     */
+    fn answer_from_cache(&mut self, request: &mut RedisRequest, kind: ValueKind) -> bool {
+        let key = request.key.clone();
+        match self.cache.get_with_refresh(&key) {
+            Some((val, refresh)) => {
+                request.set_result(ProxyResult::from(val));
+                if refresh {
+                    self.refresh(&key, kind);
+                }
+                true
+            }
+            None if self.known_miss(&key) => {
+                request.set_result(ProxyResult::Miss);
+                true
+            }
+            None => match self.cache.get_stale(&key) {
+                Some(val) => {
+                    request.set_result(ProxyResult::Stale(val));
+                    self.refresh(&key, kind);
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+
+    /*
+     * Fetches a key into the cache once the client has its answer: an
+     * entry that was just served stale, one the cache asked to refresh
+     * before it expires, or a collection only part of which was read. The
+     * refresh is led through the SingleFlight under a request of its own,
+     * so while it runs other gets of the key are served stale too rather
+     * than fetching it again, and a write of the key waits for it. If it
+     * fails a stale entry is left alone, to be served until the grace
+     * window runs out.
+     */
+    fn refresh(&mut self, key: &str, kind: ValueKind) {
+        let mut flight = RedisRequest::typed(key.to_string(), kind);
+        if !self.single_flight.join(&flight) {
+            return;
+        }
+        let result = self.fetch(key, kind);
+        self.single_flight.complete(&mut flight, result);
+    }
+
+    //Fetches a key from redis and caches what it holds
+    fn fetch(&mut self, key: &str, kind: ValueKind) -> ProxyResult {
+        let start = Instant::now();
+        let redis_get = self.redis_provider.fetch(key, kind);
+        self.metrics.redis_fetch_duration.observe(start.elapsed());
+        //Only fill cache on successful redis response
+        match redis_get {
+            Ok(Fetched::Value(ref fetched)) => {
+                //Replaces the entry being refreshed, if there is one
+                self.cache.remove(key);
+                self.cache.put(key, fetched.val.clone(), fetched.ttl)
+            }
+            Ok(Fetched::Missing) => {
+                //Drop any stale entry, the key is gone
+                self.cache.remove(key);
+                self.remember_miss(key);
+            }
+            //The key has changed kind since it was cached
+            Ok(Fetched::WrongType(_)) => self.cache.remove(key),
+            Err(_) => self.metrics.redis_errors.inc(),
+        }
+        match redis_get {
+            Ok(Fetched::Value(fetched)) => ProxyResult::from(fetched.val),
+            Ok(Fetched::Missing) => ProxyResult::Miss,
+            Ok(Fetched::WrongType(held)) => ProxyResult::WrongType(held),
+            Err(err) => ProxyResult::from(Err(err)),
+        }
+    }
+
+    /*
+     * Cache hits are served straight away and the other keys are fetched
+     * together in one round trip. Each fetched key is led through the
+     * SingleFlight like a single get, so writes and other fetches of it
+     * are ordered the same way. Keys some other consumer is already
+     * fetching or writing are parked on that flight instead, and are only
+     * waited on once our own fetch has completed, so two overlapping
+     * batches can never end up waiting on each other.
+     *
+     * If redis can't be read, a key with an expired value still in the
+     * cache's grace window is answered with it, and the batch is marked
+     * stale, so an outage fails a batch no sooner than a single get.
+     *
+     * A batch only reads strings. A key holding any other kind of value
+     * comes back as null, the same as MGET answers for it.
+     */
+    fn handle_get_many(&mut self, mut request: RedisRequest) {
+        let keys = match std::mem::replace(&mut request.op, Operation::Get) {
+            Operation::GetMany(keys) => keys,
+            _ => unreachable!("handle_get_many called without keys"),
+        };
+        let mut vals = Vec::with_capacity(keys.len());
+        let mut led = Vec::new();
+        let mut parked = Vec::new();
+        for (i, key) in keys.into_iter().enumerate() {
+            let cached = self.cache.get(&key);
+            if cached.is_none() && !self.known_miss(&key) {
+                let mut flight = RedisRequest::new(key);
+                flight.deadline = request.deadline;
+                if self.single_flight.join(&flight) {
+                    led.push((i, flight));
+                } else {
+                    parked.push((i, flight));
+                }
+            }
+            vals.push(cached.and_then(CacheValue::into_str));
+        }
+
+        let mut failure = None;
+        let mut stale = false;
+        if !led.is_empty() {
+            let led_keys: Vec<&str> = led.iter().map(|(_, flight)| flight.key.as_str()).collect();
+            let start = Instant::now();
+            let redis_get = self.redis_provider.fetch_many(&led_keys);
+            self.metrics.redis_fetch_duration.observe(start.elapsed());
+            match redis_get {
+                Ok(fetched) => {
+                    for ((i, mut flight), fetched) in led.into_iter().zip(fetched) {
+                        let result = match fetched {
+                            Fetched::Value(fetched) => {
+                                self.cache
+                                    .put(&flight.key, fetched.val.clone(), fetched.ttl);
+                                ProxyResult::from(fetched.val)
+                            }
+                            Fetched::Missing => {
+                                //Drop any stale entry, the key is gone
+                                self.cache.remove(&flight.key);
+                                self.remember_miss(&flight.key);
+                                ProxyResult::Miss
+                            }
+                            //The key has changed kind since it was cached
+                            Fetched::WrongType(held) => {
+                                self.cache.remove(&flight.key);
+                                ProxyResult::WrongType(held)
+                            }
+                        };
+                        if let ProxyResult::Hit(ref val) = result {
+                            vals[i] = Some(val.clone());
+                        }
+                        self.single_flight.complete(&mut flight, result);
+                    }
+                }
+                Err(err) => {
+                    self.metrics.redis_errors.inc();
+                    let result = ProxyResult::from(Err(err));
+                    for (i, mut flight) in led {
+                        //Waiters on the key are served stale along with us
+                        match self.cache.get_stale(&flight.key) {
+                            Some(val) => {
+                                vals[i] = val.clone().into_str();
+                                stale = true;
+                                self.single_flight
+                                    .complete(&mut flight, ProxyResult::Stale(val));
+                            }
+                            None => {
+                                self.single_flight.complete(&mut flight, result.clone());
+                                failure = Some(result.clone());
                             }
-                            request.set_result(redis_get);
                         }
                     }
                 }
             }
         }
+
+        for (i, flight) in parked {
+            let key = flight.key.clone();
+            match flight.get_result() {
+                ProxyResult::Hit(val) => vals[i] = Some(val),
+                ProxyResult::Stale(val) => {
+                    vals[i] = val.into_str();
+                    stale = true;
+                }
+                ProxyResult::Miss | ProxyResult::Value(_) | ProxyResult::WrongType(_) => {}
+                result => match self.cache.get_stale(&key) {
+                    Some(val) => {
+                        vals[i] = val.into_str();
+                        stale = true;
+                    }
+                    None => failure = failure.or(Some(result)),
+                },
+            }
+        }
+        //A failure on a key with no stale value to fall back on fails the
+        //whole batch rather than being passed off as a miss
+        request.set_result(match failure {
+            Some(failure) => failure,
+            None if stale => ProxyResult::StaleValues(vals),
+            None => ProxyResult::Values(vals),
+        });
     }
 }
 
 #[cfg(test)]
 mod tests {
     use {
+        crate::clock::FakeClock,
+        crate::lru_cache::ConcurrentCache,
         crate::redis_consumer::*,
-        crate::redis_request::RedisRequest,
-        std::sync::mpsc::{sync_channel, Receiver, SyncSender},
+        crate::sharded_cache::ShardedCache,
+        std::{
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                mpsc::{sync_channel, Receiver, SyncSender},
+                Mutex,
+            },
+            thread,
+        },
     };
 
     struct MockCache;
-    impl Cache for MockCache {
-        fn get(&mut self, key: &String) -> Option<String> {
+    impl Cache<CacheValue> for MockCache {
+        fn get(&mut self, key: &str) -> Option<CacheValue> {
             if key == "cache_hit" {
-                return Some(String::from("hit_cache"));
+                return Some(CacheValue::Str(b"hit_cache".to_vec()));
+            } else if key == "cache_hit_list" {
+                return Some(CacheValue::List(vec![String::from("hit_cache")]));
             }
             None
         }
-        fn put(&mut self, _: &String, _: String) {}
+        fn put(&mut self, _: &str, _: CacheValue, _: Option<Duration>) {}
+        fn remove(&mut self, _: &str) {}
     }
 
+    //Records the ttl of every put so tests can check what reached the cache
+    #[derive(Clone, Default)]
+    struct RecordingCache {
+        put_ttls: Arc<Mutex<Vec<Option<Duration>>>>,
+    }
+    impl Cache<CacheValue> for RecordingCache {
+        fn get(&mut self, _: &str) -> Option<CacheValue> {
+            None
+        }
+        fn put(&mut self, _: &str, _: CacheValue, ttl: Option<Duration>) {
+            self.put_ttls.lock().unwrap().push(ttl);
+        }
+        fn remove(&mut self, _: &str) {}
+    }
+
+    //Like redis, only reads a key as the kind of value it holds
     struct MockRedis;
     impl RedisProvider for MockRedis {
-        fn fetch(&self, key: &String) -> Result<Option<String>, redis::RedisError> {
+        fn fetch(&self, key: &str, kind: ValueKind) -> Result<Fetched, redis::RedisError> {
+            let held = match key {
+                "redis_hit" | "redis_hit_ttl" => ValueKind::Str,
+                "redis_hash" => ValueKind::Hash,
+                _ => kind,
+            };
+            if held != kind {
+                return Ok(Fetched::WrongType(held));
+            }
             if key == "redis_hit" {
-                return Ok(Some(String::from("hit_redis")));
+                return Ok(Fetched::Value(FetchedValue {
+                    val: CacheValue::Str(b"hit_redis".to_vec()),
+                    ttl: None,
+                }));
+            } else if key == "redis_hit_ttl" {
+                return Ok(Fetched::Value(FetchedValue {
+                    val: CacheValue::Str(b"hit_redis".to_vec()),
+                    ttl: Some(Duration::from_millis(2000)),
+                }));
+            } else if key == "redis_hash" {
+                return Ok(Fetched::Value(FetchedValue {
+                    val: CacheValue::Hash(vec![(String::from("field"), String::from("val"))]),
+                    ttl: None,
+                }));
             } else if key == "redis_err" {
                 return Err(redis::RedisError::from((
                     redis::ErrorKind::ResponseError,
                     "err",
                 )));
             }
-            Ok(None)
+            Ok(Fetched::Missing)
+        }
+        fn store(&self, _: &str, _: &[u8], _: Option<Duration>) -> Result<(), redis::RedisError> {
+            Ok(())
+        }
+        fn delete(&self, key: &str) -> Result<bool, redis::RedisError> {
+            Ok(key == "redis_hit")
+        }
+        fn ping(&self) -> Result<(), redis::RedisError> {
+            Ok(())
         }
     }
 
     #[test]
     fn test_shutdown() {
         let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
-        let consumer = RedisConsumer::new(rx, MockCache, MockRedis);
+        let consumer = RedisConsumer::new(Arc::new(Mutex::new(rx)), MockCache, MockRedis);
 
-        tx.send(Message::Shutdown);
+        tx.send(Message::Shutdown).unwrap();
         //expect to exit immediately.
         consumer.consume_requests()
     }
@@ -160,55 +1046,786 @@ mod tests {
     #[test]
     fn test_cache_get() {
         let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
-        let consumer = RedisConsumer::new(rx, MockCache, MockRedis);
+        let consumer = RedisConsumer::new(Arc::new(Mutex::new(rx)), MockCache, MockRedis);
 
         let request = RedisRequest::new(String::from("cache_hit"));
 
-        tx.send(Message::Request(request.clone()));
-        tx.send(Message::Shutdown);
+        tx.send(Message::Request(request.clone())).unwrap();
+        tx.send(Message::Shutdown).unwrap();
         consumer.consume_requests();
         let val = request.get_result();
-        assert_eq!(val, Some("hit_cache".to_string()));
+        assert_eq!(val, ProxyResult::Hit(b"hit_cache".to_vec()));
     }
     #[test]
     fn test_redis_get() {
         let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
-        let consumer = RedisConsumer::new(rx, MockCache, MockRedis);
+        let consumer = RedisConsumer::new(Arc::new(Mutex::new(rx)), MockCache, MockRedis);
 
         let request = RedisRequest::new(String::from("redis_hit"));
 
-        tx.send(Message::Request(request.clone()));
-        tx.send(Message::Shutdown);
+        tx.send(Message::Request(request.clone())).unwrap();
+        tx.send(Message::Shutdown).unwrap();
         consumer.consume_requests();
         let val = request.get_result();
-        assert_eq!(val, Some("hit_redis".to_string()));
+        assert_eq!(val, ProxyResult::Hit(b"hit_redis".to_vec()));
     }
 
     #[test]
     fn test_redis_err() {
         let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
-        let consumer = RedisConsumer::new(rx, MockCache, MockRedis);
+        let consumer = RedisConsumer::new(Arc::new(Mutex::new(rx)), MockCache, MockRedis);
 
         let request = RedisRequest::new(String::from("redis_err"));
 
-        tx.send(Message::Request(request.clone()));
-        tx.send(Message::Shutdown);
+        tx.send(Message::Request(request.clone())).unwrap();
+        tx.send(Message::Shutdown).unwrap();
         consumer.consume_requests();
         let val = request.get_result();
-        assert_eq!(val, Some("err".to_string()));
+        assert_eq!(
+            val,
+            ProxyResult::BackendError(Arc::new(redis::RedisError::from((
+                redis::ErrorKind::ResponseError,
+                "err",
+            ))))
+        );
+    }
+
+    #[test]
+    fn test_redis_metrics() {
+        let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
+        let metrics = Arc::new(ProxyMetrics::default());
+        let consumer = RedisConsumer::new(Arc::new(Mutex::new(rx)), MockCache, MockRedis)
+            .with_metrics(metrics.clone());
+
+        for key in &["redis_hit", "redis_err", "cache_hit"] {
+            metrics.queue_depth.inc();
+            let request = RedisRequest::new(key.to_string());
+            tx.send(Message::Request(request)).unwrap();
+        }
+        tx.send(Message::Shutdown).unwrap();
+        consumer.consume_requests();
+
+        assert_eq!(metrics.queue_depth.get(), 0);
+        assert_eq!(metrics.redis_errors.get(), 1);
+        let out = metrics.render(Default::default(), Default::default());
+        assert!(out.contains("redis_proxy_redis_fetch_duration_seconds_count 2\n"));
     }
 
     #[test]
     fn test_redis_miss() {
         let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
-        let consumer = RedisConsumer::new(rx, MockCache, MockRedis);
+        let consumer = RedisConsumer::new(Arc::new(Mutex::new(rx)), MockCache, MockRedis);
 
         let request = RedisRequest::new(String::from("redis_miss"));
 
-        tx.send(Message::Request(request.clone()));
-        tx.send(Message::Shutdown);
+        tx.send(Message::Request(request.clone())).unwrap();
+        tx.send(Message::Shutdown).unwrap();
         consumer.consume_requests();
         let val = request.get_result();
-        assert_eq!(val, None);
+        assert_eq!(val, ProxyResult::Miss);
+    }
+
+    #[test]
+    fn test_redis_ttl_reaches_cache() {
+        let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
+        let cache = RecordingCache::default();
+        let consumer = RedisConsumer::new(Arc::new(Mutex::new(rx)), cache.clone(), MockRedis);
+
+        let request = RedisRequest::new(String::from("redis_hit_ttl"));
+
+        tx.send(Message::Request(request.clone())).unwrap();
+        tx.send(Message::Shutdown).unwrap();
+        consumer.consume_requests();
+        assert_eq!(
+            request.get_result(),
+            ProxyResult::Hit(b"hit_redis".to_vec())
+        );
+        assert_eq!(
+            *cache.put_ttls.lock().unwrap(),
+            vec![Some(Duration::from_millis(2000))]
+        );
+    }
+
+    #[test]
+    fn test_put_and_delete_update_cache() {
+        let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
+        let cache = Arc::new(ShardedCache::new(4, 100, Duration::from_secs(60)));
+        let consumer = RedisConsumer::new(Arc::new(Mutex::new(rx)), cache, MockRedis);
+
+        let put = RedisRequest::put(String::from("written"), b"val".to_vec(), None);
+        let get = RedisRequest::new(String::from("written"));
+        let delete = RedisRequest::delete(String::from("written"));
+        let get_deleted = RedisRequest::new(String::from("written"));
+        for request in &[&put, &get, &delete, &get_deleted] {
+            tx.send(Message::Request((*request).clone())).unwrap();
+        }
+        tx.send(Message::Shutdown).unwrap();
+        consumer.consume_requests();
+
+        assert_eq!(put.get_result(), ProxyResult::Stored);
+        //MockRedis has no value for this key, so a hit came from the cache
+        assert_eq!(get.get_result(), ProxyResult::Hit(b"val".to_vec()));
+        assert_eq!(delete.get_result(), ProxyResult::Deleted(false));
+        assert_eq!(get_deleted.get_result(), ProxyResult::Miss);
+    }
+
+    #[test]
+    fn test_negative_cache() {
+        let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
+        let negative_cache = Arc::new(ShardedCache::new(1, 10, Duration::from_secs(60)));
+        let consumer = RedisConsumer::new(Arc::new(Mutex::new(rx)), MockCache, MockRedis)
+            .with_negative_cache(negative_cache.clone());
+
+        let requests = [
+            RedisRequest::new(String::from("redis_miss")),
+            RedisRequest::new(String::from("redis_miss")),
+            RedisRequest::get_many(vec![String::from("redis_miss"), String::from("batch_miss")]),
+            RedisRequest::new(String::from("batch_miss")),
+        ];
+        for request in &requests {
+            tx.send(Message::Request(request.clone())).unwrap();
+        }
+        tx.send(Message::Shutdown).unwrap();
+        consumer.consume_requests();
+
+        let results: Vec<_> = requests.iter().map(|r| r.clone().get_result()).collect();
+        assert_eq!(
+            results,
+            vec![
+                ProxyResult::Miss,
+                ProxyResult::Miss,
+                ProxyResult::Values(vec![None, None]),
+                ProxyResult::Miss,
+            ]
+        );
+        //Only the first lookup of each key reached redis
+        let stats = negative_cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (3, 2, 2));
+    }
+
+    #[test]
+    fn test_write_clears_negative_cache() {
+        let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
+        let cache = Arc::new(ShardedCache::new(4, 100, Duration::from_secs(60)));
+        let negative_cache = Arc::new(ShardedCache::new(1, 10, Duration::from_secs(60)));
+        let consumer = RedisConsumer::new(Arc::new(Mutex::new(rx)), cache, MockRedis)
+            .with_negative_cache(negative_cache.clone());
+
+        let miss = RedisRequest::new(String::from("written"));
+        let put = RedisRequest::put(String::from("written"), b"val".to_vec(), None);
+        let get = RedisRequest::new(String::from("written"));
+        let delete = RedisRequest::delete(String::from("written"));
+        for request in &[&miss, &put, &get, &delete] {
+            tx.send(Message::Request((*request).clone())).unwrap();
+        }
+        tx.send(Message::Shutdown).unwrap();
+        consumer.consume_requests();
+
+        assert_eq!(miss.get_result(), ProxyResult::Miss);
+        assert_eq!(get.get_result(), ProxyResult::Hit(b"val".to_vec()));
+        //The delete leaves the key known to be missing
+        assert_eq!(negative_cache.stats().entries, 1);
+        assert!(ConcurrentCache::get(&*negative_cache, "written").is_some());
+    }
+
+    #[test]
+    fn test_stale_while_revalidate() {
+        let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
+        let clock = Arc::new(FakeClock::default());
+        let cache = Arc::new(
+            ShardedCache::new(1, 10, Duration::from_secs(20))
+                .with_clock(clock.clone())
+                .with_stale_grace(Duration::from_secs(60)),
+        );
+        for key in &["redis_hit", "redis_err", "redis_gone"] {
+            ConcurrentCache::put(&*cache, key, CacheValue::Str(b"old".to_vec()), None);
+        }
+        clock.advance(Duration::from_secs(40));
+        let consumer = RedisConsumer::new(Arc::new(Mutex::new(rx)), cache.clone(), MockRedis);
+
+        let requests = [
+            RedisRequest::new(String::from("redis_hit")),
+            RedisRequest::new(String::from("redis_hit")),
+            RedisRequest::new(String::from("redis_err")),
+            RedisRequest::new(String::from("redis_err")),
+            RedisRequest::new(String::from("redis_gone")),
+            RedisRequest::new(String::from("redis_gone")),
+        ];
+        for request in &requests {
+            tx.send(Message::Request(request.clone())).unwrap();
+        }
+        tx.send(Message::Shutdown).unwrap();
+        consumer.consume_requests();
+
+        let stale = ProxyResult::Stale(CacheValue::Str(b"old".to_vec()));
+        let results: Vec<_> = requests.iter().map(|r| r.clone().get_result()).collect();
+        assert_eq!(
+            results,
+            vec![
+                stale.clone(),
+                ProxyResult::Hit(b"hit_redis".to_vec()),
+                //The refresh failed, so the stale value is still served
+                stale.clone(),
+                stale.clone(),
+                stale,
+                ProxyResult::Miss,
+            ]
+        );
+        assert_eq!(cache.stats().stale_hits, 4);
+    }
+
+    //Holds an old value for every key, and asks for every hit to refresh it
+    #[derive(Clone, Default)]
+    struct EarlyRefreshCache {
+        puts: Arc<Mutex<Vec<(String, CacheValue)>>>,
+    }
+    impl Cache<CacheValue> for EarlyRefreshCache {
+        fn get(&mut self, _: &str) -> Option<CacheValue> {
+            Some(CacheValue::Str(b"old".to_vec()))
+        }
+        fn put(&mut self, key: &str, val: CacheValue, _: Option<Duration>) {
+            self.puts.lock().unwrap().push((key.to_string(), val));
+        }
+        fn remove(&mut self, _: &str) {}
+        fn get_with_refresh(&mut self, key: &str) -> Option<(CacheValue, bool)> {
+            self.get(key).map(|val| (val, true))
+        }
+    }
+
+    #[test]
+    fn test_early_refresh() {
+        let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
+        let cache = EarlyRefreshCache::default();
+        let consumer = RedisConsumer::new(Arc::new(Mutex::new(rx)), cache.clone(), MockRedis);
+
+        let request = RedisRequest::new(String::from("redis_hit"));
+        tx.send(Message::Request(request.clone())).unwrap();
+        tx.send(Message::Shutdown).unwrap();
+        consumer.consume_requests();
+
+        //The hit is answered with what was cached, then refreshed
+        assert_eq!(request.get_result(), ProxyResult::Hit(b"old".to_vec()));
+        assert_eq!(
+            *cache.puts.lock().unwrap(),
+            vec![(
+                String::from("redis_hit"),
+                CacheValue::Str(b"hit_redis".to_vec())
+            )]
+        );
+    }
+
+    #[test]
+    fn test_put_not_overwritten_by_inflight_fetch() {
+        let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
+        let work_queue_rx = Arc::new(Mutex::new(rx));
+        let cache = Arc::new(ShardedCache::new(4, 100, Duration::from_secs(60)));
+        let redis = Arc::new(CountingRedis::gated());
+        let single_flight = Arc::new(SingleFlight::default());
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let consumer =
+                    RedisConsumer::new(work_queue_rx.clone(), cache.clone(), redis.clone())
+                        .with_single_flight(single_flight.clone());
+                thread::spawn(move || consumer.consume_requests())
+            })
+            .collect();
+
+        //The fetch reads the old value and is still outstanding when the
+        //put arrives
+        let stale_get = RedisRequest::new(String::from("a"));
+        tx.send(Message::Request(stale_get.clone())).unwrap();
+        wait_until(|| redis.fetches.load(Ordering::SeqCst) == 1);
+        let put = RedisRequest::put(String::from("a"), b"new".to_vec(), None);
+        tx.send(Message::Request(put.clone())).unwrap();
+        redis.open();
+
+        assert_eq!(stale_get.get_result(), ProxyResult::Hit(b"val-a".to_vec()));
+        assert_eq!(put.get_result(), ProxyResult::Stored);
+        let get = RedisRequest::new(String::from("a"));
+        tx.send(Message::Request(get.clone())).unwrap();
+        assert_eq!(get.get_result(), ProxyResult::Hit(b"new".to_vec()));
+
+        for _ in &workers {
+            tx.send(Message::Shutdown).unwrap();
+        }
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(redis.fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_invalidate_drops_cached_entry() {
+        let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
+        let cache = Arc::new(ShardedCache::new(4, 100, Duration::from_secs(60)));
+        let consumer = RedisConsumer::new(Arc::new(Mutex::new(rx)), cache.clone(), MockRedis);
+
+        ConcurrentCache::put(
+            &*cache,
+            "redis_hit",
+            CacheValue::Str(b"stale".to_vec()),
+            None,
+        );
+        let get = RedisRequest::new(String::from("redis_hit"));
+        tx.send(Message::Invalidate(String::from("redis_hit")))
+            .unwrap();
+        tx.send(Message::Request(get.clone())).unwrap();
+        tx.send(Message::Shutdown).unwrap();
+        consumer.consume_requests();
+
+        assert_eq!(get.get_result(), ProxyResult::Hit(b"hit_redis".to_vec()));
+    }
+
+    #[test]
+    fn test_get_many() {
+        let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
+        let consumer = RedisConsumer::new(Arc::new(Mutex::new(rx)), MockCache, MockRedis);
+
+        let keys = ["cache_hit", "redis_hit", "redis_miss"];
+        let batch = RedisRequest::get_many(keys.iter().map(|k| k.to_string()).collect());
+        let failed =
+            RedisRequest::get_many(vec![String::from("redis_hit"), String::from("redis_err")]);
+        tx.send(Message::Request(batch.clone())).unwrap();
+        tx.send(Message::Request(failed.clone())).unwrap();
+        tx.send(Message::Shutdown).unwrap();
+        consumer.consume_requests();
+
+        assert_eq!(
+            batch.get_result(),
+            ProxyResult::Values(vec![
+                Some(b"hit_cache".to_vec()),
+                Some(b"hit_redis".to_vec()),
+                None,
+            ])
+        );
+        assert!(matches!(failed.get_result(), ProxyResult::BackendError(_)));
+    }
+
+    #[test]
+    fn test_get_many_serves_stale() {
+        let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
+        let clock = Arc::new(FakeClock::default());
+        let cache = Arc::new(
+            ShardedCache::new(1, 10, Duration::from_secs(20))
+                .with_clock(clock.clone())
+                .with_stale_grace(Duration::from_secs(60)),
+        );
+        ConcurrentCache::put(&*cache, "redis_err", CacheValue::Str(b"old".to_vec()), None);
+        clock.advance(Duration::from_secs(40));
+        let consumer = RedisConsumer::new(Arc::new(Mutex::new(rx)), cache.clone(), MockRedis);
+
+        //The fetch fails, but the one key fetched has a stale value to
+        //fall back on
+        let batch =
+            RedisRequest::get_many(vec![String::from("redis_err"), String::from("cache_miss")]);
+        ConcurrentCache::put(
+            &*cache,
+            "cache_miss",
+            CacheValue::Str(b"new".to_vec()),
+            None,
+        );
+        let failed =
+            RedisRequest::get_many(vec![String::from("redis_err"), String::from("redis_hit")]);
+        tx.send(Message::Request(batch.clone())).unwrap();
+        tx.send(Message::Request(failed.clone())).unwrap();
+        tx.send(Message::Shutdown).unwrap();
+        consumer.consume_requests();
+
+        assert_eq!(
+            batch.get_result(),
+            ProxyResult::StaleValues(vec![Some(b"old".to_vec()), Some(b"new".to_vec())])
+        );
+        //redis_hit was never cached, so there is nothing to serve for it
+        assert!(matches!(failed.get_result(), ProxyResult::BackendError(_)));
+    }
+
+    #[test]
+    fn test_get_many_drops_stale_entry_of_missing_key() {
+        let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
+        let clock = Arc::new(FakeClock::default());
+        let cache = Arc::new(
+            ShardedCache::new(1, 10, Duration::from_secs(20))
+                .with_clock(clock.clone())
+                .with_stale_grace(Duration::from_secs(60)),
+        );
+        ConcurrentCache::put(
+            &*cache,
+            "redis_gone",
+            CacheValue::Str(b"old".to_vec()),
+            None,
+        );
+        clock.advance(Duration::from_secs(40));
+        let consumer = RedisConsumer::new(Arc::new(Mutex::new(rx)), cache, MockRedis);
+
+        let batch = RedisRequest::get_many(vec![String::from("redis_gone")]);
+        let get = RedisRequest::new(String::from("redis_gone"));
+        tx.send(Message::Request(batch.clone())).unwrap();
+        tx.send(Message::Request(get.clone())).unwrap();
+        tx.send(Message::Shutdown).unwrap();
+        consumer.consume_requests();
+
+        assert_eq!(batch.get_result(), ProxyResult::Values(vec![None]));
+        //Not the deleted key's old value, served stale
+        assert_eq!(get.get_result(), ProxyResult::Miss);
+    }
+
+    #[test]
+    fn test_typed_get() {
+        let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
+        let consumer = RedisConsumer::new(Arc::new(Mutex::new(rx)), MockCache, MockRedis);
+
+        let hash = RedisRequest::typed(String::from("redis_hash"), ValueKind::Hash);
+        let cached_list = RedisRequest::typed(String::from("cache_hit_list"), ValueKind::List);
+        //A plain get of a hash only learns what it holds, for the front
+        //end to refuse
+        let plain = RedisRequest::new(String::from("redis_hash"));
+        let batch = RedisRequest::get_many(vec![
+            String::from("cache_hit_list"),
+            String::from("redis_hash"),
+            String::from("redis_hit"),
+        ]);
+        for request in &[&hash, &cached_list, &plain, &batch] {
+            tx.send(Message::Request((*request).clone())).unwrap();
+        }
+        tx.send(Message::Shutdown).unwrap();
+        consumer.consume_requests();
+
+        let expected_hash = ProxyResult::Value(CacheValue::Hash(vec![(
+            String::from("field"),
+            String::from("val"),
+        )]));
+        assert_eq!(hash.get_result(), expected_hash);
+        assert_eq!(
+            cached_list.get_result(),
+            ProxyResult::Value(CacheValue::List(vec![String::from("hit_cache")]))
+        );
+        assert_eq!(plain.get_result(), ProxyResult::WrongType(ValueKind::Hash));
+        assert_eq!(
+            batch.get_result(),
+            ProxyResult::Values(vec![None, None, Some(b"hit_redis".to_vec())])
+        );
+    }
+
+    #[test]
+    fn test_part_get() {
+        let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
+        let cache = Arc::new(ShardedCache::new(1, 10, Duration::from_secs(60)));
+        let consumer = RedisConsumer::new(Arc::new(Mutex::new(rx)), cache.clone(), MockRedis);
+
+        let field = ValuePart::HashField(String::from("field"));
+        let part = RedisRequest::part(String::from("redis_hash"), field.clone());
+        let cached = RedisRequest::part(String::from("redis_hash"), field.clone());
+        let wrong_type = RedisRequest::part(String::from("redis_hit"), field.clone());
+        let missing = RedisRequest::part(String::from("redis_miss"), field);
+        for request in &[&part, &cached, &wrong_type, &missing] {
+            tx.send(Message::Request((*request).clone())).unwrap();
+        }
+        tx.send(Message::Shutdown).unwrap();
+        consumer.consume_requests();
+
+        let hash = CacheValue::Hash(vec![(String::from("field"), String::from("val"))]);
+        assert_eq!(part.get_result(), ProxyResult::Part(hash.clone()));
+        //The hash is small, so it was then fetched whole and cached
+        assert_eq!(cached.get_result(), ProxyResult::Value(hash));
+        assert_eq!(
+            wrong_type.get_result(),
+            ProxyResult::WrongType(ValueKind::Str)
+        );
+        assert_eq!(missing.get_result(), ProxyResult::Miss);
+        assert_eq!(cache.stats().entries, 1);
+    }
+
+    #[test]
+    fn test_ping_through_consumer() {
+        let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
+        let consumer = RedisConsumer::new(Arc::new(Mutex::new(rx)), MockCache, MockRedis);
+
+        let request = RedisRequest::ping();
+        tx.send(Message::Request(request.clone())).unwrap();
+        tx.send(Message::Shutdown).unwrap();
+        consumer.consume_requests();
+        assert_eq!(request.get_result(), ProxyResult::Pong);
+    }
+
+    #[test]
+    fn test_retry_with_backoff() {
+        let mut attempts = 0;
+        let result = retry_with_backoff(
+            Duration::from_millis(1),
+            Duration::from_millis(4),
+            Duration::from_secs(5),
+            || {
+                attempts += 1;
+                if attempts < 4 {
+                    Err("not yet")
+                } else {
+                    Ok(attempts)
+                }
+            },
+        );
+        assert_eq!(result, Ok(4));
+
+        let start = Instant::now();
+        let result: Result<(), &str> = retry_with_backoff(
+            Duration::from_millis(10),
+            Duration::from_millis(10),
+            Duration::from_millis(50),
+            || Err("down"),
+        );
+        assert_eq!(result, Err("down"));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_pttl_to_ttl() {
+        assert_eq!(pttl_to_ttl(-1), None);
+        assert_eq!(pttl_to_ttl(-2), Some(Duration::from_millis(0)));
+        assert_eq!(pttl_to_ttl(1500), Some(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn test_consumer_pool_shares_queue() {
+        let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
+        let work_queue_rx = Arc::new(Mutex::new(rx));
+        let cache = Arc::new(ShardedCache::new(4, 100, Duration::from_secs(60)));
+        let redis = Arc::new(MockRedis);
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let consumer =
+                    RedisConsumer::new(work_queue_rx.clone(), cache.clone(), redis.clone());
+                thread::spawn(move || consumer.consume_requests())
+            })
+            .collect();
+
+        let requests: Vec<RedisRequest> = (0..100)
+            .map(|i| {
+                let key = if i % 2 == 0 {
+                    "redis_hit"
+                } else {
+                    "redis_miss"
+                };
+                let request = RedisRequest::new(String::from(key));
+                tx.send(Message::Request(request.clone())).unwrap();
+                request
+            })
+            .collect();
+        //One shutdown per consumer
+        for _ in &workers {
+            tx.send(Message::Shutdown).unwrap();
+        }
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        for (i, request) in requests.into_iter().enumerate() {
+            let expected = if i % 2 == 0 {
+                ProxyResult::Hit(b"hit_redis".to_vec())
+            } else {
+                ProxyResult::Miss
+            };
+            assert_eq!(request.get_result(), expected);
+        }
+    }
+
+    /*
+     * Counts how many times it is asked. A gated one holds every fetch
+     * until the test opens it, so fetches stay outstanding for as long as
+     * the test needs rather than for as long as some sleep lasts.
+     */
+    struct CountingRedis {
+        fetches: AtomicUsize,
+        //Fetches wait on this until its sender is dropped
+        gate: Mutex<Receiver<()>>,
+        opener: Mutex<Option<SyncSender<()>>>,
+    }
+    impl Default for CountingRedis {
+        fn default() -> CountingRedis {
+            let redis = CountingRedis::gated();
+            redis.open();
+            redis
+        }
+    }
+    impl CountingRedis {
+        fn gated() -> CountingRedis {
+            let (opener, gate) = sync_channel(0);
+            CountingRedis {
+                fetches: AtomicUsize::new(0),
+                gate: Mutex::new(gate),
+                opener: Mutex::new(Some(opener)),
+            }
+        }
+
+        //Lets every held fetch, and every later one, through
+        fn open(&self) {
+            self.opener.lock().unwrap().take();
+        }
+    }
+    impl RedisProvider for CountingRedis {
+        fn fetch(&self, key: &str, _: ValueKind) -> Result<Fetched, redis::RedisError> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            //Nothing is ever sent, so this returns once the gate is open
+            let _ = self.gate.lock().unwrap().recv();
+            Ok(Fetched::Value(FetchedValue {
+                val: CacheValue::Str(format!("val-{}", key).into_bytes()),
+                ttl: None,
+            }))
+        }
+        fn store(&self, _: &str, _: &[u8], _: Option<Duration>) -> Result<(), redis::RedisError> {
+            Ok(())
+        }
+        fn delete(&self, _: &str) -> Result<bool, redis::RedisError> {
+            Ok(true)
+        }
+        fn ping(&self) -> Result<(), redis::RedisError> {
+            Ok(())
+        }
+    }
+
+    //Spins until done says so, for what a test can only poll for
+    fn wait_until<F: Fn() -> bool>(done: F) {
+        while !done() {
+            thread::yield_now();
+        }
+    }
+
+    fn run_pool(
+        keys: &[&str],
+        redis: Arc<CountingRedis>,
+        parked: &[(&str, usize)],
+    ) -> Vec<ProxyResult> {
+        let requests = keys
+            .iter()
+            .map(|key| RedisRequest::new(key.to_string()))
+            .collect();
+        run_pool_requests(requests, redis, parked)
+    }
+
+    /*
+     * Runs the requests through a pool of consumers sharing a gated redis,
+     * which is only opened once each key has the given number of requests
+     * parked on its fetch
+     */
+    fn run_pool_requests(
+        requests: Vec<RedisRequest>,
+        redis: Arc<CountingRedis>,
+        parked: &[(&str, usize)],
+    ) -> Vec<ProxyResult> {
+        let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(100);
+        let work_queue_rx = Arc::new(Mutex::new(rx));
+        let single_flight = Arc::new(SingleFlight::default());
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let consumer = RedisConsumer::new(work_queue_rx.clone(), MockCache, redis.clone())
+                    .with_single_flight(single_flight.clone());
+                thread::spawn(move || consumer.consume_requests())
+            })
+            .collect();
+
+        for request in &requests {
+            tx.send(Message::Request(request.clone())).unwrap();
+        }
+        for (key, count) in parked {
+            wait_until(|| single_flight.parked(key) == *count);
+        }
+        redis.open();
+        for _ in &workers {
+            tx.send(Message::Shutdown).unwrap();
+        }
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        requests.into_iter().map(|r| r.get_result()).collect()
+    }
+
+    #[test]
+    fn test_reloadable_provider_finishes_inflight() {
+        let provider = Arc::new(ReloadableProvider::new(CountingRedis::gated()));
+        let inflight = {
+            let provider = provider.clone();
+            thread::spawn(move || provider.fetch("foo", ValueKind::Str))
+        };
+        wait_until(|| provider.current().fetches.load(Ordering::SeqCst) == 1);
+
+        let replaced = provider.replace(CountingRedis::default());
+        replaced.open();
+        assert_eq!(
+            inflight.join().unwrap().unwrap(),
+            Fetched::Value(FetchedValue {
+                val: CacheValue::Str(b"val-foo".to_vec()),
+                ttl: None,
+            })
+        );
+
+        provider.fetch("bar", ValueKind::Str).unwrap();
+        assert_eq!(replaced.fetches.load(Ordering::SeqCst), 1);
+        assert_eq!(provider.current().fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_expired_request_skipped() {
+        let redis = Arc::new(CountingRedis::default());
+        let (tx, rx): (SyncSender<Message>, Receiver<Message>) = sync_channel(20);
+        let consumer = RedisConsumer::new(Arc::new(Mutex::new(rx)), MockCache, redis.clone());
+
+        let request =
+            RedisRequest::new(String::from("late")).with_timeout(Duration::from_millis(0));
+
+        tx.send(Message::Request(request.clone())).unwrap();
+        tx.send(Message::Shutdown).unwrap();
+        consumer.consume_requests();
+        assert_eq!(request.get_result(), ProxyResult::Timeout);
+        assert_eq!(redis.fetches.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_concurrent_misses_coalesced() {
+        let redis = Arc::new(CountingRedis::gated());
+        let keys = vec!["cold"; 50];
+
+        let results = run_pool(&keys, redis.clone(), &[("cold", 49)]);
+        assert_eq!(redis.fetches.load(Ordering::SeqCst), 1);
+        assert!(results
+            .iter()
+            .all(|r| *r == ProxyResult::Hit(b"val-cold".to_vec())));
+    }
+
+    #[test]
+    fn test_get_many_coalesced_with_gets() {
+        let redis = Arc::new(CountingRedis::gated());
+        let keys = vec![String::from("a"), String::from("b"), String::from("c")];
+        let requests = vec![
+            RedisRequest::new(String::from("a")),
+            RedisRequest::new(String::from("b")),
+            RedisRequest::get_many(keys),
+        ];
+
+        //Whichever leads a and b, the other is parked on it
+        let results = run_pool_requests(requests, redis.clone(), &[("a", 1), ("b", 1)]);
+        assert_eq!(redis.fetches.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            results[2],
+            ProxyResult::Values(vec![
+                Some(b"val-a".to_vec()),
+                Some(b"val-b".to_vec()),
+                Some(b"val-c".to_vec()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_distinct_keys_not_coalesced() {
+        let redis = Arc::new(CountingRedis::gated());
+        let keys = vec!["a", "b", "a", "c", "b", "a"];
+
+        let results = run_pool(&keys, redis.clone(), &[("a", 2), ("b", 1)]);
+        assert_eq!(redis.fetches.load(Ordering::SeqCst), 3);
+        for (key, result) in keys.iter().zip(results) {
+            assert_eq!(
+                result,
+                ProxyResult::Hit(format!("val-{}", key).into_bytes())
+            );
+        }
     }
 }
@@ -0,0 +1,398 @@
+use {
+    redis::{ConnectionLike, RedisResult},
+    std::{
+        io,
+        ops::{Deref, DerefMut},
+        sync::{Condvar, Mutex},
+        time::{Duration, Instant},
+    },
+};
+
+/*
+ * Idle connections that have sat unused for longer than this are PINGed
+ * before being handed out, so a connection the server (or a firewall)
+ * quietly dropped is caught before a request is sent on it.
+ */
+const DEFAULT_HEALTH_CHECK_AFTER: Duration = Duration::from_secs(1);
+
+//How long get waits for a connection to be returned to a full pool
+const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/*
+ * This trait defines what the pool needs from a connection. It lets the
+ * pool be tested with mock connections rather than a live redis.
+ */
+pub trait PoolableConnection: Send {
+    fn is_healthy(&mut self) -> bool;
+}
+
+impl PoolableConnection for redis::Connection {
+    fn is_healthy(&mut self) -> bool {
+        self.is_open() && self.check_connection()
+    }
+}
+
+struct IdleConnection<C> {
+    con: C,
+    idle_since: Instant,
+}
+
+struct PoolState<C> {
+    //Oldest returned first, so the longest idle are at the bottom
+    idle: Vec<IdleConnection<C>>,
+    //Connections that exist, whether idle or checked out
+    open: usize,
+}
+
+/*
+ * The ConnectionPool keeps up to max_size live connections to redis so
+ * requests don't pay a TCP handshake each. Connections are created lazily
+ * and returned to the pool when the PooledConnection guard is dropped.
+ *
+ * - When every connection is checked out and the pool is at max_size,
+ *   get blocks on a Condvar until one is returned, or fails with a
+ *   timed out IoError after wait_timeout.
+ * - Idle connections older than idle_timeout are closed whenever a
+ *   connection is checked out or returned, rather than reused. The most
+ *   recently returned are reused first, so those left at the bottom of
+ *   the stack are the ones that time out.
+ * - Idle connections older than health_check_after are PINGed first and
+ *   replaced if the PING fails.
+ * - A caller that sees a connection fail can discard it so it is closed
+ *   rather than returned to the pool.
+ */
+pub struct ConnectionPool<C: PoolableConnection> {
+    connect: Box<dyn Fn() -> RedisResult<C> + Send + Sync>,
+    state: Mutex<PoolState<C>>,
+    connection_returned: Condvar,
+    max_size: usize,
+    idle_timeout: Duration,
+    health_check_after: Duration,
+    wait_timeout: Duration,
+}
+
+impl<C: PoolableConnection> ConnectionPool<C> {
+    pub fn new(
+        connect: Box<dyn Fn() -> RedisResult<C> + Send + Sync>,
+        max_size: usize,
+        idle_timeout: Duration,
+    ) -> ConnectionPool<C> {
+        ConnectionPool {
+            connect,
+            state: Mutex::new(PoolState {
+                idle: Vec::with_capacity(max_size),
+                open: 0,
+            }),
+            connection_returned: Condvar::new(),
+            max_size: max_size.max(1),
+            idle_timeout,
+            health_check_after: DEFAULT_HEALTH_CHECK_AFTER,
+            wait_timeout: DEFAULT_WAIT_TIMEOUT,
+        }
+    }
+
+    pub fn with_wait_timeout(mut self, wait_timeout: Duration) -> ConnectionPool<C> {
+        self.wait_timeout = wait_timeout;
+        self
+    }
+
+    /*
+     * Checks out a connection, reusing the most recently returned idle
+     * connection if there is a healthy one, opening a new one if the pool
+     * has room, and otherwise waiting up to wait_timeout for one to be
+     * returned
+     */
+    pub fn get(&self) -> RedisResult<PooledConnection<'_, C>> {
+        let deadline = Instant::now() + self.wait_timeout;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            self.close_timed_out(&mut state);
+            while let Some(idle) = state.idle.pop() {
+                let idle_for = idle.idle_since.elapsed();
+                if idle_for > self.idle_timeout {
+                    state.open -= 1;
+                    continue;
+                }
+                if idle_for <= self.health_check_after {
+                    return Ok(self.guard(idle.con));
+                }
+
+                //Don't hold the pool lock across a round trip to redis
+                drop(state);
+                let mut con = idle.con;
+                if con.is_healthy() {
+                    return Ok(self.guard(con));
+                }
+                state = self.state.lock().unwrap();
+                state.open -= 1;
+            }
+
+            if state.open < self.max_size {
+                state.open += 1;
+                drop(state);
+                return match (self.connect)() {
+                    Ok(con) => Ok(self.guard(con)),
+                    Err(err) => {
+                        self.close_one();
+                        Err(err)
+                    }
+                };
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "timed out waiting for a pooled redis connection",
+                )
+                .into());
+            }
+            state = self
+                .connection_returned
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    //Closes the idle connections at the bottom of the stack that timed out
+    fn close_timed_out(&self, state: &mut PoolState<C>) {
+        let timed_out = state
+            .idle
+            .iter()
+            .take_while(|idle| idle.idle_since.elapsed() > self.idle_timeout)
+            .count();
+        if timed_out > 0 {
+            state.idle.drain(..timed_out);
+            state.open -= timed_out;
+        }
+    }
+
+    fn guard(&self, con: C) -> PooledConnection<'_, C> {
+        PooledConnection {
+            pool: self,
+            con: Some(con),
+        }
+    }
+
+    fn return_connection(&self, con: C) {
+        let mut state = self.state.lock().unwrap();
+        self.close_timed_out(&mut state);
+        state.idle.push(IdleConnection {
+            con,
+            idle_since: Instant::now(),
+        });
+        self.connection_returned.notify_one();
+    }
+
+    fn close_one(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.open -= 1;
+        self.connection_returned.notify_one();
+    }
+}
+
+/*
+ * A checked out connection. Derefs to the underlying connection and hands
+ * it back to the pool when dropped.
+ */
+pub struct PooledConnection<'a, C: PoolableConnection> {
+    pool: &'a ConnectionPool<C>,
+    con: Option<C>,
+}
+
+impl<'a, C: PoolableConnection> PooledConnection<'a, C> {
+    /*
+     * Closes the connection instead of returning it to the pool. Used when
+     * the connection has failed and its state can no longer be trusted.
+     */
+    pub fn discard(mut self) {
+        self.con.take();
+        self.pool.close_one();
+    }
+}
+
+impl<'a, C: PoolableConnection> Deref for PooledConnection<'a, C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        self.con
+            .as_ref()
+            .expect("pooled connection used after discard")
+    }
+}
+
+impl<'a, C: PoolableConnection> DerefMut for PooledConnection<'a, C> {
+    fn deref_mut(&mut self) -> &mut C {
+        self.con
+            .as_mut()
+            .expect("pooled connection used after discard")
+    }
+}
+
+impl<'a, C: PoolableConnection> Drop for PooledConnection<'a, C> {
+    fn drop(&mut self) {
+        if let Some(con) = self.con.take() {
+            self.pool.return_connection(con);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::redis_pool::*,
+        std::{
+            sync::{
+                atomic::{AtomicBool, AtomicUsize, Ordering},
+                Arc,
+            },
+            thread,
+        },
+    };
+
+    struct MockConnection {
+        id: usize,
+        healthy: Arc<AtomicBool>,
+    }
+
+    impl PoolableConnection for MockConnection {
+        fn is_healthy(&mut self) -> bool {
+            self.healthy.load(Ordering::SeqCst)
+        }
+    }
+
+    //Returns a pool of mock connections and a count of connections opened
+    fn mock_pool(
+        max_size: usize,
+        idle_timeout: Duration,
+        healthy: Arc<AtomicBool>,
+    ) -> (ConnectionPool<MockConnection>, Arc<AtomicUsize>) {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let counter = opened.clone();
+        let connect = Box::new(move || {
+            Ok(MockConnection {
+                id: counter.fetch_add(1, Ordering::SeqCst),
+                healthy: healthy.clone(),
+            })
+        });
+        (ConnectionPool::new(connect, max_size, idle_timeout), opened)
+    }
+
+    #[test]
+    fn test_connection_reused() {
+        let healthy = Arc::new(AtomicBool::new(true));
+        let (pool, opened) = mock_pool(2, Duration::from_secs(60), healthy);
+        let first_id = pool.get().unwrap().id;
+        let second_id = pool.get().unwrap().id;
+        assert_eq!(first_id, second_id);
+        assert_eq!(opened.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_pool_bounded() {
+        let healthy = Arc::new(AtomicBool::new(true));
+        let (pool, opened) = mock_pool(1, Duration::from_secs(60), healthy);
+        let pool = Arc::new(pool);
+
+        let held = pool.get().unwrap();
+        let waiter_done = Arc::new(AtomicBool::new(false));
+        let waiter = {
+            let pool = pool.clone();
+            let waiter_done = waiter_done.clone();
+            thread::spawn(move || {
+                let id = pool.get().unwrap().id;
+                waiter_done.store(true, Ordering::SeqCst);
+                id
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiter_done.load(Ordering::SeqCst));
+        let held_id = held.id;
+        drop(held);
+
+        assert_eq!(waiter.join().unwrap(), held_id);
+        assert_eq!(opened.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_wait_times_out() {
+        let healthy = Arc::new(AtomicBool::new(true));
+        let (pool, _) = mock_pool(1, Duration::from_secs(60), healthy);
+        let pool = pool.with_wait_timeout(Duration::from_millis(20));
+
+        let _held = pool.get().unwrap();
+        let start = Instant::now();
+        let err = pool.get().err().unwrap();
+        assert!(err.is_timeout());
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_idle_timeout_closes_connection() {
+        let healthy = Arc::new(AtomicBool::new(true));
+        let (pool, opened) = mock_pool(2, Duration::from_millis(10), healthy);
+        drop(pool.get().unwrap());
+        thread::sleep(Duration::from_millis(20));
+        drop(pool.get().unwrap());
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.state.lock().unwrap().open, 1);
+    }
+
+    #[test]
+    fn test_idle_timeout_closes_bottom_of_stack() {
+        let healthy = Arc::new(AtomicBool::new(true));
+        let (pool, _) = mock_pool(3, Duration::from_millis(30), healthy);
+        let first = pool.get().unwrap();
+        let second = pool.get().unwrap();
+        drop(first);
+        thread::sleep(Duration::from_millis(40));
+        //Returning the second closes the first, which get would never
+        //reach while the second keeps being reused from the top
+        drop(second);
+        let state = pool.state.lock().unwrap();
+        assert_eq!((state.idle.len(), state.open), (1, 1));
+    }
+
+    #[test]
+    fn test_unhealthy_connection_replaced() {
+        let healthy = Arc::new(AtomicBool::new(true));
+        let (mut pool, opened) = mock_pool(2, Duration::from_secs(60), healthy.clone());
+        pool.health_check_after = Duration::from_millis(0);
+
+        let first_id = pool.get().unwrap().id;
+        healthy.store(false, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(5));
+        let second_id = pool.get().unwrap().id;
+        assert_ne!(first_id, second_id);
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.state.lock().unwrap().open, 1);
+    }
+
+    #[test]
+    fn test_discard_closes_connection() {
+        let healthy = Arc::new(AtomicBool::new(true));
+        let (pool, opened) = mock_pool(1, Duration::from_secs(60), healthy);
+        pool.get().unwrap().discard();
+        pool.get().unwrap();
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_connect_error_frees_slot() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = attempts.clone();
+        let connect = Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Err(redis::RedisError::from((
+                redis::ErrorKind::IoError,
+                "connection refused",
+            )))
+        });
+        let pool: ConnectionPool<MockConnection> =
+            ConnectionPool::new(connect, 1, Duration::from_secs(60));
+        assert!(pool.get().is_err());
+        assert!(pool.get().is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,4 +1,10 @@
-use std::sync::{Arc, Condvar, Mutex};
+use {
+    crate::cache_value::{CacheValue, ValueKind, ValuePart},
+    std::{
+        sync::{mpsc::Receiver, Arc, Condvar, Mutex},
+        time::{Duration, Instant},
+    },
+};
 
 /*
  * The RedisRequest is responsible for passing the requested key
@@ -12,32 +18,185 @@ use std::sync::{Arc, Condvar, Mutex};
  *     across threads (allows the producer and the consumer to read the value)
  *   - Mutex: guards the result value, preventing simultaneous access and
  *     any data race
- *   - Optional and Result:
- *      - Outer Option signals to the condvar that the consumer has completed
- *      - Result indicates if Redis returned an error
- *      - inner Option signals if redis contained a value for the key
+ *   - Option: signals to the condvar that the consumer has completed. The
+ *     producer takes the ProxyResult out of the Option when it wakes, so
+ *     the value is moved out of the Mutex rather than copied
  *   - Condvar: allows us to synchronize the completion of the request. The
  *     consumer can notify the producer when the optional result has been set
+ *
+ * A request can carry a deadline. The producer stops waiting once it has
+ * passed, so a stuck or crashed consumer can't hang the front ends, and a
+ * consumer that pulls the request off the queue after its deadline drops
+ * it rather than doing work nobody is waiting for.
+ */
+
+/*
+ * What the request asks the consumer to do with its key
  */
+#[derive(Clone)]
+pub enum Operation {
+    Get,
+    //Get a key expected to hold the given kind of value. A key that turns
+    //out to hold another kind is still fetched, as whatever it holds.
+    GetTyped(ValueKind),
+    //Get one part of a collection. Answered from the whole value if that
+    //is cached, and otherwise by reading only the part from redis
+    GetPart(ValuePart),
+    Put { val: Vec<u8>, ttl: Option<Duration> },
+    Delete,
+    //PING redis. Used to check that the consumers and redis are both answering
+    Ping,
+    //Get every one of these keys. The request's own key is unused.
+    GetMany(Vec<String>),
+}
+
+/*
+ * The outcome of a request, as seen by the front ends. Misses and failures
+ * are kept apart from values so that each front end can report them in its
+ * own protocol.
+ */
+#[derive(Debug, Clone)]
+pub enum ProxyResult {
+    //The value of a string key. Redis strings are binary safe, so this is
+    //bytes rather than text
+    Hit(Vec<u8>),
+    //A hit on a key holding a hash, list, set or sorted set
+    Value(CacheValue),
+    //Just the part of a collection a GetPart asked for, read from redis as
+    //ValuePart::select would pick it out of the whole value
+    Part(CacheValue),
+    //The key holds this other kind of value, which was not read
+    WrongType(ValueKind),
+    //An expired value served from the cache's grace window while it is
+    //refreshed in the background
+    Stale(CacheValue),
+    Miss,
+    //A Put was written to redis
+    Stored,
+    //A Delete was applied. Holds whether the key existed
+    Deleted(bool),
+    //Redis answered a Ping
+    Pong,
+    //The values of a GetMany, in the order the keys were given. None for a miss
+    Values(Vec<Option<Vec<u8>>>),
+    //Values of a GetMany of which some were served stale from the cache's
+    //grace window, as redis couldn't be read for them
+    StaleValues(Vec<Option<Vec<u8>>>),
+    //Redis could not be reached or replied with an error. The error is held
+    //behind an Arc as RedisError can't be cloned and a coalesced fetch
+    //completes several requests with the same result.
+    BackendError(Arc<redis::RedisError>),
+    //The request passed its deadline, or redis did not answer in time
+    Timeout,
+    //The consumers have shut down and the request was never served
+    Shutdown,
+}
+
+impl From<Result<Option<Vec<u8>>, redis::RedisError>> for ProxyResult {
+    fn from(result: Result<Option<Vec<u8>>, redis::RedisError>) -> ProxyResult {
+        match result {
+            Ok(Some(val)) => ProxyResult::Hit(val),
+            Ok(None) => ProxyResult::Miss,
+            Err(err) if err.is_timeout() => ProxyResult::Timeout,
+            Err(err) => ProxyResult::BackendError(Arc::new(err)),
+        }
+    }
+}
+
+//Strings are a Hit, any other kind of value is passed on whole
+impl From<CacheValue> for ProxyResult {
+    fn from(val: CacheValue) -> ProxyResult {
+        match val {
+            CacheValue::Str(val) => ProxyResult::Hit(val),
+            val => ProxyResult::Value(val),
+        }
+    }
+}
+
+//RedisError has no PartialEq, so backend errors compare by kind and message
+impl PartialEq for ProxyResult {
+    fn eq(&self, other: &ProxyResult) -> bool {
+        match (self, other) {
+            (ProxyResult::Hit(a), ProxyResult::Hit(b)) => a == b,
+            (ProxyResult::Value(a), ProxyResult::Value(b)) => a == b,
+            (ProxyResult::Part(a), ProxyResult::Part(b)) => a == b,
+            (ProxyResult::WrongType(a), ProxyResult::WrongType(b)) => a == b,
+            (ProxyResult::Stale(a), ProxyResult::Stale(b)) => a == b,
+            (ProxyResult::Deleted(a), ProxyResult::Deleted(b)) => a == b,
+            (ProxyResult::Values(a), ProxyResult::Values(b)) => a == b,
+            (ProxyResult::StaleValues(a), ProxyResult::StaleValues(b)) => a == b,
+            (ProxyResult::BackendError(a), ProxyResult::BackendError(b)) => {
+                a.kind() == b.kind() && a.to_string() == b.to_string()
+            }
+            (ProxyResult::Miss, ProxyResult::Miss)
+            | (ProxyResult::Stored, ProxyResult::Stored)
+            | (ProxyResult::Pong, ProxyResult::Pong)
+            | (ProxyResult::Timeout, ProxyResult::Timeout)
+            | (ProxyResult::Shutdown, ProxyResult::Shutdown) => true,
+            _ => false,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct RedisRequest {
     pub key: String,
-    pub result: Arc<(
-        Mutex<Option<Result<Option<String>, redis::RedisError>>>,
-        Condvar,
-    )>,
+    pub op: Operation,
+    pub result: Arc<(Mutex<Option<ProxyResult>>, Condvar)>,
+    pub deadline: Option<Instant>,
 }
 
 impl RedisRequest {
     pub fn new(key: String) -> RedisRequest {
+        RedisRequest::with_op(key, Operation::Get)
+    }
+
+    pub fn typed(key: String, kind: ValueKind) -> RedisRequest {
+        RedisRequest::with_op(key, Operation::GetTyped(kind))
+    }
+
+    pub fn part(key: String, part: ValuePart) -> RedisRequest {
+        RedisRequest::with_op(key, Operation::GetPart(part))
+    }
+
+    pub fn put(key: String, val: Vec<u8>, ttl: Option<Duration>) -> RedisRequest {
+        RedisRequest::with_op(key, Operation::Put { val, ttl })
+    }
+
+    pub fn delete(key: String) -> RedisRequest {
+        RedisRequest::with_op(key, Operation::Delete)
+    }
+
+    pub fn ping() -> RedisRequest {
+        RedisRequest::with_op(String::new(), Operation::Ping)
+    }
+
+    pub fn get_many(keys: Vec<String>) -> RedisRequest {
+        RedisRequest::with_op(String::new(), Operation::GetMany(keys))
+    }
+
+    fn with_op(key: String, op: Operation) -> RedisRequest {
         RedisRequest {
-            key: key,
+            key,
+            op,
             result: Arc::new((Mutex::new(None), Condvar::new())),
+            deadline: None,
         }
     }
 
-    pub fn set_result(&mut self, res: Result<Option<String>, redis::RedisError>) {
+    pub fn with_timeout(mut self, timeout: Duration) -> RedisRequest {
+        self.deadline = Some(Instant::now() + timeout);
+        self
+    }
+
+    pub fn expired(&self) -> bool {
+        match self.deadline {
+            Some(deadline) => Instant::now() >= deadline,
+            None => false,
+        }
+    }
+
+    pub fn set_result(&mut self, res: ProxyResult) {
         let (locked_result, cvar) = &*self.result;
         let mut result_guard = locked_result.lock().unwrap();
         *result_guard = Some(res);
@@ -45,30 +204,104 @@ impl RedisRequest {
     }
 
     /*
-     * Consumes the redis request. Blocks until a result is
-     * ready. Returns the result.
+     * Consumes the redis request. Blocks until a result is ready or
+     * the deadline passes. Returns the result, or ProxyResult::Timeout
+     * if the deadline passed first.
      */
-    pub fn get_result(self) -> Option<String> {
+    pub fn get_result(self) -> ProxyResult {
         let (locked_result, cvar) = &*self.result;
         let mut result_guard = locked_result.lock().unwrap();
-        while let None = *result_guard {
-            result_guard = cvar.wait(result_guard).unwrap();
-        }
-
-        //Deref mutex guard, then get ref to mutex data
-        match &(*result_guard) {
-            Some(result) => match result {
-                Ok(r) => r.clone(),
-                Err(e) => Some(e.to_string()),
-            },
-            None => panic!("Woke up from condvar.wait with no result"),
+        loop {
+            if let Some(result) = result_guard.take() {
+                return result;
+            }
+            result_guard = match self.deadline {
+                None => cvar.wait(result_guard).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return ProxyResult::Timeout;
+                    }
+                    //wait_timeout can wake spuriously, so loop round and
+                    //check the result and the deadline again
+                    cvar.wait_timeout(result_guard, deadline - now).unwrap().0
+                }
+            };
         }
     }
 }
 
 pub enum Message {
     Request(RedisRequest),
+    //The key was changed in redis by someone else, drop any cached copy
+    Invalidate(String),
     Shutdown,
 }
 
-//todo add unit tests
+/*
+ * Rust's mpsc channel only supports a single receiver. Wrapping the receiver
+ * in a Mutex lets a pool of consumers share one work queue: whichever
+ * consumer is idle takes the lock, pulls the next message and releases the
+ * lock before processing it.
+ *
+ * Each consumer exits on the first Shutdown it receives, so shutting down a
+ * pool takes one Shutdown message per consumer.
+ */
+pub type SharedReceiver = Arc<Mutex<Receiver<Message>>>;
+
+#[cfg(test)]
+mod tests {
+    use {crate::redis_request::*, std::thread};
+
+    #[test]
+    fn test_result_handed_to_waiting_producer() {
+        let request = RedisRequest::new(String::from("foo"));
+        let mut consumer_side = request.clone();
+        let consumer = thread::spawn(move || {
+            consumer_side.set_result(ProxyResult::Hit(b"bar".to_vec()));
+        });
+        assert_eq!(request.get_result(), ProxyResult::Hit(b"bar".to_vec()));
+        consumer.join().unwrap();
+    }
+
+    #[test]
+    fn test_deadline_bounds_wait() {
+        let request =
+            RedisRequest::new(String::from("foo")).with_timeout(Duration::from_millis(50));
+        let _consumer_side = request.clone();
+        let start = Instant::now();
+        assert_eq!(request.get_result(), ProxyResult::Timeout);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_result_beats_deadline() {
+        let request = RedisRequest::new(String::from("foo")).with_timeout(Duration::from_secs(5));
+        let mut consumer_side = request.clone();
+        assert!(!consumer_side.expired());
+        consumer_side.set_result(ProxyResult::Miss);
+        assert_eq!(request.get_result(), ProxyResult::Miss);
+    }
+
+    #[test]
+    fn test_from_redis_result() {
+        assert_eq!(
+            ProxyResult::from(Ok(Some(b"bar".to_vec()))),
+            ProxyResult::Hit(b"bar".to_vec())
+        );
+        assert_eq!(ProxyResult::from(Ok(None)), ProxyResult::Miss);
+
+        let timeout = std::io::Error::new(std::io::ErrorKind::TimedOut, "slow");
+        assert_eq!(
+            ProxyResult::from(Err(redis::RedisError::from(timeout))),
+            ProxyResult::Timeout
+        );
+
+        let response_error = || redis::RedisError::from((redis::ErrorKind::ResponseError, "err"));
+        assert_eq!(
+            ProxyResult::from(Err(response_error())),
+            ProxyResult::BackendError(Arc::new(response_error()))
+        );
+    }
+}
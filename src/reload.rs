@@ -0,0 +1,597 @@
+use {
+    crate::cache_value::CacheValue,
+    crate::config::{ConfigError, ProxyConfig, Startup},
+    crate::redis_consumer::{RedisProvider, ReloadableProvider},
+    crate::sharded_cache::ShardedCache,
+    std::{
+        collections::HashMap,
+        fmt,
+        sync::{Arc, Mutex},
+        thread,
+    },
+};
+
+/*
+ * The Reloader applies a changed configuration without restarting the
+ * proxy, so the cache survives. It loads every config layer again and
+ * applies the options that can change live:
+ *   - cache_size       resizes the cache, evicting down if it shrank
+ *   - cache_expr_sec   caps the lifetime of new and already cached entries
+ *   - cache_grace_sec  changes how long expired entries may be served stale
+ *   - cache_expr_jitter_percent, cache_early_refresh_percent
+ *                      change how the expiry of entries is spread out
+ *   - cache_max_bytes, cache_max_entry_percent
+ *                      change the budget of a cache bounded by bytes.
+ *                      Switching between bounding it by entries and by
+ *                      bytes needs a restart.
+ *   - negative_cache_size, negative_cache_expr_sec
+ *                      do the same for the negative cache, as long as it
+ *                      stays on. Turning it on or off needs a restart.
+ *   - redis_addr, redis_pool_size, redis_pool_idle_sec
+ *                      connects a new provider and swaps it in. Requests
+ *                      already running finish against the old one.
+ * Every other option only takes effect on restart, and is reported as
+ * such. A reload applies completely or not at all: an invalid config or
+ * an unreachable redis leaves the proxy as it was.
+ *
+ * A reload is triggered by SIGHUP or by POST /admin/reload.
+ */
+
+//Connects a provider for the redis settings in the config
+pub type Connect<T> = Box<dyn Fn(&ProxyConfig) -> redis::RedisResult<T> + Send + Sync>;
+
+pub struct Reloader<T: RedisProvider> {
+    //The command line and environment the proxy was started with, so a
+    //reload sees the same layers as startup did
+    args: Vec<String>,
+    env: HashMap<String, String>,
+    //The settings in effect. Options that need a restart keep their
+    //startup value here, so they are reported by every reload until then.
+    config: Mutex<ProxyConfig>,
+    cache: Arc<ShardedCache<CacheValue>>,
+    negative_cache: Option<Arc<ShardedCache<()>>>,
+    redis_provider: Arc<ReloadableProvider<T>>,
+    connect: Connect<T>,
+}
+
+//The options a reload changed, and those it could not
+#[derive(Debug, Default, PartialEq)]
+pub struct ReloadReport {
+    pub applied: Vec<&'static str>,
+    pub restart_required: Vec<&'static str>,
+}
+
+impl ReloadReport {
+    pub fn to_json(&self) -> String {
+        let list = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| format!("\"{}\"", name))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        format!(
+            "{{\"status\":\"reloaded\",\"applied\":[{}],\"restart_required\":[{}]}}",
+            list(&self.applied),
+            list(&self.restart_required)
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum ReloadError {
+    Config(ConfigError),
+    Redis(redis::RedisError),
+}
+
+impl fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReloadError::Config(err) => write!(f, "invalid configuration {}", err),
+            ReloadError::Redis(err) => write!(f, "could not reach the new redis {}", err),
+        }
+    }
+}
+
+impl<T: RedisProvider> Reloader<T> {
+    pub fn new(
+        args: Vec<String>,
+        env: HashMap<String, String>,
+        config: ProxyConfig,
+        cache: Arc<ShardedCache<CacheValue>>,
+        redis_provider: Arc<ReloadableProvider<T>>,
+        connect: Connect<T>,
+    ) -> Reloader<T> {
+        Reloader {
+            args,
+            env,
+            config: Mutex::new(config),
+            cache,
+            negative_cache: None,
+            redis_provider,
+            connect,
+        }
+    }
+
+    pub fn with_negative_cache(mut self, negative_cache: Arc<ShardedCache<()>>) -> Reloader<T> {
+        self.negative_cache = Some(negative_cache);
+        self
+    }
+
+    pub fn reload(&self) -> Result<ReloadReport, ReloadError> {
+        let new = match ProxyConfig::load(&self.args, &self.env).map_err(ReloadError::Config)? {
+            Startup::Run(config) | Startup::PrintConfig(config) => config,
+            Startup::Help => unreachable!("the proxy never runs with --help"),
+        };
+        //Held throughout so a SIGHUP and an admin request can't interleave
+        let mut current = self.config.lock().unwrap();
+        let mut report = ReloadReport::default();
+
+        //Connecting is the only step that can fail, so it goes first
+        let redis_changes = [
+            ("redis_addr", new.redis_addr != current.redis_addr),
+            (
+                "redis_pool_size",
+                new.redis_pool_size != current.redis_pool_size,
+            ),
+            (
+                "redis_pool_idle_sec",
+                new.redis_pool_idle != current.redis_pool_idle,
+            ),
+        ];
+        if redis_changes.iter().any(|(_, changed)| *changed) {
+            let provider = (self.connect)(&new).map_err(ReloadError::Redis)?;
+            self.redis_provider.replace(provider);
+            current.redis_addr = new.redis_addr.clone();
+            current.redis_pool_size = new.redis_pool_size;
+            current.redis_pool_idle = new.redis_pool_idle;
+            for (name, changed) in redis_changes.iter() {
+                if *changed {
+                    report.applied.push(name);
+                }
+            }
+            //The keyspace subscriber stays connected to the old redis
+            if current.keyspace_invalidation {
+                report.restart_required.push("keyspace_invalidation");
+            }
+        }
+
+        if new.cache_size != current.cache_size {
+            self.cache.resize(new.cache_size);
+            current.cache_size = new.cache_size;
+            report.applied.push("cache_size");
+        }
+        if new.cache_expr != current.cache_expr {
+            self.cache.set_max_lifetime(new.cache_expr);
+            current.cache_expr = new.cache_expr;
+            report.applied.push("cache_expr_sec");
+        }
+        if new.cache_grace != current.cache_grace {
+            self.cache.set_stale_grace(new.cache_grace);
+            current.cache_grace = new.cache_grace;
+            report.applied.push("cache_grace_sec");
+        }
+        let early_expiry_changes = [
+            (
+                "cache_expr_jitter_percent",
+                new.cache_expr_jitter_percent != current.cache_expr_jitter_percent,
+            ),
+            (
+                "cache_early_refresh_percent",
+                new.cache_early_refresh_percent != current.cache_early_refresh_percent,
+            ),
+        ];
+        if early_expiry_changes.iter().any(|(_, changed)| *changed) {
+            self.cache.set_early_expiry(
+                new.cache_expr_jitter_percent,
+                new.cache_early_refresh_percent,
+            );
+            current.cache_expr_jitter_percent = new.cache_expr_jitter_percent;
+            current.cache_early_refresh_percent = new.cache_early_refresh_percent;
+            for (name, changed) in early_expiry_changes.iter() {
+                if *changed {
+                    report.applied.push(name);
+                }
+            }
+        }
+        let bytes_changes = [
+            (
+                "cache_max_bytes",
+                new.cache_max_bytes != current.cache_max_bytes,
+            ),
+            (
+                "cache_max_entry_percent",
+                new.cache_max_entry_percent != current.cache_max_entry_percent,
+            ),
+        ];
+        if current.cache_max_bytes > 0
+            && new.cache_max_bytes > 0
+            && bytes_changes.iter().any(|(_, changed)| *changed)
+        {
+            self.cache
+                .set_max_bytes(new.cache_max_bytes, new.cache_max_entry_bytes());
+            current.cache_max_bytes = new.cache_max_bytes;
+            current.cache_max_entry_percent = new.cache_max_entry_percent;
+            for (name, changed) in bytes_changes.iter() {
+                if *changed {
+                    report.applied.push(name);
+                }
+            }
+        }
+        if let Some(ref negative_cache) = self.negative_cache {
+            if new.negative_cache_size > 0 && new.negative_cache_size != current.negative_cache_size
+            {
+                negative_cache.resize(new.negative_cache_size);
+                current.negative_cache_size = new.negative_cache_size;
+                report.applied.push("negative_cache_size");
+            }
+            if new.negative_cache_expr != current.negative_cache_expr {
+                negative_cache.set_max_lifetime(new.negative_cache_expr);
+                current.negative_cache_expr = new.negative_cache_expr;
+                report.applied.push("negative_cache_expr_sec");
+            }
+        }
+
+        let restart_changes = [
+            ("address", new.address != current.address),
+            ("port", new.port != current.port),
+            ("workers", new.workers != current.workers),
+            ("cache_policy", new.cache_policy != current.cache_policy),
+            (
+                "cache_max_bytes",
+                new.cache_max_bytes != current.cache_max_bytes,
+            ),
+            (
+                "cache_max_entry_percent",
+                new.cache_max_entry_percent != current.cache_max_entry_percent,
+            ),
+            (
+                "negative_cache_size",
+                new.negative_cache_size != current.negative_cache_size,
+            ),
+            (
+                "negative_cache_expr_sec",
+                new.negative_cache_expr != current.negative_cache_expr,
+            ),
+            ("resp_port", new.resp_port != current.resp_port),
+            (
+                "request_timeout_ms",
+                new.request_timeout != current.request_timeout,
+            ),
+            (
+                "legacy_empty_miss",
+                new.legacy_empty_miss != current.legacy_empty_miss,
+            ),
+            (
+                "keyspace_invalidation",
+                new.keyspace_invalidation != current.keyspace_invalidation,
+            ),
+            (
+                "redis_startup_timeout_sec",
+                new.redis_startup_timeout != current.redis_startup_timeout,
+            ),
+        ];
+        for (name, changed) in restart_changes.iter() {
+            if *changed && !report.restart_required.contains(name) {
+                report.restart_required.push(name);
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl<T: RedisProvider + Send + Sync + 'static> Reloader<T> {
+    /*
+     * Reloads on every SIGHUP the process receives. The signal is taken
+     * with sigwait on this thread rather than in a signal handler, so the
+     * reload runs as ordinary code. block_sighup must have been called
+     * before any other thread was started.
+     */
+    pub fn spawn_sighup_handler(self: Arc<Self>) -> thread::JoinHandle<()> {
+        thread::spawn(move || loop {
+            let set = sighup_set();
+            let mut signal = 0;
+            //Safe as set is a valid, initialized signal set
+            if unsafe { libc::sigwait(&set, &mut signal) } != 0 {
+                eprintln!("waiting for SIGHUP failed, config reload on SIGHUP disabled");
+                return;
+            }
+            println!("SIGHUP received, reloading config");
+            match self.reload() {
+                Ok(report) => println!("config reloaded {}", report.to_json()),
+                Err(err) => eprintln!("config reload failed {}", err),
+            }
+        })
+    }
+}
+
+/*
+ * Blocks SIGHUP in the calling thread, so that it is only ever received
+ * by sigwait. Threads inherit the signal mask of the thread that spawned
+ * them, so this is called first thing in main.
+ */
+pub fn block_sighup() {
+    let set = sighup_set();
+    //Safe as set is a valid, initialized signal set
+    unsafe {
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+    }
+}
+
+fn sighup_set() -> libc::sigset_t {
+    //Safe as sigemptyset initializes the set before it is used
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::cache_value::ValueKind,
+        crate::lru_cache::ConcurrentCache,
+        crate::redis_consumer::{Fetched, FetchedValue},
+        crate::reload::*,
+        std::{env, fs, process, time::Duration},
+    };
+
+    //Answers every fetch with the address it was connected to
+    struct AddrRedis(String);
+    impl RedisProvider for AddrRedis {
+        fn fetch(&self, _: &str, _: ValueKind) -> Result<Fetched, redis::RedisError> {
+            Ok(Fetched::Value(FetchedValue {
+                val: CacheValue::Str(self.0.clone().into_bytes()),
+                ttl: None,
+            }))
+        }
+        fn store(&self, _: &str, _: &[u8], _: Option<Duration>) -> Result<(), redis::RedisError> {
+            Ok(())
+        }
+        fn delete(&self, _: &str) -> Result<bool, redis::RedisError> {
+            Ok(true)
+        }
+        fn ping(&self) -> Result<(), redis::RedisError> {
+            Ok(())
+        }
+    }
+
+    fn connect(config: &ProxyConfig) -> redis::RedisResult<AddrRedis> {
+        if config.redis_addr.contains("down") {
+            return Err(redis::RedisError::from((
+                redis::ErrorKind::IoError,
+                "connection refused",
+            )));
+        }
+        Ok(AddrRedis(config.redis_addr.clone()))
+    }
+
+    struct Fixture {
+        path: String,
+        reloader: Reloader<AddrRedis>,
+        cache: Arc<ShardedCache<CacheValue>>,
+        negative_cache: Option<Arc<ShardedCache<()>>>,
+        provider: Arc<ReloadableProvider<AddrRedis>>,
+    }
+
+    impl Fixture {
+        fn new(name: &str, contents: &str) -> Fixture {
+            let path = env::temp_dir()
+                .join(format!(
+                    "redis_proxy_reload_{}_{}.toml",
+                    name,
+                    process::id()
+                ))
+                .to_str()
+                .unwrap()
+                .to_string();
+            fs::write(&path, contents).unwrap();
+            let args = vec![
+                String::from("redis_proxy"),
+                String::from("--config"),
+                path.clone(),
+            ];
+            let config = match ProxyConfig::load(&args, &HashMap::new()).unwrap() {
+                Startup::Run(config) => config,
+                startup => panic!("unexpected {:?}", startup),
+            };
+            let mut cache = ShardedCache::new(1, config.cache_size, config.cache_expr);
+            if config.cache_max_bytes > 0 {
+                cache =
+                    cache.with_max_bytes(config.cache_max_bytes, config.cache_max_entry_bytes());
+            }
+            let cache = Arc::new(cache);
+            let provider = Arc::new(ReloadableProvider::new(connect(&config).unwrap()));
+            let negative_cache = if config.negative_cache_size > 0 {
+                Some(Arc::new(ShardedCache::new(
+                    1,
+                    config.negative_cache_size,
+                    config.negative_cache_expr,
+                )))
+            } else {
+                None
+            };
+            let mut reloader = Reloader::new(
+                args,
+                HashMap::new(),
+                config,
+                cache.clone(),
+                provider.clone(),
+                Box::new(connect),
+            );
+            if let Some(ref negative_cache) = negative_cache {
+                reloader = reloader.with_negative_cache(negative_cache.clone());
+            }
+            Fixture {
+                path,
+                reloader,
+                cache,
+                negative_cache,
+                provider,
+            }
+        }
+
+        fn rewrite(&self, contents: &str) {
+            fs::write(&self.path, contents).unwrap();
+        }
+
+        fn redis_addr(&self) -> String {
+            match self.provider.fetch("any", ValueKind::Str).unwrap() {
+                Fetched::Value(fetched) => {
+                    String::from_utf8(fetched.val.into_str().unwrap()).unwrap()
+                }
+                fetched => panic!("unexpected {:?}", fetched),
+            }
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    #[test]
+    fn test_reload_applies_live_settings() {
+        let fixture = Fixture::new(
+            "live",
+            "cache_size = 4\nredis_addr = \"redis://one/\"\nworkers = 2\n",
+        );
+        for key in &["a", "b", "c", "d"] {
+            fixture
+                .cache
+                .put(key, CacheValue::Str(key.as_bytes().to_vec()), None);
+        }
+
+        fixture.rewrite(
+            "cache_size = 2\ncache_grace_sec = 30\ncache_early_refresh_percent = 10\n\
+             redis_addr = \"redis://two/\"\nworkers = 8\n",
+        );
+        let report = fixture.reloader.reload().unwrap();
+        assert_eq!(
+            report,
+            ReloadReport {
+                applied: vec![
+                    "redis_addr",
+                    "cache_size",
+                    "cache_grace_sec",
+                    "cache_early_refresh_percent"
+                ],
+                restart_required: vec!["workers"],
+            }
+        );
+        assert_eq!(fixture.cache.stats().entries, 2);
+        assert_eq!(fixture.redis_addr(), "redis://two/");
+
+        //Nothing left to apply, but workers still waits on a restart
+        let report = fixture.reloader.reload().unwrap();
+        assert!(report.applied.is_empty());
+        assert_eq!(report.restart_required, vec!["workers"]);
+        assert_eq!(
+            report.to_json(),
+            r#"{"status":"reloaded","applied":[],"restart_required":["workers"]}"#
+        );
+    }
+
+    #[test]
+    fn test_reload_cache_max_bytes() {
+        let fixture = Fixture::new("bytes", "cache_max_bytes = \"64K\"\n");
+        for i in 0..20 {
+            fixture
+                .cache
+                .put(&format!("key{}", i), CacheValue::Str(vec![0; 100]), None);
+        }
+        assert_eq!(fixture.cache.stats().entries, 20);
+
+        fixture.rewrite("cache_max_bytes = \"1K\"\ncache_max_entry_percent = 50\n");
+        let report = fixture.reloader.reload().unwrap();
+        assert_eq!(
+            report.applied,
+            vec!["cache_max_bytes", "cache_max_entry_percent"]
+        );
+        let stats = fixture.cache.stats();
+        assert!(stats.bytes <= 1024 && stats.entries < 20);
+
+        //Going back to bounding it by entries waits on a restart
+        fixture.rewrite("cache_max_entry_percent = 50\n");
+        let report = fixture.reloader.reload().unwrap();
+        assert!(report.applied.is_empty());
+        assert_eq!(report.restart_required, vec!["cache_max_bytes"]);
+    }
+
+    #[test]
+    fn test_reload_negative_cache() {
+        let fixture = Fixture::new(
+            "negative",
+            "negative_cache_size = 3
+",
+        );
+        let negative_cache = fixture.negative_cache.clone().unwrap();
+        for key in &["a", "b", "c"] {
+            negative_cache.put(key, (), None);
+        }
+
+        fixture.rewrite(
+            "negative_cache_size = 1
+negative_cache_expr_sec = 5
+",
+        );
+        let report = fixture.reloader.reload().unwrap();
+        assert_eq!(
+            report.applied,
+            vec!["negative_cache_size", "negative_cache_expr_sec"]
+        );
+        assert_eq!(negative_cache.stats().entries, 1);
+
+        //Turning it off waits on a restart
+        fixture.rewrite(
+            "negative_cache_size = 0
+negative_cache_expr_sec = 5
+",
+        );
+        let report = fixture.reloader.reload().unwrap();
+        assert!(report.applied.is_empty());
+        assert_eq!(report.restart_required, vec!["negative_cache_size"]);
+
+        //As does turning it on
+        let fixture = Fixture::new("negative_off", "");
+        fixture.rewrite(
+            "negative_cache_size = 10
+negative_cache_expr_sec = 5
+",
+        );
+        let report = fixture.reloader.reload().unwrap();
+        assert!(report.applied.is_empty());
+        assert_eq!(
+            report.restart_required,
+            vec!["negative_cache_size", "negative_cache_expr_sec"]
+        );
+    }
+
+    #[test]
+    fn test_failed_reload_changes_nothing() {
+        let fixture = Fixture::new("failed", "cache_size = 4\nredis_addr = \"redis://one/\"\n");
+
+        fixture.rewrite("cache_size = \"none\"\n");
+        match fixture.reloader.reload() {
+            Err(ReloadError::Config(err)) => assert_eq!(err.message, "invalid value \"none\""),
+            result => panic!("unexpected {:?}", result),
+        }
+
+        fixture.rewrite("cache_size = 2\nredis_addr = \"redis://down/\"\n");
+        assert!(matches!(
+            fixture.reloader.reload(),
+            Err(ReloadError::Redis(_))
+        ));
+        assert_eq!(fixture.redis_addr(), "redis://one/");
+
+        //The cache size was not applied either, so it is still a change
+        fixture.rewrite("cache_size = 2\nredis_addr = \"redis://one/\"\n");
+        assert_eq!(
+            fixture.reloader.reload().unwrap().applied,
+            vec!["cache_size"]
+        );
+    }
+}
@@ -0,0 +1,339 @@
+use std::io::{self, BufRead, Read};
+
+/*
+ * RESP (the REdis Serialization Protocol) is the wire format redis clients
+ * speak. This module only implements the subset a proxy needs: reading
+ * client commands and writing replies.
+ *
+ * Clients send commands as an array of bulk strings, or as a single line
+ * of space separated words ("inline commands", used by telnet and
+ * redis-cli in some modes). Replies are encoded for whichever protocol
+ * version the client negotiated, 2 by default or 3 after HELLO 3.
+ */
+
+//Upper bounds that stop a misbehaving client making us allocate unbounded memory
+const MAX_ARGS: usize = 1024 * 1024;
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+//Longest inline command or length header, the same as redis allows
+const MAX_LINE_LEN: usize = 64 * 1024;
+//The most argument slots reserved up front, however many the header claims
+const MAX_ARGS_PREALLOC: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RespValue {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<RespValue>),
+    Map(Vec<(RespValue, RespValue)>),
+}
+
+#[derive(Debug)]
+pub enum RespError {
+    Io(io::Error),
+    //The client sent something that isn't valid RESP. The connection
+    //can't be resynchronized so it should be closed after replying.
+    Protocol(String),
+}
+
+impl From<io::Error> for RespError {
+    fn from(err: io::Error) -> RespError {
+        RespError::Io(err)
+    }
+}
+
+impl RespValue {
+    pub fn ok() -> RespValue {
+        RespValue::Simple(String::from("OK"))
+    }
+
+    pub fn error(msg: &str) -> RespValue {
+        RespValue::Error(msg.to_string())
+    }
+
+    pub fn encode(&self, protocol: u8, out: &mut Vec<u8>) {
+        match self {
+            RespValue::Simple(s) => write_line(out, b'+', s.as_bytes()),
+            RespValue::Error(e) => write_line(out, b'-', e.as_bytes()),
+            RespValue::Integer(i) => write_line(out, b':', i.to_string().as_bytes()),
+            RespValue::Bulk(b) => {
+                write_line(out, b'$', b.len().to_string().as_bytes());
+                out.extend_from_slice(b);
+                out.extend_from_slice(b"\r\n");
+            }
+            RespValue::Null if protocol >= 3 => out.extend_from_slice(b"_\r\n"),
+            RespValue::Null => out.extend_from_slice(b"$-1\r\n"),
+            RespValue::Array(items) => {
+                write_line(out, b'*', items.len().to_string().as_bytes());
+                for item in items {
+                    item.encode(protocol, out);
+                }
+            }
+            //RESP2 has no map type, so maps are flattened into an array of
+            //alternating keys and values, which is what redis itself does
+            RespValue::Map(pairs) => {
+                let (prefix, len) = match protocol {
+                    3 => (b'%', pairs.len()),
+                    _ => (b'*', pairs.len() * 2),
+                };
+                write_line(out, prefix, len.to_string().as_bytes());
+                for (key, val) in pairs {
+                    key.encode(protocol, out);
+                    val.encode(protocol, out);
+                }
+            }
+        }
+    }
+}
+
+fn write_line(out: &mut Vec<u8>, prefix: u8, body: &[u8]) {
+    out.push(prefix);
+    out.extend_from_slice(body);
+    out.extend_from_slice(b"\r\n");
+}
+
+/*
+ * Reads one command from the client. Returns Ok(None) when the client has
+ * closed the connection cleanly between commands.
+ */
+pub fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>, RespError> {
+    loop {
+        let line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if line.first() == Some(&b'*') {
+            let count = parse_len(&line[1..])?;
+            if count > MAX_ARGS {
+                return Err(RespError::Protocol(String::from(
+                    "invalid multibulk length",
+                )));
+            }
+            let mut args = Vec::with_capacity(count.min(MAX_ARGS_PREALLOC));
+            for _ in 0..count {
+                args.push(read_bulk(reader)?);
+            }
+            if args.is_empty() {
+                continue;
+            }
+            return Ok(Some(args));
+        }
+
+        let args: Vec<Vec<u8>> = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_vec())
+            .collect();
+        //Blank lines are ignored, as redis does
+        if !args.is_empty() {
+            return Ok(Some(args));
+        }
+    }
+}
+
+fn read_bulk<R: BufRead>(reader: &mut R) -> Result<Vec<u8>, RespError> {
+    let line = read_line(reader)?.ok_or_else(unexpected_eof)?;
+    if line.first() != Some(&b'$') {
+        return Err(RespError::Protocol(format!(
+            "expected '$', got '{}'",
+            String::from_utf8_lossy(&line[..line.len().min(1)])
+        )));
+    }
+    let len = parse_len(&line[1..])?;
+    if len > MAX_BULK_LEN {
+        return Err(RespError::Protocol(String::from("invalid bulk length")));
+    }
+    //Grows as the payload arrives rather than trusting the claimed length
+    let mut bulk = Vec::new();
+    reader
+        .by_ref()
+        .take(len as u64 + 2)
+        .read_to_end(&mut bulk)?;
+    if bulk.len() < len + 2 {
+        return Err(unexpected_eof());
+    }
+    if &bulk[len..] != b"\r\n" {
+        return Err(RespError::Protocol(String::from(
+            "bulk string not terminated by CRLF",
+        )));
+    }
+    bulk.truncate(len);
+    Ok(bulk)
+}
+
+/*
+ * Reads a line and strips its line ending. Ok(None) on a clean EOF. A line
+ * longer than MAX_LINE_LEN is a protocol error.
+ */
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>, RespError> {
+    let mut line = Vec::new();
+    let limit = MAX_LINE_LEN as u64 + 2;
+    if reader.by_ref().take(limit).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') && line.len() as u64 == limit {
+        return Err(RespError::Protocol(String::from("too big inline request")));
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(unexpected_eof());
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8]) -> Result<usize, RespError> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse::<usize>().ok())
+        .ok_or_else(|| RespError::Protocol(String::from("invalid length")))
+}
+
+fn unexpected_eof() -> RespError {
+    RespError::Io(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "client closed connection mid command",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use {crate::resp::*, std::io::Cursor};
+
+    fn read_all(input: &[u8]) -> Vec<Vec<Vec<u8>>> {
+        let mut reader = Cursor::new(input.to_vec());
+        let mut commands = Vec::new();
+        while let Some(command) = read_command(&mut reader).unwrap() {
+            commands.push(command);
+        }
+        commands
+    }
+
+    fn encoded(value: RespValue, protocol: u8) -> Vec<u8> {
+        let mut out = Vec::new();
+        value.encode(protocol, &mut out);
+        out
+    }
+
+    #[test]
+    fn test_read_multibulk() {
+        let commands = read_all(b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n*1\r\n$4\r\nPING\r\n");
+        assert_eq!(
+            commands,
+            vec![
+                vec![b"GET".to_vec(), b"foo".to_vec()],
+                vec![b"PING".to_vec()]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_binary_bulk() {
+        let commands = read_all(b"*2\r\n$3\r\nGET\r\n$4\r\na\r\nb\r\n");
+        assert_eq!(commands, vec![vec![b"GET".to_vec(), b"a\r\nb".to_vec()]]);
+    }
+
+    #[test]
+    fn test_read_inline() {
+        let commands = read_all(b"GET  foo\r\n\r\nPING\n");
+        assert_eq!(
+            commands,
+            vec![
+                vec![b"GET".to_vec(), b"foo".to_vec()],
+                vec![b"PING".to_vec()]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_protocol_errors() {
+        let mut bad_len = Cursor::new(b"*x\r\n".to_vec());
+        assert!(matches!(
+            read_command(&mut bad_len),
+            Err(RespError::Protocol(_))
+        ));
+
+        let mut bad_bulk = Cursor::new(b"*1\r\n:3\r\n".to_vec());
+        assert!(matches!(
+            read_command(&mut bad_bulk),
+            Err(RespError::Protocol(_))
+        ));
+
+        let mut truncated = Cursor::new(b"*1\r\n$10\r\nabc".to_vec());
+        assert!(matches!(
+            read_command(&mut truncated),
+            Err(RespError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_size_limits() {
+        //A huge claimed length is only read as far as the data goes
+        let mut huge_bulk = Cursor::new(b"*1\r\n$536870912\r\nabc".to_vec());
+        assert!(matches!(
+            read_command(&mut huge_bulk),
+            Err(RespError::Io(_))
+        ));
+
+        let mut long_line = "GET ".repeat(MAX_LINE_LEN).into_bytes();
+        long_line.extend_from_slice(b"\r\n");
+        assert!(matches!(
+            read_command(&mut Cursor::new(long_line)),
+            Err(RespError::Protocol(_))
+        ));
+
+        let mut longest_line = "x".repeat(MAX_LINE_LEN).into_bytes();
+        longest_line.extend_from_slice(b"\r\n");
+        assert_eq!(read_all(&longest_line).len(), 1);
+    }
+
+    #[test]
+    fn test_encode_resp2() {
+        assert_eq!(encoded(RespValue::ok(), 2), b"+OK\r\n".to_vec());
+        assert_eq!(
+            encoded(RespValue::error("ERR bad"), 2),
+            b"-ERR bad\r\n".to_vec()
+        );
+        assert_eq!(encoded(RespValue::Integer(-3), 2), b":-3\r\n".to_vec());
+        assert_eq!(
+            encoded(RespValue::Bulk(b"bar".to_vec()), 2),
+            b"$3\r\nbar\r\n".to_vec()
+        );
+        assert_eq!(encoded(RespValue::Null, 2), b"$-1\r\n".to_vec());
+        assert_eq!(
+            encoded(
+                RespValue::Array(vec![RespValue::Bulk(b"a".to_vec()), RespValue::Null]),
+                2
+            ),
+            b"*2\r\n$1\r\na\r\n$-1\r\n".to_vec()
+        );
+        assert_eq!(
+            encoded(
+                RespValue::Map(vec![(
+                    RespValue::Simple(String::from("k")),
+                    RespValue::Integer(1)
+                )]),
+                2
+            ),
+            b"*2\r\n+k\r\n:1\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_encode_resp3() {
+        assert_eq!(encoded(RespValue::Null, 3), b"_\r\n".to_vec());
+        assert_eq!(
+            encoded(
+                RespValue::Map(vec![(
+                    RespValue::Simple(String::from("k")),
+                    RespValue::Integer(1)
+                )]),
+                3
+            ),
+            b"%1\r\n+k\r\n:1\r\n".to_vec()
+        );
+    }
+}
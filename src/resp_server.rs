@@ -0,0 +1,544 @@
+use {
+    crate::redis_request::ProxyResult,
+    crate::resp::{read_command, RespError, RespValue},
+    crate::RedisProducer,
+    std::{
+        io::{BufReader, BufWriter, Write},
+        net::{TcpListener, TcpStream},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    },
+};
+
+/*
+ * The RespServer is a second front end alongside the HTTP API that speaks
+ * the redis protocol, so existing redis clients can be pointed at the proxy
+ * unchanged. Reads are turned into RedisRequests and sent through the same
+ * RedisProducer as the HTTP handler, so they share the consumer pool and
+ * cache.
+ *
+ * The proxy is read only, so only read commands and the connection
+ * housekeeping commands clients send on connect are supported. Everything
+ * else gets an -ERR reply.
+ *
+ * Like the rest of the proxy this uses plain blocking IO with a thread per
+ * client connection.
+ */
+
+const PROXY_NAME: &str = "redis_proxy";
+
+//Connections over this many are refused, so clients can't exhaust threads
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+pub struct RespServer {
+    listener: TcpListener,
+    producer: RedisProducer,
+    max_connections: usize,
+}
+
+/*
+ * Per connection state. The protocol starts at RESP2 and can be switched
+ * to RESP3 with HELLO 3.
+ */
+struct Session {
+    protocol: u8,
+}
+
+enum Reply {
+    Send(RespValue),
+    SendAndClose(RespValue),
+}
+
+impl RespServer {
+    pub fn bind(addr: &str, producer: RedisProducer) -> std::io::Result<RespServer> {
+        Ok(RespServer {
+            listener: TcpListener::bind(addr)?,
+            producer,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+        })
+    }
+
+    pub fn with_max_connections(mut self, max_connections: usize) -> RespServer {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /*
+     * Accepts connections on a background thread for as long as the
+     * process runs. Once max_connections are open, further clients are
+     * told so and closed, as redis does past maxclients.
+     */
+    pub fn spawn(self) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let open = Arc::new(AtomicUsize::new(0));
+            for stream in self.listener.incoming() {
+                match stream {
+                    Ok(stream) if open.load(Ordering::SeqCst) >= self.max_connections => {
+                        refuse_connection(stream)
+                    }
+                    Ok(stream) => {
+                        let producer = self.producer.clone();
+                        let open = OpenConnection::new(open.clone());
+                        thread::spawn(move || {
+                            serve_connection(stream, producer);
+                            drop(open);
+                        });
+                    }
+                    Err(err) => eprintln!("RESP listener failed to accept connection {}", err),
+                }
+            }
+        })
+    }
+}
+
+//Counts a connection as open for as long as it is held
+struct OpenConnection(Arc<AtomicUsize>);
+
+impl OpenConnection {
+    fn new(open: Arc<AtomicUsize>) -> OpenConnection {
+        open.fetch_add(1, Ordering::SeqCst);
+        OpenConnection(open)
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn refuse_connection(mut stream: TcpStream) {
+    let mut out = Vec::new();
+    RespValue::error("ERR max number of clients reached").encode(2, &mut out);
+    //The client is being dropped either way
+    let _ = stream.write_all(&out);
+}
+
+fn serve_connection(stream: TcpStream, producer: RedisProducer) {
+    let write_half = match stream.try_clone() {
+        Ok(write_half) => write_half,
+        Err(err) => {
+            eprintln!("RESP listener failed to set up connection {}", err);
+            return;
+        }
+    };
+    let mut reader = BufReader::new(stream);
+    let mut writer = BufWriter::new(write_half);
+    let mut session = Session { protocol: 2 };
+    let mut out = Vec::new();
+
+    loop {
+        let (reply, close) = match read_command(&mut reader) {
+            Ok(Some(args)) => match execute(&args, &mut session, &producer) {
+                Reply::Send(reply) => (reply, false),
+                Reply::SendAndClose(reply) => (reply, true),
+            },
+            Ok(None) | Err(RespError::Io(_)) => return,
+            Err(RespError::Protocol(msg)) => (
+                RespValue::Error(format!("ERR Protocol error: {}", msg)),
+                true,
+            ),
+        };
+
+        out.clear();
+        reply.encode(session.protocol, &mut out);
+        if writer.write_all(&out).and_then(|_| writer.flush()).is_err() || close {
+            return;
+        }
+    }
+}
+
+fn execute(args: &[Vec<u8>], session: &mut Session, producer: &RedisProducer) -> Reply {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+    let args = &args[1..];
+    let reply = match name.as_str() {
+        "PING" => match args {
+            [] => RespValue::Simple(String::from("PONG")),
+            [msg] => RespValue::Bulk(msg.clone()),
+            _ => wrong_arity("ping"),
+        },
+        "ECHO" => match args {
+            [msg] => RespValue::Bulk(msg.clone()),
+            _ => wrong_arity("echo"),
+        },
+        "QUIT" => return Reply::SendAndClose(RespValue::ok()),
+        "HELLO" => hello(args, session),
+        "SELECT" => RespValue::error("ERR SELECT is not supported by the proxy"),
+        "CLIENT" => client(args),
+        "COMMAND" => RespValue::Array(Vec::new()),
+        "GET" => match args {
+            [_] => match parse_keys(args) {
+                Ok(keys) => get(&keys[0], producer),
+                Err(err) => err,
+            },
+            _ => wrong_arity("get"),
+        },
+        "MGET" if !args.is_empty() => match parse_keys(args) {
+            Ok(keys) => {
+                let replies: Vec<_> = fetch_all(keys, producer)
+                    .into_iter()
+                    .map(mget_reply)
+                    .collect();
+                //A failure on any key fails the whole command rather than
+                //being passed off as a value
+                match replies.iter().find(|r| matches!(r, RespValue::Error(_))) {
+                    Some(err) => err.clone(),
+                    None => RespValue::Array(replies),
+                }
+            }
+            Err(err) => err,
+        },
+        "MGET" => wrong_arity("mget"),
+        "EXISTS" if !args.is_empty() => match parse_keys(args) {
+            Ok(keys) => {
+                let mut found = 0;
+                for result in fetch_all(keys, producer) {
+                    match result {
+                        ProxyResult::Hit(_)
+                        | ProxyResult::Value(_)
+                        | ProxyResult::Stale(_)
+                        | ProxyResult::WrongType(_) => found += 1,
+                        ProxyResult::Miss => {}
+                        failure => return Reply::Send(to_reply(failure)),
+                    }
+                }
+                RespValue::Integer(found)
+            }
+            Err(err) => err,
+        },
+        "EXISTS" => wrong_arity("exists"),
+        _ => unknown_command(&name, args),
+    };
+    Reply::Send(reply)
+}
+
+fn get(key: &str, producer: &RedisProducer) -> RespValue {
+    to_reply(producer.produce_requests(key.to_string()).get_result())
+}
+
+//MGET answers nil for a key holding a hash, list or set, where GET fails
+fn mget_reply(result: ProxyResult) -> RespValue {
+    match result {
+        ProxyResult::Value(_) | ProxyResult::Part(_) | ProxyResult::WrongType(_) => RespValue::Null,
+        ProxyResult::Stale(val) => mget_reply(ProxyResult::from(val)),
+        result => to_reply(result),
+    }
+}
+
+fn to_reply(result: ProxyResult) -> RespValue {
+    match result {
+        ProxyResult::Hit(val) => RespValue::Bulk(val),
+        //GET on a hash, list or set, which redis refuses the same way
+        ProxyResult::Value(_) | ProxyResult::Part(_) | ProxyResult::WrongType(_) => {
+            RespValue::error("WRONGTYPE Operation against a key holding the wrong kind of value")
+        }
+        //RESP has nowhere to mark a reply as stale
+        ProxyResult::Stale(val) => to_reply(ProxyResult::from(val)),
+        ProxyResult::Miss => RespValue::Null,
+        ProxyResult::Stored => RespValue::ok(),
+        ProxyResult::Pong => RespValue::Simple(String::from("PONG")),
+        ProxyResult::Values(vals) | ProxyResult::StaleValues(vals) => RespValue::Array(
+            vals.into_iter()
+                .map(|val| to_reply(ProxyResult::from(Ok(val))))
+                .collect(),
+        ),
+        ProxyResult::Deleted(existed) => RespValue::Integer(existed as i64),
+        ProxyResult::BackendError(err) => RespValue::Error(format!("ERR backend error: {}", err)),
+        ProxyResult::Timeout => RespValue::error("ERR request timed out"),
+        ProxyResult::Shutdown => RespValue::error("ERR proxy is shutting down"),
+    }
+}
+
+/*
+ * Sends every key to the consumers before waiting on any of them, so the
+ * keys are looked up in parallel across the consumer pool
+ */
+fn fetch_all(keys: Vec<String>, producer: &RedisProducer) -> Vec<ProxyResult> {
+    let requests: Vec<_> = keys
+        .into_iter()
+        .map(|key| producer.produce_requests(key))
+        .collect();
+    requests.into_iter().map(|r| r.get_result()).collect()
+}
+
+/*
+ * HELLO [protover [AUTH username password] [SETNAME clientname]]
+ * Only the protocol version is honoured. The proxy has no auth of its own.
+ */
+fn hello(args: &[Vec<u8>], session: &mut Session) -> RespValue {
+    if let Some(version) = args.first() {
+        match String::from_utf8_lossy(version).parse::<u8>() {
+            Ok(version @ 2) | Ok(version @ 3) => session.protocol = version,
+            Ok(_) => {
+                return RespValue::error("NOPROTO unsupported protocol version");
+            }
+            Err(_) => {
+                return RespValue::error("ERR Protocol version is not an integer or out of range");
+            }
+        }
+    }
+    let field = |name: &str| RespValue::Bulk(name.as_bytes().to_vec());
+    RespValue::Map(vec![
+        (field("server"), field(PROXY_NAME)),
+        (field("version"), field(env!("CARGO_PKG_VERSION"))),
+        (field("proto"), RespValue::Integer(session.protocol as i64)),
+        (field("mode"), field("standalone")),
+        (field("role"), field("master")),
+        (field("modules"), RespValue::Array(Vec::new())),
+    ])
+}
+
+/*
+ * Client libraries commonly name their connection or report their version
+ * on connect. There is nothing for the proxy to do with either, but failing
+ * them would break those clients.
+ */
+fn client(args: &[Vec<u8>]) -> RespValue {
+    let subcommand = args
+        .first()
+        .map(|s| String::from_utf8_lossy(s).to_ascii_uppercase());
+    match subcommand.as_deref() {
+        Some("SETNAME") | Some("SETINFO") => RespValue::ok(),
+        Some(other) => RespValue::Error(format!(
+            "ERR CLIENT {} is not supported by the proxy",
+            other
+        )),
+        None => wrong_arity("client"),
+    }
+}
+
+//Keys are strings throughout the proxy, so binary keys are rejected
+fn parse_keys(args: &[Vec<u8>]) -> Result<Vec<String>, RespValue> {
+    args.iter()
+        .map(|key| {
+            String::from_utf8(key.clone())
+                .map_err(|_| RespValue::error("ERR the proxy only supports UTF-8 keys"))
+        })
+        .collect()
+}
+
+fn wrong_arity(command: &str) -> RespValue {
+    RespValue::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        command
+    ))
+}
+
+fn unknown_command(name: &str, args: &[Vec<u8>]) -> RespValue {
+    let args: Vec<String> = args
+        .iter()
+        .take(10)
+        .map(|arg| format!("'{}'", String::from_utf8_lossy(arg)))
+        .collect();
+    RespValue::Error(format!(
+        "ERR unknown command '{}', with args beginning with: {}",
+        name.to_ascii_lowercase(),
+        args.join(" ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::cache_value::ValueKind,
+        crate::redis_request::Message,
+        crate::resp_server::*,
+        std::{
+            io::BufRead,
+            sync::mpsc::{sync_channel, Receiver},
+            time::Duration,
+        },
+    };
+
+    /*
+     * Stands in for the consumer pool. Keys starting with "hit" have the
+     * value "val-<key>", keys starting with "hash" hold a hash, keys
+     * starting with "slow" time out and every other key is missing.
+     */
+    fn fake_consumer(rx: Receiver<Message>) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            for msg in rx {
+                match msg {
+                    Message::Shutdown => return,
+                    Message::Invalidate(_) => {}
+                    Message::Request(mut request) => {
+                        let result = if request.key.starts_with("hit") {
+                            ProxyResult::Hit(format!("val-{}", request.key).into_bytes())
+                        } else if request.key.starts_with("hash") {
+                            ProxyResult::WrongType(ValueKind::Hash)
+                        } else if request.key.starts_with("slow") {
+                            ProxyResult::Timeout
+                        } else {
+                            ProxyResult::Miss
+                        };
+                        request.set_result(result);
+                    }
+                }
+            }
+        })
+    }
+
+    fn run(commands: &[&[&str]]) -> (Vec<RespValue>, u8) {
+        let (tx, rx) = sync_channel(20);
+        let consumer = fake_consumer(rx);
+        let producer = RedisProducer::new(tx.clone(), Duration::from_secs(5));
+        let mut session = Session { protocol: 2 };
+
+        let replies = commands
+            .iter()
+            .map(|command| {
+                let args: Vec<Vec<u8>> =
+                    command.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+                match execute(&args, &mut session, &producer) {
+                    Reply::Send(reply) | Reply::SendAndClose(reply) => reply,
+                }
+            })
+            .collect();
+
+        tx.send(Message::Shutdown).unwrap();
+        consumer.join().unwrap();
+        (replies, session.protocol)
+    }
+
+    fn bulk(val: &str) -> RespValue {
+        RespValue::Bulk(val.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_get() {
+        let (replies, _) = run(&[&["GET", "hit1"], &["get", "miss"]]);
+        assert_eq!(replies, vec![bulk("val-hit1"), RespValue::Null]);
+    }
+
+    #[test]
+    fn test_mget_and_exists() {
+        let (replies, _) = run(&[
+            &["MGET", "hit1", "miss", "hit2"],
+            &["EXISTS", "hit1", "miss"],
+        ]);
+        assert_eq!(
+            replies,
+            vec![
+                RespValue::Array(vec![bulk("val-hit1"), RespValue::Null, bulk("val-hit2")]),
+                RespValue::Integer(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_other_kinds_of_value() {
+        let (replies, _) = run(&[&["GET", "hash1"], &["MGET", "hit1", "hash1"]]);
+        assert_eq!(
+            replies,
+            vec![
+                RespValue::error(
+                    "WRONGTYPE Operation against a key holding the wrong kind of value"
+                ),
+                //As redis answers MGET, rather than failing it
+                RespValue::Array(vec![bulk("val-hit1"), RespValue::Null]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_failures_are_errors() {
+        let (replies, _) = run(&[
+            &["GET", "slow"],
+            &["MGET", "hit1", "slow"],
+            &["EXISTS", "slow"],
+        ]);
+        let timeout = RespValue::error("ERR request timed out");
+        assert_eq!(replies, vec![timeout.clone(), timeout.clone(), timeout]);
+    }
+
+    #[test]
+    fn test_ping_and_echo() {
+        let (replies, _) = run(&[&["PING"], &["PING", "hi"], &["ECHO", "hello"]]);
+        assert_eq!(
+            replies,
+            vec![
+                RespValue::Simple(String::from("PONG")),
+                bulk("hi"),
+                bulk("hello")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hello_switches_protocol() {
+        let (replies, protocol) = run(&[&["HELLO", "3"]]);
+        assert_eq!(protocol, 3);
+        match &replies[0] {
+            RespValue::Map(fields) => {
+                assert!(fields.contains(&(bulk("proto"), RespValue::Integer(3))))
+            }
+            other => panic!("expected map reply, got {:?}", other),
+        }
+
+        let (replies, protocol) = run(&[&["HELLO", "4"]]);
+        assert_eq!(protocol, 2);
+        assert_eq!(
+            replies,
+            vec![RespValue::error("NOPROTO unsupported protocol version")]
+        );
+    }
+
+    #[test]
+    fn test_unsupported_commands_rejected() {
+        let (replies, _) = run(&[&["SELECT", "1"], &["SET", "k", "v"], &["GET"]]);
+        assert_eq!(
+            replies,
+            vec![
+                RespValue::error("ERR SELECT is not supported by the proxy"),
+                RespValue::error("ERR unknown command 'set', with args beginning with: 'k' 'v'"),
+                RespValue::error("ERR wrong number of arguments for 'get' command"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_quit_closes() {
+        let (tx, _rx) = sync_channel(1);
+        let producer = RedisProducer::new(tx, Duration::from_secs(5));
+        let mut session = Session { protocol: 2 };
+        match execute(&[b"QUIT".to_vec()], &mut session, &producer) {
+            Reply::SendAndClose(reply) => assert_eq!(reply, RespValue::ok()),
+            Reply::Send(_) => panic!("QUIT should close the connection"),
+        }
+    }
+
+    fn ping(addr: std::net::SocketAddr) -> (TcpStream, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"PING\r\n").unwrap();
+        let mut reply = String::new();
+        BufReader::new(stream.try_clone().unwrap())
+            .read_line(&mut reply)
+            .unwrap();
+        (stream, reply)
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let (tx, _rx) = sync_channel(1);
+        let producer = RedisProducer::new(tx, Duration::from_secs(5));
+        let server = RespServer::bind("127.0.0.1:0", producer)
+            .unwrap()
+            .with_max_connections(1);
+        let addr = server.listener.local_addr().unwrap();
+        server.spawn();
+
+        let (first, reply) = ping(addr);
+        assert_eq!(reply, "+PONG\r\n");
+        let (_, reply) = ping(addr);
+        assert_eq!(reply, "-ERR max number of clients reached\r\n");
+
+        //The slot is freed once the first client goes
+        drop(first);
+        thread::sleep(Duration::from_millis(50));
+        let (_, reply) = ping(addr);
+        assert_eq!(reply, "+PONG\r\n");
+    }
+}
@@ -0,0 +1,347 @@
+use {
+    crate::cache_policy::{CachePolicy, EvictionPolicy},
+    crate::clock::Clock,
+    crate::lru_cache::{ByteSize, CacheStats, ConcurrentCache},
+    std::{
+        collections::hash_map::RandomState,
+        hash::{BuildHasher, Hash, Hasher},
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
+};
+
+/*
+ * The ShardedCache splits its capacity across a fixed number of
+ * independently locked caches, picking the shard for a key by hash.
+ * Threads working on keys in different shards never contend on a lock,
+ * so many consumers can share one cache without serializing on it.
+ *
+ * Every shard is a cache of the same eviction policy, LRU unless another
+ * is chosen, and runs it on its own. Eviction is therefore only least
+ * recently used (or frequently, for LFU) within a shard, which is a close
+ * approximation of the global policy as long as keys hash evenly.
+ */
+pub struct ShardedCache<V = String> {
+    shards: Vec<Mutex<Box<dyn EvictionPolicy<V>>>>,
+    hash_builder: RandomState,
+}
+
+impl<V: Clone + Send + ByteSize> ConcurrentCache<V> for ShardedCache<V> {
+    fn get(&self, key: &str) -> Option<V> {
+        self.shard_for(key).lock().unwrap().get(key)
+    }
+
+    fn put(&self, key: &str, val: V, ttl: Option<Duration>) {
+        self.shard_for(key).lock().unwrap().put(key, val, ttl)
+    }
+
+    fn remove(&self, key: &str) {
+        self.shard_for(key).lock().unwrap().remove(key)
+    }
+
+    fn get_stale(&self, key: &str) -> Option<V> {
+        self.shard_for(key).lock().unwrap().get_stale(key)
+    }
+
+    fn get_with_refresh(&self, key: &str) -> Option<(V, bool)> {
+        self.shard_for(key).lock().unwrap().get_with_refresh(key)
+    }
+}
+
+impl<V: Clone + Send + ByteSize + 'static> ShardedCache<V> {
+    pub fn new(
+        shard_count: usize,
+        capacity: usize,
+        max_cache_entry_lifetime: Duration,
+    ) -> ShardedCache<V> {
+        ShardedCache::with_policy(
+            CachePolicy::Lru,
+            shard_count,
+            capacity,
+            max_cache_entry_lifetime,
+        )
+    }
+
+    /*
+     * The shard count is capped at the capacity so that every shard can
+     * hold at least one entry. Capacity is divided evenly, rounding up,
+     * so the total may slightly exceed the requested capacity.
+     */
+    pub fn with_policy(
+        policy: CachePolicy,
+        shard_count: usize,
+        capacity: usize,
+        max_cache_entry_lifetime: Duration,
+    ) -> ShardedCache<V> {
+        let shard_count = shard_count.min(capacity).max(1);
+        let shard_capacity = (capacity + shard_count - 1) / shard_count;
+        let shards = (0..shard_count)
+            .map(|_| Mutex::new(policy.build(shard_capacity, max_cache_entry_lifetime)))
+            .collect();
+        ShardedCache {
+            shards,
+            hash_builder: RandomState::new(),
+        }
+    }
+}
+
+impl<V> ShardedCache<V> {
+    /*
+     * Bounds the cache by bytes instead of entries. The budget is divided
+     * evenly across the shards, rounding up, and so is the largest entry:
+     * it is the same share of a shard's budget as max_entry_bytes is of the
+     * whole, so no one value can take more of any shard than that.
+     */
+    pub fn with_max_bytes(self, max_bytes: usize, max_entry_bytes: usize) -> ShardedCache<V> {
+        self.set_max_bytes(max_bytes, max_entry_bytes);
+        self
+    }
+
+    pub fn set_max_bytes(&self, max_bytes: usize, max_entry_bytes: usize) {
+        let shard_bytes = (max_bytes + self.shards.len() - 1) / self.shards.len();
+        let shard_entry_bytes = (max_entry_bytes + self.shards.len() - 1) / self.shards.len();
+        for shard in &self.shards {
+            shard
+                .lock()
+                .unwrap()
+                .set_max_bytes(shard_bytes, shard_entry_bytes);
+        }
+    }
+
+    //Every shard tells the time by the same clock
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> ShardedCache<V> {
+        for shard in &self.shards {
+            shard.lock().unwrap().set_clock(clock.clone());
+        }
+        self
+    }
+
+    //Keeps expired entries this long so they can still be served stale
+    pub fn with_stale_grace(self, stale_grace: Duration) -> ShardedCache<V> {
+        self.set_stale_grace(stale_grace);
+        self
+    }
+
+    pub fn set_stale_grace(&self, stale_grace: Duration) {
+        for shard in &self.shards {
+            shard.lock().unwrap().set_stale_grace(stale_grace);
+        }
+    }
+
+    //Spreads out expiry with jitter and early refresh, see LRUCache
+    pub fn with_early_expiry(
+        self,
+        expr_jitter_percent: usize,
+        early_refresh_percent: usize,
+    ) -> ShardedCache<V> {
+        self.set_early_expiry(expr_jitter_percent, early_refresh_percent);
+        self
+    }
+
+    pub fn set_early_expiry(&self, expr_jitter_percent: usize, early_refresh_percent: usize) {
+        for shard in &self.shards {
+            shard
+                .lock()
+                .unwrap()
+                .set_early_expiry(expr_jitter_percent, early_refresh_percent);
+        }
+    }
+
+    //Sums the stats of every shard, locking each in turn
+    pub fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats::default();
+        for shard in &self.shards {
+            stats.merge(shard.lock().unwrap().stats());
+        }
+        stats
+    }
+
+    /*
+     * The shard count is fixed when the cache is created, so the new
+     * capacity is divided across the existing shards, rounding up to at
+     * least one entry each
+     */
+    pub fn resize(&self, capacity: usize) {
+        let shard_capacity = ((capacity + self.shards.len() - 1) / self.shards.len()).max(1);
+        for shard in &self.shards {
+            shard.lock().unwrap().set_capacity(shard_capacity);
+        }
+    }
+
+    pub fn set_max_lifetime(&self, max_cache_entry_lifetime: Duration) {
+        for shard in &self.shards {
+            shard
+                .lock()
+                .unwrap()
+                .set_max_lifetime(max_cache_entry_lifetime);
+        }
+    }
+
+    fn shard_for(&self, key: &str) -> &Mutex<Box<dyn EvictionPolicy<V>>> {
+        let mut hasher = self.hash_builder.build_hasher();
+        key.hash(&mut hasher);
+        let index = (hasher.finish() % self.shards.len() as u64) as usize;
+        &self.shards[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {crate::clock::FakeClock, crate::lru_cache::Cache, crate::sharded_cache::*, std::thread};
+
+    #[test]
+    fn test_put_and_get() {
+        let cache = ShardedCache::new(4, 100, Duration::from_secs(1));
+        cache.put("foo", String::from("bar"), None);
+        assert_eq!(cache.get("foo"), Some(String::from("bar")));
+        assert_eq!(cache.get("baz"), None);
+
+        cache.remove("foo");
+        assert_eq!(cache.get("foo"), None);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 0));
+    }
+
+    #[test]
+    fn test_shard_count_capped_by_capacity() {
+        let cache = ShardedCache::<String>::new(16, 2, Duration::from_secs(1));
+        assert_eq!(cache.shards.len(), 2);
+
+        let cache = ShardedCache::<String>::new(16, 0, Duration::from_secs(1));
+        assert_eq!(cache.shards.len(), 1);
+    }
+
+    #[test]
+    fn test_resize() {
+        let cache = ShardedCache::new(4, 100, Duration::from_secs(60));
+        for i in 0..100 {
+            cache.put(&format!("key{}", i), String::from("val"), None);
+        }
+        cache.resize(8);
+        let stats = cache.stats();
+        assert!(stats.entries <= 8);
+        assert_eq!(stats.entries + stats.evictions, 100);
+
+        cache.resize(1);
+        assert!(cache.stats().entries <= 4);
+    }
+
+    #[test]
+    fn test_max_bytes() {
+        let cache = ShardedCache::new(4, 10, Duration::from_secs(60)).with_max_bytes(4096, 4096);
+        for i in 0..100 {
+            cache.put(&format!("key{}", i), "x".repeat(100), None);
+        }
+        let stats = cache.stats();
+        assert!(stats.bytes <= 4096);
+        assert!(stats.entries > 10);
+        assert_eq!(stats.entries + stats.evictions, 100);
+
+        //Over a shard's share of the budget, though not the whole of it
+        cache.put("big", "x".repeat(2048), None);
+        assert_eq!(cache.get("big"), None);
+        assert_eq!(cache.stats().oversized, 1);
+    }
+
+    #[test]
+    fn test_max_entry_bytes_per_shard() {
+        //Each of the 4 shards takes 10240 bytes, and entries up to 1024
+        let cache = ShardedCache::new(4, 10, Duration::from_secs(60)).with_max_bytes(40960, 4096);
+        cache.put("small", "x".repeat(512), None);
+        assert!(cache.get("small").is_some());
+
+        //Under a tenth of the whole budget, but not of a shard's
+        cache.put("big", "x".repeat(2048), None);
+        assert_eq!(cache.get("big"), None);
+        assert_eq!(cache.stats().oversized, 1);
+    }
+
+    #[test]
+    fn test_policies() {
+        for policy in CachePolicy::ALL.iter() {
+            let cache = ShardedCache::with_policy(*policy, 4, 40, Duration::from_secs(60));
+            for i in 0..100 {
+                let key = format!("key{}", i % 60);
+                if cache.get(&key).is_none() {
+                    cache.put(&key, key.clone(), None);
+                }
+            }
+            //Whatever the policy, the key looked up last is cached
+            assert_eq!(cache.get("key39"), Some(String::from("key39")));
+            assert!(cache.stats().entries <= 40);
+
+            cache.resize(8);
+            assert!(cache.stats().entries <= 8, "{:?}", policy);
+            cache.put("new", String::from("new"), None);
+            cache.remove("new");
+            assert_eq!(cache.get("new"), None);
+        }
+    }
+
+    #[test]
+    fn test_stale_grace() {
+        for policy in CachePolicy::ALL.iter() {
+            let clock = Arc::new(FakeClock::default());
+            let cache = ShardedCache::with_policy(*policy, 2, 10, Duration::from_secs(20))
+                .with_clock(clock.clone())
+                .with_stale_grace(Duration::from_secs(60));
+            cache.put("foo", String::from("old"), None);
+            //Not counted as stale while the entry is still fresh
+            assert_eq!(cache.get_stale("foo"), Some(String::from("old")));
+            clock.advance(Duration::from_secs(40));
+            assert_eq!(cache.get("foo"), None, "{:?}", policy);
+            assert_eq!(cache.get_stale("foo"), Some(String::from("old")));
+
+            //The refreshed value replaces the stale one
+            cache.put("foo", String::from("new"), None);
+            assert_eq!(cache.get("foo"), Some(String::from("new")), "{:?}", policy);
+            let stats = cache.stats();
+            assert_eq!((stats.stale_hits, stats.expirations), (1, 0));
+        }
+    }
+
+    #[test]
+    fn test_shared_through_arc_as_cache() {
+        let shared = Arc::new(ShardedCache::new(4, 100, Duration::from_secs(1)));
+        let mut handle = shared.clone();
+        Cache::put(&mut handle, "foo", String::from("bar"), None);
+        assert_eq!(
+            ConcurrentCache::get(&*shared, "foo"),
+            Some(String::from("bar"))
+        );
+    }
+
+    /*
+     * Many threads read and write an overlapping key space. Every value is
+     * derived from its key, so any hit returning a mismatched value would
+     * point at a torn or misrouted entry.
+     */
+    #[test]
+    fn test_concurrent_stress() {
+        let cache = Arc::new(ShardedCache::new(8, 500, Duration::from_secs(60)));
+        let threads: Vec<_> = (0..16)
+            .map(|thread_id| {
+                let shared = cache.clone();
+                thread::spawn(move || {
+                    let cache: &ShardedCache = &shared;
+                    let mut hits = 0;
+                    for op in 0..5000 {
+                        let key = format!("key{}", (op * 7 + thread_id * 13) % 1000);
+                        match cache.get(&key) {
+                            Some(val) => {
+                                assert_eq!(val, format!("val-{}", key));
+                                hits += 1;
+                            }
+                            None => cache.put(&key, format!("val-{}", key), None),
+                        }
+                    }
+                    hits
+                })
+            })
+            .collect();
+
+        let total_hits: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        assert!(total_hits > 0);
+    }
+}
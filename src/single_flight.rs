@@ -0,0 +1,192 @@
+use {
+    crate::redis_request::{ProxyResult, RedisRequest},
+    std::{
+        collections::HashMap,
+        sync::{Condvar, Mutex},
+    },
+};
+
+/*
+ * SingleFlight tracks which keys currently have a redis fetch outstanding
+ * so that concurrent cache misses on the same key share a single fetch.
+ *
+ * The first consumer to miss on a key becomes the leader and performs the
+ * fetch. Requests for the same key that arrive at any consumer while the
+ * fetch is outstanding are parked here rather than fetched again. When
+ * the leader completes, every parked request is handed the same result.
+ *
+ * Writes also go through the SingleFlight. A writer claims the key once
+ * any outstanding fetch has finished, so a fetch that read the old value
+ * can never put it in the cache after the writer has changed it. Reads
+ * that arrive during the write are parked and completed with the value
+ * the write left behind.
+ *
+ * A SingleFlight only coalesces across the consumers that share it, so the
+ * whole pool should hold the same instance behind an Arc.
+ */
+#[derive(Default)]
+pub struct SingleFlight {
+    in_flight: Mutex<HashMap<String, Vec<RedisRequest>>>,
+    flight_finished: Condvar,
+}
+
+impl SingleFlight {
+    /*
+     * Returns true if the caller is now the leader for this key and must
+     * fetch it and then call complete. Returns false if a fetch is already
+     * outstanding, in which case the request has been parked and will be
+     * completed by the leader.
+     */
+    pub fn join(&self, request: &RedisRequest) -> bool {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        match in_flight.get_mut(&request.key) {
+            Some(waiters) => {
+                waiters.push(request.clone());
+                false
+            }
+            None => {
+                in_flight.insert(request.key.clone(), Vec::new());
+                true
+            }
+        }
+    }
+
+    /*
+     * Blocks until no fetch or write is outstanding for the key, then
+     * claims it. Requests that join while the key is claimed are parked
+     * until finish is called.
+     */
+    pub fn claim(&self, key: &str) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        while in_flight.contains_key(key) {
+            in_flight = self.flight_finished.wait(in_flight).unwrap();
+        }
+        in_flight.insert(key.to_string(), Vec::new());
+    }
+
+    /*
+     * Waits until no fetch or write is outstanding for the key and then
+     * runs f with the SingleFlight locked, so no fetch of the key can start
+     * until f returns. f should be quick as it holds up every key.
+     */
+    pub fn run_exclusive<F: FnOnce()>(&self, key: &str, f: F) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        while in_flight.contains_key(key) {
+            in_flight = self.flight_finished.wait(in_flight).unwrap();
+        }
+        f();
+    }
+
+    /*
+     * Ends the outstanding fetch for the leader's key and completes the
+     * leader and every parked request with the same result
+     */
+    pub fn complete(&self, leader: &mut RedisRequest, result: ProxyResult) {
+        self.finish(&leader.key, result.clone());
+        leader.set_result(result);
+    }
+
+    /*
+     * Releases a claimed key and completes every request parked on it
+     * with the given result
+     */
+    pub fn finish(&self, key: &str, result: ProxyResult) {
+        let waiters = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            let waiters = in_flight.remove(key).unwrap_or_default();
+            self.flight_finished.notify_all();
+            waiters
+        };
+        for mut waiter in waiters {
+            waiter.set_result(result.clone());
+        }
+    }
+
+    //Requests parked on the key. Only used by tests to wait for them.
+    #[cfg(test)]
+    pub fn parked(&self, key: &str) -> usize {
+        self.in_flight
+            .lock()
+            .unwrap()
+            .get(key)
+            .map_or(0, |waiters| waiters.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::single_flight::*,
+        std::{
+            sync::{
+                atomic::{AtomicBool, Ordering},
+                Arc,
+            },
+            thread,
+            time::Duration,
+        },
+    };
+
+    #[test]
+    fn test_first_request_leads() {
+        let single_flight = SingleFlight::default();
+        let request = RedisRequest::new(String::from("foo"));
+        assert!(single_flight.join(&request));
+    }
+
+    #[test]
+    fn test_waiters_share_leader_result() {
+        let single_flight = SingleFlight::default();
+        let mut leader = RedisRequest::new(String::from("foo"));
+        let waiter = RedisRequest::new(String::from("foo"));
+        let other_key = RedisRequest::new(String::from("bar"));
+
+        assert!(single_flight.join(&leader));
+        assert!(!single_flight.join(&waiter));
+        assert!(single_flight.join(&other_key));
+
+        single_flight.complete(&mut leader, ProxyResult::Hit(b"baz".to_vec()));
+        assert_eq!(waiter.get_result(), ProxyResult::Hit(b"baz".to_vec()));
+        assert_eq!(leader.get_result(), ProxyResult::Hit(b"baz".to_vec()));
+    }
+
+    #[test]
+    fn test_claim_waits_for_flight() {
+        let single_flight = Arc::new(SingleFlight::default());
+        let mut leader = RedisRequest::new(String::from("foo"));
+        assert!(single_flight.join(&leader));
+
+        let claimed = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let single_flight = single_flight.clone();
+            let claimed = claimed.clone();
+            thread::spawn(move || {
+                single_flight.claim("foo");
+                claimed.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!claimed.load(Ordering::SeqCst));
+
+        single_flight.complete(&mut leader, ProxyResult::Miss);
+        writer.join().unwrap();
+        assert!(claimed.load(Ordering::SeqCst));
+
+        //Reads are parked behind the claim and get the writer's result
+        let reader = RedisRequest::new(String::from("foo"));
+        assert!(!single_flight.join(&reader));
+        single_flight.finish("foo", ProxyResult::Hit(b"new".to_vec()));
+        assert_eq!(reader.get_result(), ProxyResult::Hit(b"new".to_vec()));
+    }
+
+    #[test]
+    fn test_complete_ends_flight() {
+        let single_flight = SingleFlight::default();
+        let mut leader = RedisRequest::new(String::from("foo"));
+        assert!(single_flight.join(&leader));
+        single_flight.complete(&mut leader, ProxyResult::Miss);
+
+        let next = RedisRequest::new(String::from("foo"));
+        assert!(single_flight.join(&next));
+    }
+}
@@ -0,0 +1,435 @@
+use {
+    crate::cache_policy::EvictionPolicy,
+    crate::clock::{Clock, SystemClock},
+    crate::key_list::KeyList,
+    crate::lru_cache::{entry_lifetime, ByteSize, Cache, CacheEntry, CacheStats},
+    std::{
+        collections::{hash_map::DefaultHasher, HashMap},
+        hash::{Hash, Hasher},
+        sync::Arc,
+        time::Duration,
+    },
+};
+
+//Rows of counters in the sketch, each indexed by a different hash of the key
+const SKETCH_DEPTH: usize = 4;
+//Counters saturate here, as 4 bits would in a packed sketch
+const MAX_COUNT: u8 = 15;
+/*
+ * Every counter is halved once the sketch has counted this many uses per
+ * entry of capacity, so the frequencies it reports favour recent use
+ */
+const SAMPLES_PER_ENTRY: usize = 10;
+
+/*
+ * A count-min sketch estimating how often each key has been used, in a
+ * fixed amount of memory however many keys it sees. A key's estimate is
+ * the smallest of its counters, so collisions can only overestimate it.
+ * Only the counters at that minimum are incremented (conservative update),
+ * which keeps the overestimates down.
+ */
+struct FrequencySketch {
+    counters: Vec<[u8; SKETCH_DEPTH]>,
+    samples: usize,
+    sample_limit: usize,
+}
+
+impl FrequencySketch {
+    //Four counters a row per entry, as many as Caffeine packs in its sketch
+    fn new(capacity: usize) -> FrequencySketch {
+        let width = capacity.max(1).next_power_of_two() * 4;
+        FrequencySketch {
+            counters: vec![[0; SKETCH_DEPTH]; width],
+            samples: 0,
+            sample_limit: SAMPLES_PER_ENTRY * capacity.max(1),
+        }
+    }
+
+    //The counter index for each row. DefaultHasher is keyed the same way
+    //every time, so estimates are repeatable.
+    fn indices(&self, key: &str) -> [usize; SKETCH_DEPTH] {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let (low, high) = (hash as usize, (hash >> 32) as usize | 1);
+        let mut indices = [0; SKETCH_DEPTH];
+        for (row, index) in indices.iter_mut().enumerate() {
+            *index = low.wrapping_add(row.wrapping_mul(high)) % self.counters.len();
+        }
+        indices
+    }
+
+    fn estimate(&self, key: &str) -> u8 {
+        let indices = self.indices(key);
+        (0..SKETCH_DEPTH)
+            .map(|row| self.counters[indices[row]][row])
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn increment(&mut self, key: &str) {
+        let indices = self.indices(key);
+        let min = self.estimate(key);
+        if min < MAX_COUNT {
+            for (row, &index) in indices.iter().enumerate() {
+                if self.counters[index][row] == min {
+                    self.counters[index][row] += 1;
+                }
+            }
+        }
+        self.samples += 1;
+        if self.samples >= self.sample_limit {
+            self.age();
+        }
+    }
+
+    fn age(&mut self) {
+        for row in self.counters.iter_mut() {
+            for counter in row.iter_mut() {
+                *counter /= 2;
+            }
+        }
+        self.samples /= 2;
+    }
+}
+
+//Which list a cached key is in
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Region {
+    Window,
+    Probation,
+    Protected,
+}
+
+struct TinyLFUEntry<V> {
+    entry: CacheEntry<V>,
+    region: Region,
+}
+
+/*
+ * The TinyLFUCache is W-TinyLFU, as used by Caffeine. Capacity is split
+ * between:
+ *   window     a small LRU, 1% of capacity, that every new key enters
+ *   probation  keys admitted to the main cache but not used since
+ *   protected  keys used again while on probation, 80% of the main cache
+ * A key pushed out of the window only gets into the main cache if the
+ * frequency sketch says it is used more often than the key probation
+ * would evict for it. Otherwise the newcomer is dropped instead, so a scan
+ * of keys read once passes through the window without disturbing the
+ * keys that are used all the time.
+ *
+ * Every lookup counts towards the sketch, hit or miss, so a key that
+ * keeps being asked for earns its place even while it isn't cached.
+ */
+pub struct TinyLFUCache<V = String> {
+    entries: HashMap<String, TinyLFUEntry<V>>,
+    window: KeyList,
+    probation: KeyList,
+    protected: KeyList,
+    sketch: FrequencySketch,
+    window_capacity: usize,
+    protected_capacity: usize,
+    max_cache_entry_lifetime: Duration,
+    stale_grace: Duration,
+    clock: Arc<dyn Clock>,
+    capacity: usize,
+    stats: CacheStats,
+}
+
+impl<V: Clone> Cache<V> for TinyLFUCache<V> {
+    fn get(&mut self, key: &str) -> Option<V> {
+        self.sketch.increment(key);
+        let now = self.clock.now();
+        let (expired, region) = match self.entries.get(key) {
+            Some(entry) => (entry.entry.expired(now), entry.region),
+            None => {
+                self.stats.misses += 1;
+                return None;
+            }
+        };
+        if expired {
+            if self.entries[key].entry.expired_for(now, self.stale_grace) {
+                self.remove(key);
+                self.stats.expirations += 1;
+            }
+            self.stats.misses += 1;
+            return None;
+        }
+        self.stats.hits += 1;
+        match region {
+            Region::Window => self.window.push_newest(key),
+            Region::Protected => self.protected.push_newest(key),
+            Region::Probation => {
+                self.probation.remove(key);
+                self.protected.push_newest(key);
+                self.set_region(key, Region::Protected);
+                self.demote_protected();
+            }
+        }
+        Some(self.entries[key].entry.val.clone())
+    }
+
+    fn put(&mut self, key: &str, val: V, ttl: Option<Duration>) {
+        if let Some(entry) = self.entries.get(key) {
+            if !entry.entry.expired(self.clock.now()) {
+                eprintln!("unexpected double write of {} - ignoring", key);
+                return;
+            }
+            //A refresh of an entry kept through the grace window
+            self.remove(key);
+        }
+        let lifetime = match entry_lifetime(ttl, self.max_cache_entry_lifetime) {
+            Some(lifetime) => lifetime,
+            None => return,
+        };
+        self.entries.insert(
+            key.to_string(),
+            TinyLFUEntry {
+                entry: CacheEntry::new(val, lifetime, self.clock.now()),
+                region: Region::Window,
+            },
+        );
+        self.window.push_newest(key);
+        self.drain_window();
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.drop_entry(key);
+    }
+
+    fn get_stale(&mut self, key: &str) -> Option<V> {
+        let entry = &self.entries.get(key)?.entry;
+        let now = self.clock.now();
+        if entry.expired_for(now, self.stale_grace) {
+            return None;
+        }
+        let val = entry.val.clone();
+        //A fresh entry is handed back too, but isn't served stale
+        if entry.expired(now) {
+            self.stats.stale_hits += 1;
+        }
+        Some(val)
+    }
+}
+
+impl<V: Clone + ByteSize + Send> EvictionPolicy<V> for TinyLFUCache<V> {
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len() as u64,
+            ..self.stats
+        }
+    }
+
+    /*
+     * Shrinking takes the overflow from probation first, then protected.
+     * The sketch is rebuilt for the new size, forgetting what it counted.
+     */
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        let (window_capacity, protected_capacity) = region_capacities(capacity);
+        self.window_capacity = window_capacity;
+        self.protected_capacity = protected_capacity;
+        self.sketch = FrequencySketch::new(capacity);
+        while self.window.len() > self.window_capacity {
+            let key = self.window.pop_oldest().expect("window is not empty");
+            self.probation.push_newest(&key);
+            self.set_region(&key, Region::Probation);
+        }
+        self.demote_protected();
+        while self.entries.len() > capacity {
+            let victim = self
+                .probation
+                .pop_oldest()
+                .or_else(|| self.protected.pop_oldest())
+                .expect("main cache holds the overflow");
+            self.entries.remove(&victim);
+            self.stats.evictions += 1;
+        }
+    }
+
+    fn set_max_lifetime(&mut self, max_cache_entry_lifetime: Duration) {
+        self.max_cache_entry_lifetime = max_cache_entry_lifetime;
+        for entry in self.entries.values_mut() {
+            entry.entry.cap_lifetime(max_cache_entry_lifetime);
+        }
+    }
+
+    fn set_stale_grace(&mut self, stale_grace: Duration) {
+        self.stale_grace = stale_grace;
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+}
+
+//The window gets 1% of the capacity and protected 80% of the rest
+fn region_capacities(capacity: usize) -> (usize, usize) {
+    let window = (capacity / 100).max(1);
+    let main = capacity.saturating_sub(window);
+    (window, main * 4 / 5)
+}
+
+impl<V> TinyLFUCache<V> {
+    pub fn new(capacity: usize, max_cache_entry_lifetime: Duration) -> TinyLFUCache<V> {
+        let (window_capacity, protected_capacity) = region_capacities(capacity);
+        TinyLFUCache {
+            entries: HashMap::with_capacity(capacity),
+            window: KeyList::default(),
+            probation: KeyList::default(),
+            protected: KeyList::default(),
+            sketch: FrequencySketch::new(capacity),
+            window_capacity,
+            protected_capacity,
+            max_cache_entry_lifetime,
+            stale_grace: Duration::from_secs(0),
+            clock: Arc::new(SystemClock),
+            capacity,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /*
+     * Moves keys pushed out of the window into probation while the main
+     * cache has room, and once it is full makes each one compete with
+     * probation's oldest key for a place
+     */
+    fn drain_window(&mut self) {
+        while self.window.len() > self.window_capacity {
+            let candidate = self.window.pop_oldest().expect("window is not empty");
+            let main_capacity = self.capacity.saturating_sub(self.window_capacity);
+            if self.probation.len() + self.protected.len() < main_capacity {
+                self.probation.push_newest(&candidate);
+                self.set_region(&candidate, Region::Probation);
+                continue;
+            }
+            let victim = match self.probation.oldest().or_else(|| self.protected.oldest()) {
+                Some(victim) => victim.to_string(),
+                None => {
+                    self.entries.remove(&candidate);
+                    self.stats.evictions += 1;
+                    continue;
+                }
+            };
+            if self.sketch.estimate(&candidate) > self.sketch.estimate(&victim) {
+                self.drop_entry(&victim);
+                self.probation.push_newest(&candidate);
+                self.set_region(&candidate, Region::Probation);
+            } else {
+                self.entries.remove(&candidate);
+            }
+            self.stats.evictions += 1;
+        }
+    }
+
+    //Moves protected's oldest keys back to probation while it is over capacity
+    fn demote_protected(&mut self) {
+        while self.protected.len() > self.protected_capacity {
+            let key = self.protected.pop_oldest().expect("protected is not empty");
+            self.probation.push_newest(&key);
+            self.set_region(&key, Region::Probation);
+        }
+    }
+
+    fn set_region(&mut self, key: &str, region: Region) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.region = region;
+        }
+    }
+
+    fn drop_entry(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.list_mut(entry.region).remove(key);
+        }
+    }
+
+    fn list_mut(&mut self, region: Region) -> &mut KeyList {
+        match region {
+            Region::Window => &mut self.window,
+            Region::Probation => &mut self.probation,
+            Region::Protected => &mut self.protected,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tiny_lfu_cache::*;
+
+    fn lookup(cache: &mut TinyLFUCache, key: &str) -> bool {
+        let hit = cache.get(key).is_some();
+        if !hit {
+            cache.put(key, key.to_string(), None);
+        }
+        hit
+    }
+
+    #[test]
+    fn test_sketch() {
+        let mut sketch = FrequencySketch::new(64);
+        for _ in 0..5 {
+            sketch.increment("hot");
+        }
+        sketch.increment("cold");
+        assert!(sketch.estimate("hot") >= 5);
+        assert!(sketch.estimate("cold") >= 1 && sketch.estimate("cold") < 5);
+        assert_eq!(sketch.estimate("never"), 0);
+
+        for _ in 0..20 {
+            sketch.increment("hot");
+        }
+        assert_eq!(sketch.estimate("hot"), MAX_COUNT);
+        sketch.age();
+        assert_eq!(sketch.estimate("hot"), MAX_COUNT / 2);
+    }
+
+    #[test]
+    fn test_frequent_keys_survive_a_scan() {
+        let mut cache = TinyLFUCache::new(10, Duration::from_secs(60));
+        for _ in 0..3 {
+            for i in 0..8 {
+                lookup(&mut cache, &format!("hot{}", i));
+            }
+        }
+        for i in 0..50 {
+            lookup(&mut cache, &format!("scan{}", i));
+        }
+        let hits = (0..8)
+            .filter(|i| lookup(&mut cache, &format!("hot{}", i)))
+            .count();
+        assert_eq!(hits, 8);
+        assert_eq!(cache.stats().entries, 10);
+    }
+
+    #[test]
+    fn test_regions() {
+        let mut cache = TinyLFUCache::new(10, Duration::from_secs(60));
+        assert_eq!((cache.window_capacity, cache.protected_capacity), (1, 7));
+        lookup(&mut cache, "a");
+        assert_eq!(cache.window.keys(), vec!["a"]);
+        lookup(&mut cache, "b");
+        assert_eq!(cache.probation.keys(), vec!["a"]);
+        lookup(&mut cache, "a");
+        assert_eq!(cache.protected.keys(), vec!["a"]);
+
+        cache.remove("a");
+        cache.remove("b");
+        assert!(cache.entries.is_empty() && cache.window.is_empty());
+        assert!(cache.protected.is_empty() && cache.probation.is_empty());
+    }
+
+    #[test]
+    fn test_set_capacity() {
+        let mut cache = TinyLFUCache::new(200, Duration::from_secs(60));
+        for i in 0..200 {
+            lookup(&mut cache, &format!("key{}", i));
+        }
+        cache.set_capacity(20);
+        assert_eq!(cache.entries.len(), 20);
+        assert_eq!(
+            cache.window.len() + cache.probation.len() + cache.protected.len(),
+            20
+        );
+        assert!(cache.window.len() <= 1 && cache.protected.len() <= 15);
+    }
+}